@@ -0,0 +1,198 @@
+//! Vercel's Speed Insights and Web Analytics drains, see:
+//! https://vercel.com/docs/drains/reference/speed-insights
+//! https://vercel.com/docs/drains/reference/web-analytics
+//!
+//! Their records are mapped onto `Message`s with a `source` of their own, so
+//! they land in separate CloudWatch groups (`/vercel/<project>/speed-insights`)
+//! and loki streams while going through the same queue and drivers as logs.
+
+use crate::types::{derived_id, Message, Record};
+use serde::{Deserialize, Deserializer, Serialize};
+
+pub const SPEED_INSIGHTS_SOURCE: &str = "speed-insights";
+pub const WEB_ANALYTICS_SOURCE: &str = "web-analytics";
+
+/// One web vital measured in a visitor's browser.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SpeedInsight {
+    #[serde(deserialize_with = "timestamp_millis")]
+    pub timestamp: i64,
+    pub project_id: String,
+    pub project_name: Option<String>,
+    pub deployment_id: Option<String>,
+    // e.g. `LCP`, `CLS`, `INP`, `FCP` or `TTFB`
+    pub metric_type: String,
+    pub value: f64,
+    pub origin: Option<String>,
+    pub path: Option<String>,
+    pub route: Option<String>,
+    pub vercel_environment: Option<String>,
+    pub vercel_url: Option<String>,
+    // device, location and SDK details, kept as sent
+    #[serde(flatten)]
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A page view or custom event tracked by Web Analytics.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyticsEvent {
+    #[serde(deserialize_with = "timestamp_millis")]
+    pub timestamp: i64,
+    pub project_id: String,
+    pub project_name: Option<String>,
+    pub deployment_id: Option<String>,
+    // `pageview` or `event`
+    pub event_type: String,
+    pub event_name: Option<String>,
+    // custom event properties, sent as a JSON encoded string
+    #[serde(default, deserialize_with = "json_string")]
+    pub event_data: Option<serde_json::Value>,
+    pub origin: Option<String>,
+    pub path: Option<String>,
+    pub route: Option<String>,
+    pub referrer: Option<String>,
+    pub vercel_environment: Option<String>,
+    pub vercel_url: Option<String>,
+    // session, device, location and SDK details, kept as sent
+    #[serde(flatten)]
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Record for SpeedInsight {
+    fn into_message(self, raw: &str) -> Message {
+        let host = host(self.vercel_url.as_deref(), self.origin.as_deref());
+        Message {
+            id: derived_id(raw),
+            id_derived: true,
+            timestamp: self.timestamp,
+            source: SPEED_INSIGHTS_SOURCE.to_owned(),
+            project_name: self.project_name.clone().unwrap_or(self.project_id.clone()),
+            project_id: self.project_id.clone(),
+            deployment_id: self.deployment_id.clone().unwrap_or_default(),
+            host,
+            environment: self.vercel_environment.clone(),
+            path: self.path.clone(),
+            message: without_nulls(&self),
+            ..Default::default()
+        }
+    }
+}
+
+impl Record for AnalyticsEvent {
+    fn into_message(self, raw: &str) -> Message {
+        let host = host(self.vercel_url.as_deref(), self.origin.as_deref());
+        Message {
+            id: derived_id(raw),
+            id_derived: true,
+            timestamp: self.timestamp,
+            source: WEB_ANALYTICS_SOURCE.to_owned(),
+            project_name: self.project_name.clone().unwrap_or(self.project_id.clone()),
+            project_id: self.project_id.clone(),
+            deployment_id: self.deployment_id.clone().unwrap_or_default(),
+            host,
+            environment: self.vercel_environment.clone(),
+            path: self.path.clone(),
+            message: without_nulls(&self),
+            ..Default::default()
+        }
+    }
+}
+
+/// The deployment's host, or failing that the host of the page's origin.
+fn host(vercel_url: Option<&str>, origin: Option<&str>) -> String {
+    if let Some(url) = vercel_url {
+        return url.to_owned();
+    }
+    let origin = origin.unwrap_or_default();
+    let origin = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+    return origin.split('/').next().unwrap_or_default().to_owned();
+}
+
+/// The record as the structured message body, leaving out absent fields.
+fn without_nulls<T: Serialize>(record: &T) -> serde_json::Value {
+    let mut value = serde_json::to_value(record).unwrap_or_default();
+    if let serde_json::Value::Object(fields) = &mut value {
+        fields.retain(|_, value| !value.is_null());
+    }
+    return value;
+}
+
+/// Unix milliseconds, sent either as a number or an RFC 3339 string.
+fn timestamp_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(number) => number
+            .as_i64()
+            .ok_or_else(|| serde::de::Error::custom("timestamp is not an integer")),
+        serde_json::Value::String(timestamp) => crate::syslog::parse_timestamp(&timestamp)
+            .ok_or_else(|| serde::de::Error::custom("invalid RFC 3339 timestamp")),
+        _ => Err(serde::de::Error::custom(
+            "expected a number or an RFC 3339 timestamp",
+        )),
+    }
+}
+
+/// JSON that may arrive encoded in a string, kept as a string when it
+/// doesn't parse.
+fn json_string<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<serde_json::Value>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    return Ok(value.map(|value| match value {
+        serde_json::Value::String(encoded) => {
+            serde_json::from_str(&encoded).unwrap_or(serde_json::Value::String(encoded))
+        }
+        value => value,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ParsedPayload;
+
+    #[test]
+    fn maps_speed_insights_onto_messages() {
+        let payload =
+            ParsedPayload::parse::<SpeedInsight>(include_str!("fixtures/speed_insights.json"))
+                .unwrap();
+        assert!(payload.invalid.is_empty());
+        assert_eq!(payload.messages.len(), 2);
+        let message = &payload.messages[0];
+        assert_eq!(message.source, "speed-insights");
+        assert_eq!(message.project_name, "prj_1");
+        assert_eq!(message.host, "my-app-abc123.vercel.app");
+        assert_eq!(message.timestamp, 1694705400000);
+        assert_eq!(message.message["metricType"], "LCP");
+        assert_eq!(message.message["clientName"], "Chrome");
+        assert!(message.message.get("projectName").is_none());
+        // the same record always gets the same id
+        assert_ne!(message.id, payload.messages[1].id);
+        let again =
+            ParsedPayload::parse::<SpeedInsight>(include_str!("fixtures/speed_insights.json"))
+                .unwrap();
+        assert_eq!(message.id, again.messages[0].id);
+        assert!(message.id_derived);
+    }
+
+    #[test]
+    fn maps_web_analytics_onto_messages() {
+        let payload = ParsedPayload::parse_ndjson::<AnalyticsEvent>(include_str!(
+            "fixtures/web_analytics.ndjson"
+        ));
+        let fields: Vec<&str> = payload
+            .invalid
+            .iter()
+            .map(|invalid| invalid.field.as_str())
+            .collect();
+        assert_eq!(fields, vec!["eventType"]);
+        assert_eq!(payload.messages.len(), 2);
+        let event = &payload.messages[1];
+        assert_eq!(event.source, "web-analytics");
+        assert_eq!(event.host, "example.com");
+        assert_eq!(event.path.as_deref(), Some("/checkout"));
+        assert_eq!(event.message["eventName"], "purchase");
+        assert_eq!(event.message["eventData"]["amount"], 42);
+    }
+}
@@ -1,16 +1,69 @@
+use crate::analytics::{AnalyticsEvent, SpeedInsight};
 use crate::{handlers, types};
 
 pub fn create_app(state: types::AppState) -> axum::Router {
     return axum::Router::new()
         .route("/", axum::routing::post(handlers::root))
         .route("/health", axum::routing::get(handlers::health_check))
-        .route("/vercel", axum::routing::post(handlers::ingest))
+        .route("/ready", axum::routing::get(handlers::ready_check))
+        .route(
+            "/vercel",
+            axum::routing::post(handlers::ingest::<types::Message>),
+        )
+        .route(
+            "/vercel/speed-insights",
+            axum::routing::post(handlers::ingest::<SpeedInsight>),
+        )
+        .route(
+            "/vercel/web-analytics",
+            axum::routing::post(handlers::ingest::<AnalyticsEvent>),
+        )
+        .route(
+            "/vercel/:endpoint",
+            axum::routing::post(handlers::ingest_endpoint::<types::Message>),
+        )
+        .route(
+            "/vercel/:endpoint/speed-insights",
+            axum::routing::post(handlers::ingest_endpoint::<SpeedInsight>),
+        )
+        .route(
+            "/vercel/:endpoint/web-analytics",
+            axum::routing::post(handlers::ingest_endpoint::<AnalyticsEvent>),
+        )
+        .route(
+            "/otlp/v1/traces",
+            axum::routing::post(handlers::ingest_traces),
+        )
         .with_state(state);
 }
 
+/// What the tests build their app from: the verify token `test`, the secret
+/// `deadbeef1234dacb4321` and nothing optional configured.
+#[cfg(test)]
+pub fn test_state(log_queue: crate::queue::IngestSender) -> anyhow::Result<types::AppState> {
+    return Ok(types::AppState {
+        vercel_verify: String::from("test"),
+        vercel_secrets: crate::secrets::Secrets::new(
+            vec![String::from("deadbeef1234dacb4321")],
+            None,
+        )?,
+        signatures: Default::default(),
+        endpoints: Default::default(),
+        log_queue,
+        wal: None,
+        readiness: Default::default(),
+        quarantine: None,
+        traces: None,
+        max_decompressed_bytes: 1 << 20,
+        max_timestamp_skew: None,
+        dedupe: None,
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::queue;
     use anyhow::Result;
     use axum::{
         body::Body,
@@ -20,15 +73,8 @@ mod tests {
 
     #[tokio::test]
     async fn health_check() -> Result<()> {
-        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
-        let state = types::AppState {
-            vercel_verify: String::from(""),
-            vercel_secret: ring::hmac::Key::new(
-                ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
-                "".as_bytes(),
-            ),
-            log_queue: tx,
-        };
+        let (tx, _rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let state = test_state(tx)?;
         let mut app = create_app(state);
 
         let request = Request::builder()
@@ -41,18 +87,44 @@ mod tests {
         return Ok(());
     }
     #[tokio::test]
-    async fn root_check() -> Result<()> {
-        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+    async fn ready_check() -> Result<()> {
+        let (tx, _rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let controller = std::sync::Arc::new(());
         let state = types::AppState {
-            vercel_verify: String::from(""),
-            vercel_secret: ring::hmac::Key::new(
-                ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
-                "".as_bytes(),
+            readiness: crate::readiness::Readiness::new(
+                Vec::new(),
+                std::sync::Arc::downgrade(&controller),
             ),
-            log_queue: tx,
+            ..test_state(tx)?
         };
         let mut app = create_app(state);
 
+        let request = || {
+            Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app.as_service().call(request()).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let report: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(
+            report["queue"],
+            serde_json::json!({ "depth": 0, "capacity": 10 })
+        );
+
+        drop(controller);
+        let response = app.as_service().call(request()).await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn root_check() -> Result<()> {
+        let (tx, _rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let state = test_state(tx)?;
+        let mut app = create_app(state);
+
         let request = Request::builder()
             .method("POST")
             .uri("/")
@@ -73,17 +145,13 @@ mod tests {
             include_str!("fixtures/sample_5.json"),
         ];
 
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+        let (tx, rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
         let key = ring::hmac::Key::new(
             ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
             "deadbeef1234dacb4321".as_bytes(),
         );
 
-        let state = types::AppState {
-            vercel_verify: String::from("test"),
-            vercel_secret: key.clone(),
-            log_queue: tx,
-        };
+        let state = test_state(tx)?;
         let mut app = create_app(state);
         let mut app_service = app.as_service();
 
@@ -118,17 +186,13 @@ mod tests {
             include_str!("fixtures/sample_1.json"),
         ];
 
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+        let (tx, rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
         let key = ring::hmac::Key::new(
             ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
             "deadbeef1234dacb4321".as_bytes(),
         );
 
-        let state = types::AppState {
-            vercel_verify: String::from("test"),
-            vercel_secret: key.clone(),
-            log_queue: tx,
-        };
+        let state = test_state(tx)?;
         let mut app = create_app(state);
         let mut app_service = app.as_service();
 
@@ -155,4 +219,562 @@ mod tests {
         assert_eq!(rx.len(), 3);
         return Ok(());
     }
+    #[tokio::test]
+    async fn ingest_rejects_when_queue_is_full() -> Result<()> {
+        let data = include_str!("fixtures/sample_2.json");
+        let (tx, rx) = queue::ingest_queue(2, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+
+        let state = test_state(tx)?;
+        let mut app = create_app(state);
+
+        let sig = ring::hmac::sign(&key, data.as_bytes());
+        let request = || {
+            Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .uri("/vercel")
+                .body(Body::from(data))
+                .unwrap()
+        };
+        // larger than the queue, but it is empty
+        let response = app.as_service().call(request()).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(rx.len(), 3);
+
+        let response = app.as_service().call(request()).await?;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(rx.len(), 3);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_quarantines_invalid_messages() -> Result<()> {
+        let data = include_str!("fixtures/partially_invalid.json");
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("quarantine.ndjson");
+        let (tx, rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+
+        let state = types::AppState {
+            quarantine: Some(std::sync::Arc::new(crate::quarantine::Quarantine::open(
+                &path,
+                u64::MAX,
+                1,
+            )?)),
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state);
+
+        let sig = ring::hmac::sign(&key, data.as_bytes());
+        let request = Request::builder()
+            .method("POST")
+            .header("x-vercel-signature", hex::encode(sig.as_ref()))
+            .uri("/vercel")
+            .body(Body::from(data))
+            .unwrap();
+        let response = app.as_service().call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(rx.len(), 1);
+        assert_eq!(std::fs::read_to_string(&path)?.lines().count(), 2);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_check_ndjson() -> Result<()> {
+        // read as JSON and refused whole, the valid line included
+        let garbage = format!(
+            "not json\n{}",
+            include_str!("fixtures/sample_2.ndjson")
+                .lines()
+                .next()
+                .unwrap()
+        );
+        let test_data = [
+            (None, garbage.as_str()),
+            (
+                Some("application/x-ndjson"),
+                include_str!("fixtures/sample_2.ndjson"),
+            ),
+            (None, include_str!("fixtures/sample_2.ndjson")),
+            (None, include_str!("fixtures/sample_2.json")),
+            (
+                Some("application/x-ndjson"),
+                include_str!("fixtures/partially_invalid.ndjson"),
+            ),
+        ];
+
+        let (tx, rx) = queue::ingest_queue(20, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+
+        let state = test_state(tx)?;
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        for (content_type, data) in test_data {
+            let sig = ring::hmac::sign(&key, data.as_bytes());
+            let mut request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .uri("/vercel");
+            if let Some(content_type) = content_type {
+                request = request.header("content-type", content_type);
+            }
+            let response = app_service
+                .call(request.body(Body::from(data.to_owned())).unwrap())
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(rx.len(), 10);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_compressed_bodies() -> Result<()> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let data = include_str!("fixtures/sample_2.json");
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(data.as_bytes())?;
+        let gzip = gzip.finish()?;
+
+        let (tx, rx) = queue::ingest_queue(20, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+
+        let state = types::AppState {
+            max_decompressed_bytes: data.len(),
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        // signed over the body as sent, not the decompressed one, and
+        // refused once the cap is exceeded or the coding is unknown
+        let test_data = [
+            ("gzip", gzip.clone(), gzip.clone(), StatusCode::OK),
+            (
+                "gzip",
+                data.as_bytes().to_vec(),
+                gzip.clone(),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                "br",
+                gzip.clone(),
+                gzip.clone(),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+            (
+                "gzip",
+                data.as_bytes().to_vec(),
+                data.as_bytes().to_vec(),
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (encoding, signed, body, status) in test_data {
+            let sig = ring::hmac::sign(&key, &signed);
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .header("content-encoding", encoding)
+                .uri("/vercel")
+                .body(Body::from(body))
+                .unwrap();
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), status, "{}", encoding);
+        }
+        assert_eq!(rx.len(), 3);
+
+        let mut bomb = GzEncoder::new(Vec::new(), Compression::default());
+        bomb.write_all(data.as_bytes())?;
+        bomb.write_all(b" ")?;
+        let bomb = bomb.finish()?;
+        let sig = ring::hmac::sign(&key, &bomb);
+        let request = Request::builder()
+            .method("POST")
+            .header("x-vercel-signature", hex::encode(sig.as_ref()))
+            .header("content-encoding", "gzip")
+            .uri("/vercel")
+            .body(Body::from(bomb))
+            .unwrap();
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // a route signed over the decompressed body refuses the one as sent
+        let (tx, rx) = queue::ingest_queue(20, queue::OverflowPolicy::Reject, None)?;
+        let state = types::AppState {
+            signatures: crate::signature::RouteSignatures::parse(
+                "/vercel=sha1:x-vercel-signature:hex::decoded",
+            )?,
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+        for (signed, status) in [
+            (data.as_bytes().to_vec(), StatusCode::OK),
+            (gzip.clone(), StatusCode::UNPROCESSABLE_ENTITY),
+        ] {
+            let sig = ring::hmac::sign(&key, &signed);
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .header("content-encoding", "gzip")
+                .uri("/vercel")
+                .body(Body::from(gzip.clone()))
+                .unwrap();
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), status);
+        }
+        assert_eq!(rx.len(), 3);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_speed_insights_and_web_analytics() -> Result<()> {
+        let test_data = [
+            (
+                "/vercel/speed-insights",
+                include_str!("fixtures/speed_insights.json"),
+            ),
+            (
+                "/vercel/web-analytics",
+                include_str!("fixtures/web_analytics.ndjson"),
+            ),
+        ];
+
+        let (tx, mut rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+
+        let state = test_state(tx)?;
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        for (uri, data) in test_data {
+            let sig = ring::hmac::sign(&key, data.as_bytes());
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .uri(uri)
+                .body(Body::from(data))
+                .unwrap();
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(rx.len(), 4);
+        let mut sources = Vec::new();
+        for _ in 0..4 {
+            sources.push(rx.recv().await.unwrap().message.source);
+        }
+        assert_eq!(
+            sources,
+            vec![
+                "speed-insights",
+                "speed-insights",
+                "web-analytics",
+                "web-analytics"
+            ]
+        );
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_checks_each_routes_signature_scheme() -> Result<()> {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+        let data = include_str!("fixtures/sample_2.json");
+        let (tx, rx) = queue::ingest_queue(20, queue::OverflowPolicy::Reject, None)?;
+        let sha1 = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+        let sha256 =
+            ring::hmac::Key::new(ring::hmac::HMAC_SHA256, "deadbeef1234dacb4321".as_bytes());
+
+        let state = types::AppState {
+            signatures: crate::signature::RouteSignatures::parse(
+                "/vercel=sha256:x-signature:base64:sha256=",
+            )?,
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        let sha256_sig = format!(
+            "sha256={}",
+            STANDARD.encode(ring::hmac::sign(&sha256, data.as_bytes()))
+        );
+        let sha1_sig = hex::encode(ring::hmac::sign(&sha1, data.as_bytes()));
+        let test_data = [
+            ("/vercel", "x-signature", sha256_sig.clone(), StatusCode::OK),
+            (
+                "/vercel",
+                "x-vercel-signature",
+                sha1_sig.clone(),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                "/vercel",
+                "x-signature",
+                sha1_sig.clone(),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            // routes without a scheme of their own keep vercel's
+            (
+                "/vercel/web-analytics",
+                "x-signature",
+                sha256_sig,
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (uri, header, signature, status) in test_data {
+            let request = Request::builder()
+                .method("POST")
+                .header(header, signature)
+                .uri(uri)
+                .body(Body::from(data))
+                .unwrap();
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), status, "{} {}", uri, header);
+        }
+        assert_eq!(rx.len(), 3);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_named_endpoints() -> Result<()> {
+        use std::io::Write;
+
+        let mut endpoints_file = tempfile::NamedTempFile::new()?;
+        endpoints_file
+            .write_all(br#"[{"name": "team-a", "verify": "verify-a", "secret": "secret-a"}]"#)?;
+        let (tx, mut rx) = queue::ingest_queue(20, queue::OverflowPolicy::Reject, None)?;
+        let state = types::AppState {
+            endpoints: crate::endpoints::Endpoints::load(endpoints_file.path(), &[])?,
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        // a payload naming an endpoint itself is still routed by its path
+        let mut messages: serde_json::Value =
+            serde_json::from_str(include_str!("fixtures/sample_2.json"))?;
+        for message in messages.as_array_mut().unwrap() {
+            message["endpoint"] = serde_json::json!("team-a");
+        }
+        let logs = serde_json::to_string(&messages)?;
+        let analytics = include_str!("fixtures/web_analytics.ndjson");
+        let test_data = [
+            (
+                "/vercel/team-a",
+                "secret-a",
+                logs.as_str(),
+                StatusCode::OK,
+                Some("verify-a"),
+            ),
+            (
+                "/vercel/team-a/web-analytics",
+                "secret-a",
+                analytics,
+                StatusCode::OK,
+                Some("verify-a"),
+            ),
+            (
+                "/vercel",
+                "deadbeef1234dacb4321",
+                logs.as_str(),
+                StatusCode::OK,
+                Some("test"),
+            ),
+            (
+                "/vercel/team-a",
+                "deadbeef1234dacb4321",
+                logs.as_str(),
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some("verify-a"),
+            ),
+            (
+                "/vercel/team-b",
+                "secret-a",
+                logs.as_str(),
+                StatusCode::NOT_FOUND,
+                None,
+            ),
+        ];
+        for (uri, secret, data, status, verify) in test_data {
+            let key =
+                ring::hmac::Key::new(ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret.as_bytes());
+            let sig = ring::hmac::sign(&key, data.as_bytes());
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .uri(uri)
+                .body(Body::from(data.to_owned()))
+                .unwrap();
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), status, "{} {}", uri, secret);
+            assert_eq!(
+                response
+                    .headers()
+                    .get("x-vercel-verify")
+                    .map(|value| value.to_str().unwrap()),
+                verify
+            );
+        }
+        let mut endpoints = Vec::new();
+        while !rx.is_empty() {
+            endpoints.push(rx.recv().await.unwrap().message.endpoint);
+        }
+        let team_a = Some(String::from("team-a"));
+        assert_eq!(
+            endpoints,
+            vec![
+                team_a.clone(),
+                team_a.clone(),
+                team_a.clone(),
+                team_a.clone(),
+                team_a,
+                None,
+                None,
+                None
+            ]
+        );
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_drops_replayed_messages() -> Result<()> {
+        let (tx, rx) = queue::ingest_queue(20, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+        let state = types::AppState {
+            max_timestamp_skew: Some(std::time::Duration::from_secs(300)),
+            dedupe: Some(std::sync::Arc::new(crate::dedupe::Dedupe::new(
+                std::time::Duration::from_secs(300),
+                100,
+            ))),
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        let stale = include_str!("fixtures/sample_2.json").to_owned();
+        let mut messages: serde_json::Value = serde_json::from_str(&stale)?;
+        for message in messages.as_array_mut().unwrap() {
+            message["timestamp"] = serde_json::json!(crate::deadletter::now_millis());
+        }
+        let fresh = serde_json::to_string(&messages)?;
+        // only the stale message of a mixed payload is dropped
+        messages[0]["timestamp"] = serde_json::json!(1);
+        messages[1]["id"] = serde_json::json!("fresh-among-stale");
+        let mixed = serde_json::to_string(&messages)?;
+        // sent again, the fresh payload is accepted but not queued twice
+        let test_data = [
+            (stale, StatusCode::OK),
+            (fresh.clone(), StatusCode::OK),
+            (fresh, StatusCode::OK),
+            (mixed, StatusCode::OK),
+        ];
+        for (data, status) in test_data {
+            let sig = ring::hmac::sign(&key, data.as_bytes());
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", hex::encode(sig.as_ref()))
+                .uri("/vercel")
+                .body(Body::from(data))
+                .unwrap();
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), status);
+        }
+        assert_eq!(rx.len(), 4);
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_traces() -> Result<()> {
+        let data = include_str!("fixtures/traces.json");
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("traces.ndjson");
+        let (tx, _rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let key = ring::hmac::Key::new(
+            ring::hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            "deadbeef1234dacb4321".as_bytes(),
+        );
+        let (traces, trace_task) = crate::traces::spawn(
+            vec![crate::traces::TraceSpec {
+                name: String::from("file"),
+                driver: Box::new(crate::drivers::TraceFileDriver::open(&path, u64::MAX, 1)?),
+            }],
+            10,
+            None,
+        );
+
+        let state = types::AppState {
+            traces: Some(traces),
+            ..test_state(tx)?
+        };
+        let mut app = create_app(state.clone());
+
+        let sig = ring::hmac::sign(&key, data.as_bytes());
+        let request = Request::builder()
+            .method("POST")
+            .header("x-vercel-signature", hex::encode(sig.as_ref()))
+            .header("content-type", "application/json")
+            .uri("/otlp/v1/traces")
+            .body(Body::from(data))
+            .unwrap();
+        let response = app.as_service().call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], b"{}");
+
+        let request = Request::builder()
+            .method("POST")
+            .header("x-vercel-signature", hex::encode([0u8; 20]))
+            .header("content-type", "application/json")
+            .uri("/otlp/v1/traces")
+            .body(Body::from(data))
+            .unwrap();
+        let response = app.as_service().call(request).await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // the workers finish once the last sender is gone
+        drop((app, state));
+        trace_task.await?;
+        let exports: Vec<serde_json::Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(exports.len(), 1);
+        let attributes = &exports[0]["resourceSpans"][0]["scopeSpans"][0]["spans"][1]["attributes"];
+        assert!(attributes.as_array().unwrap().contains(&serde_json::json!({
+            "key": "request_id",
+            "value": { "stringValue": "iad1::abcde-1706327914122-0" }
+        })));
+        return Ok(());
+    }
+    #[tokio::test]
+    async fn ingest_traces_without_trace_drivers() -> Result<()> {
+        let (tx, _rx) = queue::ingest_queue(10, queue::OverflowPolicy::Reject, None)?;
+        let state = test_state(tx)?;
+        let mut app = create_app(state);
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/otlp/v1/traces")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.as_service().call(request).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        return Ok(());
+    }
 }
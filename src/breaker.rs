@@ -0,0 +1,281 @@
+use axum_prometheus::metrics::{counter, gauge};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+/// What a driver's workers do with messages while its breaker is open.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OpenAction {
+    /// Hold on to them until a probe gets through, letting the driver queue
+    /// fill up
+    Buffer,
+    /// Hand them straight to the dead-letter sink
+    DeadLetter,
+}
+
+/// When a driver's breaker opens and how long it stays open before probing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreakerConfig {
+    // consecutive failures that open the breaker, 0 disables it
+    pub failure_threshold: usize,
+    pub cooldown: Duration,
+    pub open_action: OpenAction,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+            open_action: OpenAction::Buffer,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Returned when a request may go out. A probe's outcome decides whether the
+/// breaker closes again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Permit {
+    Closed,
+    Probe,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { failures: usize },
+    Open { until: Instant },
+    // a single request is in flight to see whether the driver recovered
+    HalfOpen,
+}
+
+impl State {
+    fn public(&self) -> BreakerState {
+        match self {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen => BreakerState::HalfOpen,
+        }
+    }
+}
+
+/// Stops a driver's workers from calling out to a destination that keeps
+/// failing. Opens after `failure_threshold` consecutive failures, and once
+/// `cooldown` has passed lets one request through: its success closes the
+/// breaker again, its failure reopens it. Shared by all workers of a driver.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    driver: String,
+    config: BreakerConfig,
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+impl CircuitBreaker {
+    pub fn new(driver: &str, config: BreakerConfig) -> Self {
+        let breaker = Self {
+            driver: driver.to_owned(),
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+            changed: Notify::new(),
+        };
+        breaker.report(BreakerState::Closed);
+        return breaker;
+    }
+
+    pub fn open_action(&self) -> OpenAction {
+        self.config.open_action
+    }
+
+    pub fn state(&self) -> BreakerState {
+        return self.state.lock().unwrap().public();
+    }
+
+    /// Whether a request may go out now. Taking the probe slot of an open
+    /// breaker whose cooldown has passed moves it to half-open, the caller
+    /// then has to `record` the probe's outcome.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Some(Permit::Closed),
+            State::Open { until } if Instant::now() >= until => {
+                info!(driver = self.driver, "circuit breaker half-open, probing");
+                *state = State::HalfOpen;
+                self.report(BreakerState::HalfOpen);
+                Some(Permit::Probe)
+            }
+            State::Open { .. } | State::HalfOpen => None,
+        }
+    }
+
+    /// Waits until a request may go out, see `try_acquire`.
+    pub async fn acquire(&self) -> Permit {
+        loop {
+            let changed = self.changed.notified();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            let until = match *self.state.lock().unwrap() {
+                State::Open { until } => Some(until),
+                _ => None,
+            };
+            match until {
+                Some(until) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(until) => {}
+                        _ = changed => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+
+    /// Records the outcome of a request let through by `acquire`.
+    pub fn record(&self, success: bool) {
+        if self.config.failure_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let next = match (&*state, success) {
+            (State::Closed { .. }, true) => State::Closed { failures: 0 },
+            (State::HalfOpen, true) => {
+                info!(driver = self.driver, "circuit breaker closed");
+                State::Closed { failures: 0 }
+            }
+            (State::Closed { failures }, false) if failures + 1 < self.config.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Closed { .. } | State::HalfOpen, false) => {
+                warn!(
+                    driver = self.driver,
+                    cooldown = ?self.config.cooldown,
+                    "circuit breaker opened"
+                );
+                counter!("drain_driver_breaker_trips", "driver" => self.driver.clone())
+                    .increment(1);
+                State::Open {
+                    until: Instant::now() + self.config.cooldown,
+                }
+            }
+            // a request that started before the breaker opened
+            (State::Open { until }, _) => State::Open { until: *until },
+        };
+        *state = next;
+        self.report(state.public());
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    fn report(&self, state: BreakerState) {
+        // 0 closed, 1 half-open, 2 open
+        let value = match state {
+            BreakerState::Closed => 0.0,
+            BreakerState::HalfOpen => 1.0,
+            BreakerState::Open => 2.0,
+        };
+        gauge!("drain_driver_breaker_state", "driver" => self.driver.clone()).set(value);
+    }
+}
+
+/// The error messages are dead-lettered with while their driver's breaker is
+/// open.
+#[derive(Debug)]
+pub struct BreakerOpen;
+
+impl std::fmt::Display for BreakerOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "circuit breaker open")
+    }
+}
+
+impl std::error::Error for BreakerOpen {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_threshold: 3,
+                cooldown,
+                open_action: OpenAction::Buffer,
+            },
+        )
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(Duration::from_secs(60));
+        breaker.record(false);
+        breaker.record(false);
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.try_acquire(), None);
+    }
+
+    #[test]
+    fn probes_once_after_the_cooldown() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.record(false);
+        }
+        assert_eq!(breaker.try_acquire(), Some(Permit::Probe));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        // only one probe at a time
+        assert_eq!(breaker.try_acquire(), None);
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(breaker.try_acquire(), Some(Permit::Probe));
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(
+            "test",
+            BreakerConfig {
+                failure_threshold: 0,
+                ..BreakerConfig::default()
+            },
+        );
+        for _ in 0..100 {
+            breaker.record(false);
+        }
+        assert_eq!(breaker.try_acquire(), Some(Permit::Closed));
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_for_the_cooldown() {
+        let breaker = breaker(Duration::from_millis(50));
+        for _ in 0..3 {
+            breaker.record(false);
+        }
+        let started = Instant::now();
+        let permit = tokio::time::timeout(Duration::from_secs(5), breaker.acquire())
+            .await
+            .unwrap();
+        assert_eq!(permit, Permit::Probe);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    }
+}
@@ -0,0 +1,146 @@
+use axum::http::{header, HeaderMap};
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::Read;
+
+/// Why a request body couldn't be decompressed.
+#[derive(Debug)]
+pub enum DecompressError {
+    Unsupported(String),
+    TooLarge,
+    Invalid(std::io::Error),
+}
+
+impl std::fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecompressError::Unsupported(encoding) => {
+                write!(f, "unsupported content encoding {:?}", encoding)
+            }
+            DecompressError::TooLarge => write!(f, "decompressed body is too large"),
+            DecompressError::Invalid(e) => write!(f, "invalid compressed body: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+/// Undoes the `Content-Encoding` of a request body, `gzip`, `deflate` and
+/// `zstd` in the order they were applied. `None` when the body isn't
+/// compressed. Stops at `max_bytes` of output, so a small body can't expand
+/// into an unbounded one.
+pub fn decompress(
+    headers: &HeaderMap,
+    body: &[u8],
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, DecompressError> {
+    let encodings: Vec<String> = headers
+        .get_all(header::CONTENT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|encoding| encoding.trim().to_ascii_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect();
+    if encodings.is_empty() {
+        return Ok(None);
+    }
+    let mut body = body.to_vec();
+    for encoding in encodings.iter().rev() {
+        body = match encoding.as_str() {
+            "gzip" | "x-gzip" => read_capped(GzDecoder::new(&body[..]), max_bytes)?,
+            // zlib wrapped as the spec says, but raw deflate is common too
+            "deflate" => match read_capped(ZlibDecoder::new(&body[..]), max_bytes) {
+                Err(DecompressError::Invalid(_)) => {
+                    read_capped(DeflateDecoder::new(&body[..]), max_bytes)?
+                }
+                result => result?,
+            },
+            "zstd" => {
+                let decoder = zstd::Decoder::new(&body[..]).map_err(DecompressError::Invalid)?;
+                read_capped(decoder, max_bytes)?
+            }
+            _ => return Err(DecompressError::Unsupported(encoding.clone())),
+        };
+    }
+    return Ok(Some(body));
+}
+
+fn read_capped<R: Read>(reader: R, max_bytes: usize) -> Result<Vec<u8>, DecompressError> {
+    let mut output = Vec::new();
+    reader
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(DecompressError::Invalid)?;
+    if output.len() > max_bytes {
+        return Err(DecompressError::TooLarge);
+    }
+    return Ok(output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    const BODY: &[u8] = include_bytes!("fixtures/sample_2.json");
+
+    fn headers(encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
+        return headers;
+    }
+
+    #[test]
+    fn decompresses_every_encoding() -> anyhow::Result<()> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(BODY)?;
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(BODY)?;
+        let mut deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        deflate.write_all(BODY)?;
+        let zstd = zstd::encode_all(BODY, 0)?;
+        let test_data = [
+            ("gzip", gzip.finish()?),
+            ("deflate", zlib.finish()?),
+            ("deflate", deflate.finish()?),
+            ("zstd", zstd.clone()),
+            ("ZSTD, identity", zstd),
+        ];
+        for (encoding, body) in test_data {
+            let decompressed = decompress(&headers(encoding), &body, 1 << 20)?;
+            assert_eq!(decompressed.as_deref(), Some(BODY), "{}", encoding);
+        }
+        assert_eq!(decompress(&HeaderMap::new(), BODY, 1 << 20)?, None);
+        return Ok(());
+    }
+
+    #[test]
+    fn applies_encodings_in_reverse() -> anyhow::Result<()> {
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&zstd::encode_all(BODY, 0)?)?;
+        let decompressed = decompress(&headers("zstd, gzip"), &gzip.finish()?, 1 << 20)?;
+        assert_eq!(decompressed.as_deref(), Some(BODY));
+        return Ok(());
+    }
+
+    #[test]
+    fn refuses_bombs_and_unknown_encodings() -> anyhow::Result<()> {
+        let bomb = zstd::encode_all(&vec![b' '; 10 << 20][..], 19)?;
+        assert!(bomb.len() < 4096);
+        assert!(matches!(
+            decompress(&headers("zstd"), &bomb, 1 << 20),
+            Err(DecompressError::TooLarge)
+        ));
+        assert!(matches!(
+            decompress(&headers("br"), BODY, 1 << 20),
+            Err(DecompressError::Unsupported(_))
+        ));
+        assert!(matches!(
+            decompress(&headers("gzip"), BODY, 1 << 20),
+            Err(DecompressError::Invalid(_))
+        ));
+        return Ok(());
+    }
+}
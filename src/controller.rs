@@ -1,55 +1,235 @@
-use crate::types::{LogDriver, Message};
+use crate::breaker::{BreakerConfig, BreakerOpen, CircuitBreaker, OpenAction, Permit};
+use crate::deadletter::{DeadLetterFile, DeadLetterSink, DeadLetterTarget, DeadLetterWriter};
+use crate::drivers::GaveUp;
+use crate::queue::IngestReceiver;
+use crate::readiness::{DriverStatus, Readiness};
+use crate::types::{Envelope, LogDriver, Message};
+use crate::wal::{Wal, WalReplay};
 
 use anyhow::Result;
-use axum_prometheus::metrics::counter;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use axum_prometheus::metrics::{counter, gauge};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+// how often workers check on drivers that don't buffer
+const IDLE_FLUSH_CHECK: Duration = Duration::from_secs(60);
+// most messages handed to a driver in one `send_batch`
+const MAX_WORKER_BATCH: usize = 500;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(20);
+
+type DriverQueue = Arc<Mutex<mpsc::Receiver<Arc<Envelope>>>>;
+
+/// Describes how to run one kind of driver: every worker gets its own instance
+/// from `factory`, and all of them pull from a shared queue of `queue_capacity`
+/// messages.
+pub struct DriverSpec {
+    pub name: String,
+    pub factory: Box<dyn Fn() -> Box<dyn LogDriver> + Send + Sync>,
+    pub queue_capacity: usize,
+    pub concurrency: usize,
+    pub breaker: BreakerConfig,
+}
+
+/// What happened to the messages handed to one driver, reported on shutdown.
+#[derive(Debug, Default)]
+pub struct DeliveryStats {
+    delivered: AtomicUsize,
+    failed: AtomicUsize,
+    // queued for or buffered by the driver, but not delivered or failed yet
+    pending: AtomicUsize,
+    // never made it into the driver's queue before the shutdown deadline
+    abandoned: AtomicUsize,
+}
+
+impl DeliveryStats {
+    pub fn queued(&self) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn settle(&self, count: usize, delivered: bool) {
+        match delivered {
+            true => self.delivered.fetch_add(count, Ordering::Relaxed),
+            false => self.failed.fetch_add(count, Ordering::Relaxed),
+        };
+        self.pending.fetch_sub(count, Ordering::Relaxed);
+    }
+}
+
+struct DriverHandle {
+    name: String,
+    sender: mpsc::Sender<Arc<Envelope>>,
+    workers: Vec<JoinHandle<()>>,
+    stats: Arc<DeliveryStats>,
+    status: Arc<DriverStatus>,
+}
 
 pub struct Controller {
-    _sender: mpsc::UnboundedSender<Message>,
-    receiver: mpsc::UnboundedReceiver<Message>,
-    drivers: Vec<Box<dyn LogDriver>>,
+    receiver: IngestReceiver,
+    wal: Option<Arc<Wal>>,
+    replay: Option<WalReplay>,
+    specs: Vec<DriverSpec>,
+    drivers: Vec<DriverHandle>,
     processed_messages: usize,
+    shutdown_timeout: Duration,
+    // set once the ingest queue has closed
+    deadline: std::sync::OnceLock<Instant>,
+    dead_letter_target: Option<DeadLetterTarget>,
+    dead_letter: DeadLetterSink,
+    // writes to the dead-letter file until the last worker is gone
+    dead_letter_thread: Option<std::thread::JoinHandle<()>>,
+    // drivers of the endpoints that don't go to every driver
+    routes: HashMap<String, Vec<String>>,
+    // only ever dropped, tells `Readiness` whether the controller is alive
+    alive: Arc<()>,
 }
 
 impl Controller {
-    pub fn new(
-        _sender: mpsc::UnboundedSender<Message>,
-        receiver: mpsc::UnboundedReceiver<Message>,
-        drivers: Vec<Box<dyn LogDriver>>,
-    ) -> Self {
+    pub fn new(receiver: IngestReceiver, specs: Vec<DriverSpec>) -> Self {
         Self {
-            _sender,
             receiver,
-            drivers,
+            wal: None,
+            replay: None,
+            specs,
+            drivers: Vec::new(),
             processed_messages: 0,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            deadline: std::sync::OnceLock::new(),
+            dead_letter_target: None,
+            dead_letter: DeadLetterSink::None,
+            dead_letter_thread: None,
+            routes: HashMap::new(),
+            alive: Arc::new(()),
         }
     }
 
+    /// Initializes every driver instance and starts its worker.
     pub async fn init(&mut self) -> Result<()> {
-        for driver in &mut self.drivers {
-            driver.init().await?;
+        // every queue has to exist before the workers start, as any of them
+        // may be the dead-letter destination
+        let mut queues = Vec::new();
+        for spec in &self.specs {
+            let (sender, receiver) = mpsc::channel(spec.queue_capacity);
+            let queue: DriverQueue = Arc::new(Mutex::new(receiver));
+            self.drivers.push(DriverHandle {
+                name: spec.name.clone(),
+                sender,
+                workers: Vec::new(),
+                stats: Arc::new(DeliveryStats::default()),
+                status: Arc::new(DriverStatus::new(
+                    &spec.name,
+                    spec.concurrency.max(1),
+                    Arc::new(CircuitBreaker::new(&spec.name, spec.breaker.clone())),
+                )),
+            });
+            queues.push(queue);
+        }
+        self.dead_letter = match self.dead_letter_target.take() {
+            None => DeadLetterSink::None,
+            Some(DeadLetterTarget::File {
+                path,
+                max_bytes,
+                max_files,
+            }) => {
+                let file = DeadLetterFile::open(&path, max_bytes, max_files)?;
+                let (writer, thread) = DeadLetterWriter::spawn(file)?;
+                self.dead_letter_thread = Some(thread);
+                DeadLetterSink::File(writer)
+            }
+            Some(DeadLetterTarget::Driver(name)) => {
+                let Some(driver) = self.drivers.iter().find(|driver| driver.name == name) else {
+                    anyhow::bail!("dead-letter driver {:?} is not enabled", name);
+                };
+                DeadLetterSink::Driver {
+                    name,
+                    sender: driver.sender.clone(),
+                    stats: driver.stats.clone(),
+                }
+            }
+        };
+
+        for ((spec, handle), queue) in self.specs.drain(..).zip(&mut self.drivers).zip(queues) {
+            for worker in 0..spec.concurrency.max(1) {
+                let mut driver = (spec.factory)();
+                driver.init().await?;
+                debug!(driver = spec.name, worker, "driver initialized");
+                handle.status.initialized();
+                handle.workers.push(tokio::spawn(run_worker(
+                    spec.name.clone(),
+                    driver,
+                    queue.clone(),
+                    handle.stats.clone(),
+                    handle.status.clone(),
+                    self.dead_letter.clone(),
+                )));
+            }
         }
         info!("All drivers initialized");
         Ok(())
     }
 
+    /// What `/ready` reports on, available once `init` has run.
+    pub fn readiness(&self) -> Readiness {
+        return Readiness::new(
+            self.drivers
+                .iter()
+                .map(|driver| driver.status.clone())
+                .collect(),
+            Arc::downgrade(&self.alive),
+        );
+    }
+
+    /// Where messages go once a driver gives up on them, must be set before
+    /// `init`.
+    pub fn dead_letter(&mut self, target: DeadLetterTarget) {
+        self.dead_letter_target = Some(target);
+    }
+
+    /// Queues entries recovered from the write-ahead log ahead of anything
+    /// new, and keeps whatever is abandoned on shutdown in the log.
+    pub fn wal(&mut self, wal: Arc<Wal>, replay: WalReplay) {
+        self.wal = Some(wal);
+        self.replay = Some(replay);
+    }
+
+    /// Limits the messages of named endpoints to their drivers, any other
+    /// message still goes to every driver.
+    pub fn routes(&mut self, routes: HashMap<String, Vec<String>>) {
+        self.routes = routes;
+    }
+
+    /// How long draining the queues and shutting the drivers down may take
+    /// once the ingest queue has closed.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Fans messages out to the driver queues until every sender is dropped,
+    /// then gives the drivers until the shutdown deadline to drain their
+    /// queues and shut down.
     pub async fn run(&mut self) {
+        if let Some(replay) = self.replay.take() {
+            self.run_replay(replay).await;
+        }
         info!("waiting for logs to send to drivers...");
-        while let Some(message) = self.receiver.recv().await {
-            let id = message.deployment_id.clone();
+        while let Some(envelope) = self.receiver.recv().await {
+            let id = envelope.message.deployment_id.clone();
             debug!(?id, "processing message...");
-            match self.handle_message(&message).await {
-                Ok(_) => {
-                    debug!(?id, "message handled successfully");
-                    self.processed_messages += 1;
-                    counter!("drain_processed_messages").increment(1);
-                }
-                Err(e) => {
-                    error!(?id, "failed to handle message: {:?}", e);
-                    counter!("drain_failed_messages").increment(1);
-                }
+            if self.deadline.get().is_none() && self.receiver.is_closed() {
+                info!(
+                    remaining = self.receiver.len() + 1,
+                    "log queue closed, draining..."
+                );
+                self.deadline();
             }
+            self.dispatch(Arc::new(envelope)).await;
+            self.processed_messages += 1;
+            counter!("drain_processed_messages").increment(1);
             if self.processed_messages % 100 == 0 {
                 info!(
                     processed_messages = self.processed_messages,
@@ -57,14 +237,1095 @@ impl Controller {
                 );
             }
         }
+        info!("log queue closed, waiting for drivers to finish...");
+        let deadline = self.deadline();
+        let (mut delivered, mut failed, mut abandoned) = (0, 0, 0);
+        let mut drivers = std::mem::take(&mut self.drivers);
+        // the dead-letter driver's queue stays open until the other drivers
+        // are done with it, so it goes last
+        if let DeadLetterSink::Driver { name, .. } = &self.dead_letter {
+            drivers.sort_by_key(|driver| &driver.name == name);
+        }
+        self.dead_letter = DeadLetterSink::None;
+        let drivers: Vec<_> = drivers
+            .into_iter()
+            .map(|driver| (driver.name, driver.workers, driver.stats))
+            .collect();
+        for (name, workers, stats) in drivers {
+            for mut worker in workers {
+                match tokio::time::timeout_at(deadline, &mut worker).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!(driver = name, "driver worker failed: {:?}", e),
+                    Err(_) => {
+                        warn!(
+                            driver = name,
+                            "shutdown deadline reached, abandoning driver"
+                        );
+                        self.abandon();
+                        worker.abort();
+                    }
+                }
+            }
+            delivered += stats.delivered.load(Ordering::Relaxed);
+            failed += stats.failed.load(Ordering::Relaxed);
+            abandoned +=
+                stats.pending.load(Ordering::Relaxed) + stats.abandoned.load(Ordering::Relaxed);
+        }
+        if let Some(thread) = self.dead_letter_thread.take() {
+            // aborted workers may still hold a writer, so this waits off the runtime
+            if tokio::task::spawn_blocking(move || thread.join())
+                .await
+                .is_err()
+            {
+                error!("dead-letter writer panicked");
+            }
+        }
+        info!(delivered, failed, abandoned, "shutdown complete");
     }
 
-    async fn handle_message(&mut self, message: &Message) -> Result<()> {
-        for driver in &mut self.drivers {
-            if let Err(e) = driver.send_log(message).await {
-                error!("Failed to send log to driver: {:?}", e);
+    /// Waits for room in the driver queues rather than dropping anything, as
+    /// these messages were accepted before the restart.
+    async fn run_replay(&mut self, replay: WalReplay) {
+        let mut replayed = 0;
+        for envelope in replay {
+            let envelope = Arc::new(envelope);
+            for driver in self.drivers_for(&envelope.message) {
+                if driver.sender.send(envelope.clone()).await.is_err() {
+                    error!(
+                        driver = driver.name,
+                        "driver queue closed, dropping message"
+                    );
+                    counter!("drain_failed_messages").increment(1);
+                    driver.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    envelope.keep();
+                    continue;
+                }
+                driver.stats.pending.fetch_add(1, Ordering::Relaxed);
             }
+            replayed += 1;
         }
-        Ok(())
+        if replayed > 0 {
+            info!(replayed, "replayed write-ahead log");
+        }
+    }
+
+    /// Queues the message for every driver without waiting on any of them:
+    /// a driver whose queue is full misses the message, which goes to the
+    /// dead-letter sink, rather than holding up the others. Only once the
+    /// ingest queue has closed does this wait for room, until the shutdown
+    /// deadline at most.
+    async fn dispatch(&self, envelope: Arc<Envelope>) {
+        for driver in self.drivers_for(&envelope.message) {
+            let sent = if self.deadline.get().is_none() {
+                driver.sender.try_send(envelope.clone())
+            } else {
+                tokio::select! {
+                    sent = driver.sender.send(envelope.clone()) => {
+                        sent.map_err(|e| mpsc::error::TrySendError::Closed(e.0))
+                    }
+                    _ = self.past_deadline() => {
+                        self.abandon();
+                        driver.stats.abandoned.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+            };
+            match sent {
+                Ok(_) => {
+                    driver.stats.pending.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!(
+                        driver = driver.name,
+                        id = envelope.message.id,
+                        "driver queue full, dropping message"
+                    );
+                    counter!("drain_driver_dropped_messages", "driver" => driver.name.clone())
+                        .increment(1);
+                    counter!("drain_failed_messages").increment(1);
+                    driver.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    self.dead_letter.send(
+                        &driver.name,
+                        std::slice::from_ref(&envelope),
+                        &anyhow::anyhow!("driver queue full"),
+                        0,
+                    );
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    error!(
+                        driver = driver.name,
+                        "driver queue closed, dropping message"
+                    );
+                    counter!("drain_failed_messages").increment(1);
+                    driver.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    envelope.keep();
+                }
+            }
+        }
+    }
+
+    /// When draining has to be done by, set once the ingest queue has closed.
+    fn deadline(&self) -> Instant {
+        *self
+            .deadline
+            .get_or_init(|| Instant::now() + self.shutdown_timeout)
+    }
+
+    /// Resolves at the shutdown deadline, never while the ingest queue is open.
+    async fn past_deadline(&self) {
+        self.receiver.closed().await;
+        tokio::time::sleep_until(self.deadline()).await;
+    }
+
+    /// The drivers a message is routed to.
+    fn drivers_for<'a>(&'a self, message: &Message) -> impl Iterator<Item = &'a DriverHandle> {
+        let route = message
+            .endpoint
+            .as_ref()
+            .and_then(|endpoint| self.routes.get(endpoint));
+        self.drivers.iter().filter(move |driver| match route {
+            Some(drivers) => drivers.contains(&driver.name),
+            None => true,
+        })
+    }
+
+    /// Keeps the write-ahead log from acknowledging anything from here on,
+    /// so abandoned messages are replayed on the next start.
+    fn abandon(&self) {
+        if let Some(wal) = &self.wal {
+            wal.freeze();
+        }
+    }
+}
+
+/// Feeds batches from the queue to one driver instance, flushing it and
+/// checking its health on its interval and shutting it down when the queue
+/// closes. Buffering drivers hold on to their messages' log entries until the
+/// driver reports them delivered or given up on. After a failed flush the
+/// worker takes no new batches, retrying the flush on its interval until it
+/// succeeds or the queue is closed. Nothing is sent while the driver's
+/// breaker is open.
+async fn run_worker(
+    name: String,
+    mut driver: Box<dyn LogDriver>,
+    queue: DriverQueue,
+    stats: Arc<DeliveryStats>,
+    status: Arc<DriverStatus>,
+    dead_letter: DeadLetterSink,
+) {
+    let breaker = &status.breaker;
+    let buffered = driver.flush_interval().is_some();
+    let mut flush_timer =
+        tokio::time::interval(driver.flush_interval().unwrap_or(IDLE_FLUSH_CHECK));
+    // buffered messages by the number the driver knows them by, see `Settled`
+    let mut unflushed = HashMap::new();
+    let mut next_seq: u64 = 0;
+    let mut flush_failed = false;
+    // a batch waiting for the open breaker to let it through
+    let mut held = Vec::new();
+    loop {
+        let (batch, permit) = tokio::select! {
+            batch = next_batch(&name, &queue), if held.is_empty() && !flush_failed => {
+                if batch.is_empty() {
+                    break;
+                }
+                match breaker.try_acquire() {
+                    Some(permit) => (batch, permit),
+                    None if breaker.open_action() == OpenAction::Buffer => {
+                        held = batch;
+                        continue;
+                    }
+                    None => {
+                        counter!("drain_driver_breaker_rejected_messages", "driver" => name.clone())
+                            .increment(batch.len() as u64);
+                        let error = anyhow::Error::new(BreakerOpen).context(GaveUp {
+                            attempts: 0,
+                            permanent: false,
+                        });
+                        settle(&name, &stats, &dead_letter, &batch, Err(error));
+                        continue;
+                    }
+                }
+            }
+            // waiting here rather than before the select keeps the flush
+            // timer, health checks and shutdown going while the breaker is open
+            permit = breaker.acquire(), if !held.is_empty() => (std::mem::take(&mut held), permit),
+            _ = flush_timer.tick() => {
+                if unflushed.is_empty() {
+                    let _ = flush(&name, driver.as_mut()).await;
+                } else if breaker.try_acquire().is_some() {
+                    flush_failed = !flush_buffered(
+                        &name,
+                        driver.as_mut(),
+                        &status,
+                        &stats,
+                        &dead_letter,
+                        &mut unflushed,
+                    )
+                    .await;
+                }
+                check_health(&name, driver.as_ref()).await;
+                // the last flush on shutdown settles what is still buffered
+                if flush_failed
+                    && queue
+                        .try_lock()
+                        .is_ok_and(|queue| queue.is_closed() && queue.is_empty())
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+        let sent = send(&name, driver.as_mut(), &batch).await;
+        if buffered && sent.is_ok() {
+            for envelope in batch {
+                unflushed.insert(next_seq, envelope);
+                next_seq += 1;
+            }
+            // what an early flush sent, or gave up on, is settled right away
+            settle_buffered(&name, driver.as_mut(), &stats, &dead_letter, &mut unflushed);
+            // buffering the probe didn't reach the destination, the flush is
+            // what tells whether it recovered
+            if permit == Permit::Probe {
+                flush_failed = !flush_buffered(
+                    &name,
+                    driver.as_mut(),
+                    &status,
+                    &stats,
+                    &dead_letter,
+                    &mut unflushed,
+                )
+                .await;
+            }
+        } else {
+            status.record(&sent);
+            settle(&name, &stats, &dead_letter, &batch, sent);
+        }
+    }
+    let flushed = shutdown(&name, driver.as_mut()).await;
+    settle_buffered(&name, driver.as_mut(), &stats, &dead_letter, &mut unflushed);
+    if !unflushed.is_empty() {
+        let error = flushed
+            .err()
+            .unwrap_or_else(|| anyhow::anyhow!("driver stopped without reporting on messages"));
+        let remaining: Vec<_> = unflushed.into_values().collect();
+        settle(&name, &stats, &dead_letter, &remaining, Err(error));
+    }
+}
+
+/// Flushes a buffering driver and settles what it reports on. Messages a
+/// failed flush couldn't send are kept by the driver for the next one, so
+/// they stay unsettled. Returns whether the flush succeeded.
+async fn flush_buffered(
+    name: &str,
+    driver: &mut dyn LogDriver,
+    status: &DriverStatus,
+    stats: &DeliveryStats,
+    dead_letter: &DeadLetterSink,
+    unflushed: &mut HashMap<u64, Arc<Envelope>>,
+) -> bool {
+    let flushed = flush(name, driver).await;
+    status.record(&flushed);
+    settle_buffered(name, driver, stats, dead_letter, unflushed);
+    return flushed.is_ok();
+}
+
+/// Settles the buffered messages the driver reports delivered or given up on.
+fn settle_buffered(
+    name: &str,
+    driver: &mut dyn LogDriver,
+    stats: &DeliveryStats,
+    dead_letter: &DeadLetterSink,
+    unflushed: &mut HashMap<u64, Arc<Envelope>>,
+) {
+    let settled = driver.take_settled();
+    let delivered = settled
+        .delivered
+        .iter()
+        .filter(|seq| unflushed.remove(seq).is_some())
+        .count();
+    stats.settle(delivered, true);
+    for (seqs, error) in settled.failed {
+        let batch: Vec<_> = seqs
+            .iter()
+            .filter_map(|seq| unflushed.remove(seq))
+            .collect();
+        counter!("drain_driver_failed_messages", "driver" => name.to_owned())
+            .increment(batch.len() as u64);
+        counter!("drain_failed_messages").increment(batch.len() as u64);
+        fail(name, stats, dead_letter, &batch, &error);
+    }
+}
+
+/// Records the outcome for a batch, handing it to the dead-letter sink if the
+/// driver gave up on it.
+fn settle(
+    name: &str,
+    stats: &DeliveryStats,
+    dead_letter: &DeadLetterSink,
+    batch: &[Arc<Envelope>],
+    result: Result<()>,
+) {
+    match &result {
+        Ok(()) => stats.settle(batch.len(), true),
+        Err(e) => fail(name, stats, dead_letter, batch, e),
+    }
+}
+
+/// Hands a batch the driver gave up on to the dead-letter sink.
+fn fail(
+    name: &str,
+    stats: &DeliveryStats,
+    dead_letter: &DeadLetterSink,
+    batch: &[Arc<Envelope>],
+    error: &anyhow::Error,
+) {
+    let attempts = error
+        .downcast_ref::<GaveUp>()
+        .map_or(1, |gave_up| gave_up.attempts);
+    dead_letter.send(name, batch, error, attempts);
+    stats.settle(batch.len(), false);
+}
+
+/// Waits for the next message and takes whatever else is already queued along
+/// with it, an empty batch means the queue is closed.
+async fn next_batch(name: &str, queue: &DriverQueue) -> Vec<Arc<Envelope>> {
+    let mut queue = queue.lock().await;
+    let mut batch = Vec::new();
+    if let Some(envelope) = queue.recv().await {
+        batch.push(envelope);
+        while batch.len() < MAX_WORKER_BATCH {
+            let Ok(envelope) = queue.try_recv() else {
+                break;
+            };
+            batch.push(envelope);
+        }
+    }
+    gauge!("drain_driver_queue_depth", "driver" => name.to_owned()).set(queue.len() as f64);
+    return batch;
+}
+
+async fn send(name: &str, driver: &mut dyn LogDriver, batch: &[Arc<Envelope>]) -> Result<()> {
+    let messages: Vec<&Message> = batch.iter().map(|envelope| &envelope.message).collect();
+    match driver.send_batch(&messages).await {
+        Ok(_) => {
+            counter!("drain_driver_sent_messages", "driver" => name.to_owned())
+                .increment(batch.len() as u64);
+            return Ok(());
+        }
+        Err(e) => {
+            error!(
+                driver = name,
+                messages = batch.len(),
+                "Failed to send logs to driver: {:?}",
+                e
+            );
+            counter!("drain_driver_failed_messages", "driver" => name.to_owned())
+                .increment(batch.len() as u64);
+            counter!("drain_failed_messages").increment(batch.len() as u64);
+            return Err(e);
+        }
+    }
+}
+
+async fn check_health(name: &str, driver: &dyn LogDriver) {
+    let healthy = match driver.health().await {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(driver = name, "driver unhealthy: {:?}", e);
+            false
+        }
+    };
+    gauge!("drain_driver_healthy", "driver" => name.to_owned()).set(healthy as u8 as f64);
+}
+
+async fn flush(name: &str, driver: &mut dyn LogDriver) -> Result<()> {
+    let result = driver.flush().await;
+    if let Err(e) = &result {
+        error!(driver = name, "Failed to flush driver: {:?}", e);
+        counter!("drain_driver_failed_flushes", "driver" => name.to_owned()).increment(1);
+    }
+    return result;
+}
+
+async fn shutdown(name: &str, driver: &mut dyn LogDriver) -> Result<()> {
+    let result = driver.shutdown().await;
+    if let Err(e) = &result {
+        error!(driver = name, "Failed to shut down driver: {:?}", e);
+        counter!("drain_driver_failed_flushes", "driver" => name.to_owned()).increment(1);
+    }
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::OverflowPolicy;
+    use async_trait::async_trait;
+
+    // records the ids it receives, or never finishes sending when stalled
+    struct TestDriver {
+        stalled: bool,
+        received: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogDriver for TestDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send_log(&mut self, message: &Message) -> Result<()> {
+            if self.stalled {
+                std::future::pending::<()>().await;
+            }
+            self.received.lock().unwrap().push(message.id.clone());
+            Ok(())
+        }
+    }
+
+    fn spec(name: &str, stalled: bool, received: Arc<std::sync::Mutex<Vec<String>>>) -> DriverSpec {
+        DriverSpec {
+            name: name.to_owned(),
+            factory: Box::new(move || {
+                Box::new(TestDriver {
+                    stalled,
+                    received: received.clone(),
+                })
+            }),
+            queue_capacity: 2,
+            concurrency: 1,
+            breaker: BreakerConfig::default(),
+        }
+    }
+
+    fn message(id: usize) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "timestamp": 1,
+            "source": "lambda",
+            "projectName": "project",
+            "projectId": "prj_1",
+            "deploymentId": "dpl_1",
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    fn wal_config(dir: &std::path::Path) -> crate::wal::WalConfig {
+        crate::wal::WalConfig {
+            dir: dir.to_owned(),
+            max_segment_bytes: 1024 * 1024,
+            fsync: crate::wal::FsyncPolicy::Never,
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_driver_does_not_block_others() -> Result<()> {
+        for policy in [
+            OverflowPolicy::Reject,
+            OverflowPolicy::DropOldest,
+            OverflowPolicy::DropNewest,
+        ] {
+            let stalled = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let healthy = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let (tx, rx) = crate::queue::ingest_queue(100, policy, None)?;
+            let mut controller = Controller::new(
+                rx,
+                vec![
+                    spec("stalled", true, stalled.clone()),
+                    spec("healthy", false, healthy.clone()),
+                ],
+            );
+            controller.init().await?;
+            let readiness = controller.readiness();
+            tokio::spawn(async move { controller.run().await });
+
+            for id in 0..10 {
+                tx.send_batch(vec![message(id).into()])?;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while healthy.lock().unwrap().len() < 10 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await?;
+            assert!(stalled.lock().unwrap().is_empty());
+            assert_eq!(tx.depth(), 0, "{:?}", policy);
+            assert!(readiness.report(&tx).ready, "{:?}", policy);
+        }
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn endpoint_messages_go_to_their_drivers() -> Result<()> {
+        let loki = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let cloudwatch = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let mut controller = Controller::new(
+            rx,
+            vec![
+                spec("loki", false, loki.clone()),
+                spec("cloudwatch", false, cloudwatch.clone()),
+            ],
+        );
+        controller.routes(HashMap::from([(
+            String::from("team-a"),
+            vec![String::from("loki")],
+        )]));
+        controller.init().await?;
+        let task = tokio::spawn(async move { controller.run().await });
+
+        // team-b has no drivers of its own, so it goes everywhere
+        for (id, endpoint) in [None, Some("team-a"), Some("team-b")]
+            .into_iter()
+            .enumerate()
+        {
+            let mut message = message(id);
+            message.endpoint = endpoint.map(str::to_owned);
+            tx.send_batch(vec![message.into()])?;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), task).await??;
+        assert_eq!(*loki.lock().unwrap(), vec!["0", "1", "2"]);
+        assert_eq!(*cloudwatch.lock().unwrap(), vec!["0", "2"]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn workers_share_the_driver_queue() -> Result<()> {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let mut driver = spec("test", false, received.clone());
+        driver.queue_capacity = 100;
+        driver.concurrency = 3;
+        let mut controller = Controller::new(rx, vec![driver]);
+        controller.init().await?;
+
+        for id in 0..50 {
+            tx.send_batch(vec![message(id).into()])?;
+        }
+        drop(tx);
+        controller.run().await;
+
+        let mut ids = received.lock().unwrap().clone();
+        ids.sort_by_key(|id| id.parse::<usize>().unwrap());
+        assert_eq!(ids, (0..50).map(|id| id.to_string()).collect::<Vec<_>>());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_sent_as_one_batch() -> Result<()> {
+        // counts send_batch calls, the first one blocks until every message is queued
+        struct BatchDriver {
+            batches: Arc<std::sync::Mutex<Vec<usize>>>,
+            gate: Arc<tokio::sync::Semaphore>,
+        }
+
+        #[async_trait]
+        impl LogDriver for BatchDriver {
+            async fn init(&mut self) -> Result<()> {
+                Ok(())
+            }
+            async fn send_log(&mut self, _: &Message) -> Result<()> {
+                Ok(())
+            }
+            async fn send_batch(&mut self, messages: &[&Message]) -> Result<()> {
+                let _permit = self.gate.acquire().await?;
+                self.batches.lock().unwrap().push(messages.len());
+                Ok(())
+            }
+        }
+
+        let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let (driver_batches, driver_gate) = (batches.clone(), gate.clone());
+        let mut controller = Controller::new(
+            rx,
+            vec![DriverSpec {
+                name: "batch".to_owned(),
+                factory: Box::new(move || {
+                    Box::new(BatchDriver {
+                        batches: driver_batches.clone(),
+                        gate: driver_gate.clone(),
+                    })
+                }),
+                queue_capacity: 100,
+                concurrency: 1,
+                breaker: BreakerConfig::default(),
+            }],
+        );
+        controller.init().await?;
+        let running = tokio::spawn(async move { controller.run().await });
+
+        tx.send_batch(vec![message(0).into()])?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        for id in 1..10 {
+            tx.send_batch(vec![message(id).into()])?;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        gate.add_permits(100);
+        drop(tx);
+        running.await?;
+        assert_eq!(*batches.lock().unwrap(), vec![1, 9]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn replay_is_sent_before_new_messages() -> Result<()> {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let mut controller = Controller::new(rx, vec![spec("test", false, received.clone())]);
+        controller.init().await?;
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(wal_config(dir.path()))?;
+        std::mem::forget(wal.append((0..5).map(message).collect()).await?);
+        drop(wal);
+        let (wal, replay) = Wal::open(wal_config(dir.path()))?;
+        controller.wal(wal, replay);
+
+        tx.send_batch(vec![message(5).into()])?;
+        drop(tx);
+        controller.run().await;
+
+        let ids = received.lock().unwrap().clone();
+        assert_eq!(ids, (0..6).map(|id| id.to_string()).collect::<Vec<_>>());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn shutdown_abandons_stalled_drivers_at_the_deadline() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(wal_config(dir.path()))?;
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let stalled = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut controller = Controller::new(rx, vec![spec("stalled", true, stalled)]);
+        controller.init().await?;
+        controller.shutdown_timeout(Duration::from_millis(50));
+        let (_, replay) = Wal::open(wal_config(&dir.path().join("empty")))?;
+        controller.wal(wal.clone(), replay);
+
+        tx.send_batch(wal.append((0..3).map(message).collect()).await?)?;
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), controller.run()).await?;
+        drop(controller);
+        // let the aborted worker drop its messages, then checkpoint
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        wal.sync()?;
+
+        // nothing was delivered, so everything is replayed on the next start
+        let (_wal, replay) = Wal::open(wal_config(dir.path()))?;
+        let ids: Vec<String> = replay.map(|envelope| envelope.message.id).collect();
+        assert_eq!(ids, vec!["0", "1", "2"]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn failed_messages_stay_in_the_wal() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(wal_config(dir.path()))?;
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut controller = Controller::new(
+            rx,
+            vec![
+                flaky_spec(
+                    down,
+                    Arc::new(AtomicUsize::new(0)),
+                    Arc::new(std::sync::Mutex::new(Vec::new())),
+                    BreakerConfig::default(),
+                ),
+                spec("healthy", false, received.clone()),
+            ],
+        );
+        controller.init().await?;
+        let (_, replay) = Wal::open(wal_config(&dir.path().join("empty")))?;
+        controller.wal(wal.clone(), replay);
+
+        tx.send_batch(wal.append((0..3).map(message).collect()).await?)?;
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), controller.run()).await?;
+        drop(controller);
+        wal.sync()?;
+
+        // the healthy driver got them, but without a dead-letter sink the
+        // failed driver's messages are replayed on the next start
+        assert_eq!(received.lock().unwrap().len(), 3);
+        let (_wal, replay) = Wal::open(wal_config(dir.path()))?;
+        let ids: Vec<String> = replay.map(|envelope| envelope.message.id).collect();
+        assert_eq!(ids, vec!["0", "1", "2"]);
+        return Ok(());
+    }
+
+    // fails while `down` is set, counting every call that reached it
+    struct FlakyDriver {
+        down: Arc<std::sync::atomic::AtomicBool>,
+        calls: Arc<AtomicUsize>,
+        received: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogDriver for FlakyDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send_log(&mut self, message: &Message) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if self.down.load(Ordering::Relaxed) {
+                anyhow::bail!("destination down");
+            }
+            self.received.lock().unwrap().push(message.id.clone());
+            Ok(())
+        }
+    }
+
+    fn flaky_spec(
+        down: Arc<std::sync::atomic::AtomicBool>,
+        calls: Arc<AtomicUsize>,
+        received: Arc<std::sync::Mutex<Vec<String>>>,
+        breaker: BreakerConfig,
+    ) -> DriverSpec {
+        DriverSpec {
+            name: "flaky".to_owned(),
+            factory: Box::new(move || {
+                Box::new(FlakyDriver {
+                    down: down.clone(),
+                    calls: calls.clone(),
+                    received: received.clone(),
+                })
+            }),
+            queue_capacity: 100,
+            concurrency: 1,
+            breaker,
+        }
+    }
+
+    #[tokio::test]
+    async fn open_breaker_dead_letters_without_calling_the_driver() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let mut controller = Controller::new(
+            rx,
+            vec![flaky_spec(
+                down.clone(),
+                calls.clone(),
+                received.clone(),
+                BreakerConfig {
+                    failure_threshold: 2,
+                    cooldown: Duration::from_millis(100),
+                    open_action: OpenAction::DeadLetter,
+                },
+            )],
+        );
+        let path = dir.path().join("dead-letters.ndjson");
+        controller.dead_letter(DeadLetterTarget::File {
+            path: path.clone(),
+            max_bytes: u64::MAX,
+            max_files: 1,
+        });
+        controller.init().await?;
+        let readiness = controller.readiness();
+        let running = tokio::spawn(async move { controller.run().await });
+
+        for id in 0..5 {
+            tx.send_batch(vec![message(id).into()])?;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        let report = serde_json::to_value(readiness.report(&tx))?;
+        assert_eq!(report["drivers"]["flaky"]["breaker"], "open");
+
+        // the first message after the cooldown probes and closes the breaker
+        down.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for id in 5..7 {
+            tx.send_batch(vec![message(id).into()])?;
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(tx);
+        running.await?;
+
+        assert_eq!(*received.lock().unwrap(), vec!["5", "6"]);
+        let attempts: Vec<(String, usize)> = crate::deadletter::read(&path)?
+            .map(|entry| (entry.message.id, entry.attempts))
+            .collect();
+        assert_eq!(
+            attempts,
+            vec![
+                ("0".to_owned(), 1),
+                ("1".to_owned(), 1),
+                ("2".to_owned(), 0),
+                ("3".to_owned(), 0),
+                ("4".to_owned(), 0),
+            ]
+        );
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn open_breaker_holds_messages_until_the_driver_recovers() -> Result<()> {
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let mut controller = Controller::new(
+            rx,
+            vec![flaky_spec(
+                down.clone(),
+                calls.clone(),
+                received.clone(),
+                BreakerConfig {
+                    failure_threshold: 1,
+                    cooldown: Duration::from_millis(100),
+                    open_action: OpenAction::Buffer,
+                },
+            )],
+        );
+        controller.init().await?;
+        let running = tokio::spawn(async move { controller.run().await });
+
+        tx.send_batch(vec![message(0).into()])?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for id in 1..4 {
+            tx.send_batch(vec![message(id).into()])?;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        down.store(false, Ordering::Relaxed);
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), running).await??;
+        assert_eq!(*received.lock().unwrap(), vec!["1", "2", "3"]);
+        return Ok(());
+    }
+
+    // buffers whatever it is sent, flushing it to `received` unless `down` is
+    // set, and giving up for good on messages with the `invalid` id
+    struct BufferingDriver {
+        down: Arc<std::sync::atomic::AtomicBool>,
+        invalid: &'static str,
+        next_seq: u64,
+        buffer: Vec<(u64, String)>,
+        received: Arc<std::sync::Mutex<Vec<String>>>,
+        settled: crate::types::Settled,
+    }
+
+    impl BufferingDriver {
+        fn new(
+            down: Arc<std::sync::atomic::AtomicBool>,
+            received: Arc<std::sync::Mutex<Vec<String>>>,
+        ) -> Self {
+            return Self {
+                down,
+                invalid: "",
+                next_seq: 0,
+                buffer: Vec::new(),
+                received,
+                settled: Default::default(),
+            };
+        }
+    }
+
+    #[async_trait]
+    impl LogDriver for BufferingDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send_log(&mut self, message: &Message) -> Result<()> {
+            self.buffer.push((self.next_seq, message.id.clone()));
+            self.next_seq += 1;
+            Ok(())
+        }
+        async fn flush(&mut self) -> Result<()> {
+            if self.down.load(Ordering::Relaxed) {
+                anyhow::bail!("destination down");
+            }
+            for (seq, id) in self.buffer.drain(..) {
+                if id == self.invalid {
+                    let error = anyhow::anyhow!("invalid").context(GaveUp {
+                        attempts: 1,
+                        permanent: true,
+                    });
+                    self.settled.failed.push((vec![seq], Arc::new(error)));
+                } else {
+                    self.received.lock().unwrap().push(id);
+                    self.settled.delivered.push(seq);
+                }
+            }
+            Ok(())
+        }
+        fn take_settled(&mut self) -> crate::types::Settled {
+            std::mem::take(&mut self.settled)
+        }
+        fn flush_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+    }
+
+    // fails every send, counting the flushes the worker's timer makes
+    struct FailingDriver {
+        flushes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl LogDriver for FailingDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send_log(&mut self, _message: &Message) -> Result<()> {
+            anyhow::bail!("destination down");
+        }
+        async fn flush(&mut self) -> Result<()> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        fn flush_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(10))
+        }
+    }
+
+    #[tokio::test]
+    async fn open_breaker_does_not_stop_the_flush_timer() -> Result<()> {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let factory = {
+            let flushes = flushes.clone();
+            move || -> Box<dyn LogDriver> {
+                Box::new(FailingDriver {
+                    flushes: flushes.clone(),
+                })
+            }
+        };
+        let mut controller = Controller::new(
+            rx,
+            vec![DriverSpec {
+                name: "failing".to_owned(),
+                factory: Box::new(factory),
+                queue_capacity: 100,
+                concurrency: 1,
+                breaker: BreakerConfig {
+                    failure_threshold: 1,
+                    cooldown: Duration::from_secs(60),
+                    open_action: OpenAction::Buffer,
+                },
+            }],
+        );
+        controller.init().await?;
+        let running = tokio::spawn(async move { controller.run().await });
+
+        tx.send_batch(vec![message(0).into()])?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // held until the breaker lets it through
+        tx.send_batch(vec![message(1).into()])?;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let before = flushes.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(flushes.load(Ordering::Relaxed) > before);
+        running.abort();
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn failed_flush_keeps_messages_until_a_retry_succeeds() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let factory = {
+            let (down, received) = (down.clone(), received.clone());
+            move || -> Box<dyn LogDriver> {
+                Box::new(BufferingDriver::new(down.clone(), received.clone()))
+            }
+        };
+        let mut controller = Controller::new(
+            rx,
+            vec![DriverSpec {
+                name: "buffering".to_owned(),
+                factory: Box::new(factory),
+                queue_capacity: 100,
+                concurrency: 1,
+                breaker: BreakerConfig {
+                    failure_threshold: 0,
+                    ..BreakerConfig::default()
+                },
+            }],
+        );
+        let path = dir.path().join("dead-letters.ndjson");
+        controller.dead_letter(DeadLetterTarget::File {
+            path: path.clone(),
+            max_bytes: u64::MAX,
+            max_files: 1,
+        });
+        controller.init().await?;
+        let running = tokio::spawn(async move { controller.run().await });
+
+        tx.send_batch((0..3).map(|id| message(id).into()).collect())?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the worker waits for the failed flush before taking more
+        tx.send_batch(vec![message(3).into()])?;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(received.lock().unwrap().is_empty());
+
+        down.store(false, Ordering::Relaxed);
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), running).await??;
+        assert_eq!(*received.lock().unwrap(), vec!["0", "1", "2", "3"]);
+        assert_eq!(crate::deadletter::read(&path)?.count(), 0);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn dead_letters_buffered_messages_the_driver_gives_up_on() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (tx, rx) = crate::queue::ingest_queue(100, OverflowPolicy::Reject, None)?;
+        let factory = {
+            let (down, received) = (down.clone(), received.clone());
+            move || -> Box<dyn LogDriver> {
+                let mut driver = BufferingDriver::new(down.clone(), received.clone());
+                driver.invalid = "1";
+                Box::new(driver)
+            }
+        };
+        let mut controller = Controller::new(
+            rx,
+            vec![DriverSpec {
+                name: "buffering".to_owned(),
+                factory: Box::new(factory),
+                queue_capacity: 100,
+                concurrency: 1,
+                breaker: BreakerConfig::default(),
+            }],
+        );
+        let path = dir.path().join("dead-letters.ndjson");
+        controller.dead_letter(DeadLetterTarget::File {
+            path: path.clone(),
+            max_bytes: u64::MAX,
+            max_files: 1,
+        });
+        controller.init().await?;
+        let running = tokio::spawn(async move { controller.run().await });
+
+        tx.send_batch((0..3).map(|id| message(id).into()).collect())?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the worker doesn't wait on a flush that only gave up on messages
+        tx.send_batch(vec![message(3).into()])?;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*received.lock().unwrap(), vec!["0", "2", "3"]);
+
+        drop(tx);
+        tokio::time::timeout(Duration::from_secs(5), running).await??;
+        let dead: Vec<String> = crate::deadletter::read(&path)?
+            .map(|entry| entry.message.id)
+            .collect();
+        assert_eq!(dead, vec!["1"]);
+        return Ok(());
     }
 }
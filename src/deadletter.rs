@@ -0,0 +1,492 @@
+use crate::controller::DeliveryStats;
+use crate::drivers::GaveUp;
+use crate::types::{Envelope, LogDriver, Message};
+
+use anyhow::{Context, Result};
+use axum_prometheus::metrics::counter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// A message a driver gave up on, as written to the dead-letter file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeadLetter {
+    pub driver: String,
+    pub error: String,
+    pub attempts: usize,
+    // unix milliseconds when the message was given up on
+    pub failed_at: i64,
+    pub message: Message,
+}
+
+/// Where messages that a driver gave up on are sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeadLetterTarget {
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        max_files: usize,
+    },
+    Driver(String),
+}
+
+/// Newline delimited JSON, rotated to `<path>.1` .. `<path>.<max_files>` once
+/// it grows past `max_bytes`.
+#[derive(Debug)]
+pub struct DeadLetterFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    bytes: u64,
+}
+
+impl DeadLetterFile {
+    /// Refuses `max_files` of 0, which would leave nowhere to rotate to.
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> Result<Self> {
+        if max_files == 0 {
+            anyhow::bail!("{} has to keep at least one rotated file", path.display());
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("creating dead-letter directory {}", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening dead-letter file {}", path.display()))?;
+        let bytes = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_owned(),
+            max_bytes,
+            max_files,
+            file,
+            bytes,
+        })
+    }
+
+    pub fn write<T: Serialize>(&mut self, entry: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        if self.bytes > 0 && self.bytes + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.bytes += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+/// Reads the entries of a dead-letter file, skipping unreadable lines.
+pub fn read(path: &Path) -> Result<impl Iterator<Item = DeadLetter>> {
+    let file =
+        File::open(path).with_context(|| format!("opening dead-letter file {}", path.display()))?;
+    Ok(BufReader::new(file)
+        .lines()
+        .enumerate()
+        .filter_map(|(number, line)| {
+            let line = line
+                .map_err(anyhow::Error::from)
+                .and_then(|line| Ok(serde_json::from_str(&line)?));
+            match line {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    error!(
+                        line = number + 1,
+                        "skipping unreadable dead letter: {:?}", e
+                    );
+                    None
+                }
+            }
+        }))
+}
+
+/// Messages one driver gave up on, waiting to be written.
+struct Rejected {
+    driver: String,
+    error: String,
+    attempts: usize,
+    failed_at: i64,
+    batch: Vec<Arc<Envelope>>,
+}
+
+/// Writes to a dead-letter file on a thread of its own, so driver workers
+/// never wait for the disk. The thread stops once every writer is dropped.
+#[derive(Clone)]
+pub struct DeadLetterWriter {
+    sender: std::sync::mpsc::Sender<Rejected>,
+}
+
+impl DeadLetterWriter {
+    pub fn spawn(mut file: DeadLetterFile) -> Result<(Self, std::thread::JoinHandle<()>)> {
+        let (sender, receiver) = std::sync::mpsc::channel::<Rejected>();
+        let thread = std::thread::Builder::new()
+            .name("dead-letter".to_owned())
+            .spawn(move || {
+                for rejected in receiver {
+                    let written = rejected.batch.iter().try_for_each(|envelope| {
+                        file.write(&DeadLetterRef {
+                            driver: &rejected.driver,
+                            error: rejected.error.clone(),
+                            attempts: rejected.attempts,
+                            failed_at: rejected.failed_at,
+                            message: &envelope.message,
+                        })
+                    });
+                    if let Err(e) = written {
+                        error!(
+                            driver = rejected.driver,
+                            "failed writing dead letter: {:?}", e
+                        );
+                        rejected.batch.iter().for_each(|envelope| envelope.keep());
+                        continue;
+                    }
+                    counter!("drain_dead_letter_messages", "driver" => rejected.driver)
+                        .increment(rejected.batch.len() as u64);
+                }
+            })
+            .context("starting the dead-letter writer")?;
+        Ok((Self { sender }, thread))
+    }
+}
+
+/// The dead-letter destination handed to every driver worker.
+#[derive(Clone)]
+pub enum DeadLetterSink {
+    None,
+    File(DeadLetterWriter),
+    Driver {
+        name: String,
+        sender: mpsc::Sender<Arc<Envelope>>,
+        stats: Arc<DeliveryStats>,
+    },
+}
+
+impl DeadLetterSink {
+    /// Hands over messages `driver` gave up on after `attempts` attempts.
+    /// Messages that don't make it to the sink keep their write-ahead log
+    /// entry.
+    pub fn send(
+        &self,
+        driver: &str,
+        batch: &[Arc<Envelope>],
+        error: &anyhow::Error,
+        attempts: usize,
+    ) {
+        match self {
+            DeadLetterSink::None => batch.iter().for_each(|envelope| envelope.keep()),
+            DeadLetterSink::File(writer) => {
+                let rejected = Rejected {
+                    driver: driver.to_owned(),
+                    error: format!("{:#}", error),
+                    attempts,
+                    failed_at: now_millis(),
+                    batch: batch.to_vec(),
+                };
+                if let Err(unsent) = writer.sender.send(rejected) {
+                    error!(driver, "dead-letter writer stopped, dropping messages");
+                    unsent.0.batch.iter().for_each(|envelope| envelope.keep());
+                }
+            }
+            DeadLetterSink::Driver {
+                name,
+                sender,
+                stats,
+            } => {
+                if name == driver {
+                    // the dead-letter driver's own failures have nowhere to go
+                    batch.iter().for_each(|envelope| envelope.keep());
+                    return;
+                }
+                for envelope in batch {
+                    if sender.try_send(envelope.clone()).is_err() {
+                        warn!(
+                            driver,
+                            dead_letter_driver = name,
+                            "dead-letter driver queue full, dropping message"
+                        );
+                        envelope.keep();
+                        continue;
+                    }
+                    stats.queued();
+                    counter!("drain_dead_letter_messages", "driver" => driver.to_owned())
+                        .increment(1);
+                }
+            }
+        }
+    }
+}
+
+// serializes like `DeadLetter` without cloning the message
+#[derive(Serialize)]
+struct DeadLetterRef<'a> {
+    driver: &'a str,
+    error: String,
+    attempts: usize,
+    failed_at: i64,
+    message: &'a Message,
+}
+
+// entries read from the dead-letter file per round of sends
+const REPLAY_CHUNK: usize = 500;
+
+/// Sends the entries of a dead-letter file back through `drivers`. Each entry
+/// goes to the driver that gave up on it, or to every driver when `broadcast`
+/// is set. Entries that fail again are written to `failed`.
+pub async fn replay(
+    path: &Path,
+    mut drivers: HashMap<String, Box<dyn LogDriver>>,
+    broadcast: bool,
+    failed: &mut DeadLetterFile,
+) -> Result<(usize, usize)> {
+    let (mut delivered, mut failures) = (0, 0);
+    // messages each driver has buffered so far, to place what it reports on
+    let mut buffered: HashMap<String, u64> = HashMap::new();
+    let mut entries = read(path)?.peekable();
+    while entries.peek().is_some() {
+        let chunk: Vec<DeadLetter> = entries.by_ref().take(REPLAY_CHUNK).collect();
+        for (name, driver) in drivers.iter_mut() {
+            let messages: Vec<&Message> = chunk
+                .iter()
+                .filter(|entry| broadcast || &entry.driver == name)
+                .map(|entry| &entry.message)
+                .collect();
+            if messages.is_empty() {
+                continue;
+            }
+            let first = buffered.entry(name.clone()).or_default();
+            let base = *first;
+            let mut result = driver.send_batch(&messages).await;
+            if result.is_ok() {
+                *first += messages.len() as u64;
+                result = driver.flush().await;
+            }
+            // a buffering driver gives up on some messages without failing the flush
+            let mut given_up: Vec<Option<Arc<anyhow::Error>>> = vec![None; messages.len()];
+            for (seqs, e) in driver.take_settled().failed {
+                for seq in seqs {
+                    if let Some(slot) = seq
+                        .checked_sub(base)
+                        .and_then(|index| given_up.get_mut(index as usize))
+                    {
+                        *slot = Some(e.clone());
+                    }
+                }
+            }
+            if let Err(e) = &result {
+                error!(
+                    driver = name,
+                    messages = messages.len(),
+                    "replay failed: {:?}",
+                    e
+                );
+            }
+            for (message, gave_up) in messages.into_iter().zip(given_up) {
+                let error = match (&gave_up, &result) {
+                    (Some(e), _) => e.as_ref(),
+                    (None, Err(e)) => e,
+                    (None, Ok(())) => {
+                        delivered += 1;
+                        continue;
+                    }
+                };
+                failures += 1;
+                let attempts = error
+                    .downcast_ref::<GaveUp>()
+                    .map_or(1, |gave_up| gave_up.attempts);
+                failed.write(&DeadLetterRef {
+                    driver: name,
+                    error: format!("{:#}", error),
+                    attempts,
+                    failed_at: now_millis(),
+                    message,
+                })?;
+            }
+        }
+        if !broadcast {
+            let skipped = chunk
+                .iter()
+                .filter(|entry| !drivers.contains_key(&entry.driver))
+                .count();
+            if skipped > 0 {
+                warn!(
+                    skipped,
+                    "skipping dead letters for drivers that aren't enabled"
+                );
+            }
+        }
+    }
+    for (name, mut driver) in drivers {
+        if let Err(e) = driver.shutdown().await {
+            error!(driver = name, "failed to shut down driver: {:?}", e);
+        }
+    }
+    info!(delivered, failed = failures, "replayed dead letters");
+    Ok((delivered, failures))
+}
+
+pub fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    fn message(id: usize) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "timestamp": 1,
+            "source": "lambda",
+            "projectName": "project",
+            "projectId": "prj_1",
+            "deploymentId": "dpl_1",
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    fn entry(driver: &str, id: usize) -> DeadLetter {
+        DeadLetter {
+            driver: driver.to_owned(),
+            error: "unavailable".to_owned(),
+            attempts: 6,
+            failed_at: 0,
+            message: message(id),
+        }
+    }
+
+    // records the ids it receives, failing every send when `fail` is set
+    struct TestDriver {
+        fail: bool,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogDriver for TestDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+        async fn send_log(&mut self, message: &Message) -> Result<()> {
+            if self.fail {
+                anyhow::bail!("still down");
+            }
+            self.received.lock().unwrap().push(message.id.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rotates_when_full() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead-letter.ndjson");
+        let mut file = DeadLetterFile::open(&path, 1, 2)?;
+        for id in 0..4 {
+            file.write(&entry("loki", id))?;
+        }
+        let ids = |path: &Path| -> Result<Vec<String>> {
+            Ok(read(path)?.map(|entry| entry.message.id).collect())
+        };
+        assert_eq!(ids(&path)?, vec!["3"]);
+        assert_eq!(ids(&dir.path().join("dead-letter.ndjson.1"))?, vec!["2"]);
+        assert_eq!(ids(&dir.path().join("dead-letter.ndjson.2"))?, vec!["1"]);
+        assert!(!dir.path().join("dead-letter.ndjson.3").exists());
+
+        // with nowhere to rotate to, rotating would delete dead letters
+        assert!(DeadLetterFile::open(&dir.path().join("other.ndjson"), 1, 0).is_err());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn replays_to_the_driver_that_gave_up() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead-letter.ndjson");
+        let mut file = DeadLetterFile::open(&path, u64::MAX, 1)?;
+        file.write(&entry("loki", 0))?;
+        file.write(&entry("cloudwatch", 1))?;
+        file.write(&entry("loki", 2))?;
+
+        let loki = Arc::new(Mutex::new(Vec::new()));
+        let cloudwatch = Arc::new(Mutex::new(Vec::new()));
+        let mut drivers: HashMap<String, Box<dyn LogDriver>> = HashMap::new();
+        drivers.insert(
+            "loki".to_owned(),
+            Box::new(TestDriver {
+                fail: false,
+                received: loki.clone(),
+            }),
+        );
+        drivers.insert(
+            "cloudwatch".to_owned(),
+            Box::new(TestDriver {
+                fail: true,
+                received: cloudwatch.clone(),
+            }),
+        );
+        let failed_path = dir.path().join("failed.ndjson");
+        let mut failed = DeadLetterFile::open(&failed_path, u64::MAX, 1)?;
+        assert_eq!(replay(&path, drivers, false, &mut failed).await?, (2, 1));
+
+        assert_eq!(*loki.lock().unwrap(), vec!["0", "2"]);
+        let failed: Vec<DeadLetter> = read(&failed_path)?.collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].driver, "cloudwatch");
+        assert_eq!(failed[0].message.id, "1");
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn broadcast_sends_everything_to_every_driver() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("dead-letter.ndjson");
+        let mut file = DeadLetterFile::open(&path, u64::MAX, 1)?;
+        file.write(&entry("loki", 0))?;
+        file.write(&entry("cloudwatch", 1))?;
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut drivers: HashMap<String, Box<dyn LogDriver>> = HashMap::new();
+        drivers.insert(
+            "loki".to_owned(),
+            Box::new(TestDriver {
+                fail: false,
+                received: received.clone(),
+            }),
+        );
+        let mut failed = DeadLetterFile::open(&dir.path().join("failed.ndjson"), u64::MAX, 1)?;
+        assert_eq!(replay(&path, drivers, true, &mut failed).await?, (2, 0));
+        assert_eq!(*received.lock().unwrap(), vec!["0", "1"]);
+        return Ok(());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::types::Message;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Removes the messages whose timestamp is further than `max_skew` from
+/// `now`, both in unix milliseconds, taking them for replays. Returns how
+/// many were removed.
+pub fn drop_outside_window(messages: &mut Vec<Message>, max_skew: Duration, now: i64) -> usize {
+    let max_skew = max_skew.as_millis() as i64;
+    let before = messages.len();
+    messages.retain(|message| (message.timestamp - now).abs() <= max_skew);
+    return before - messages.len();
+}
+
+/// Remembers the messages accepted within the last `window`, at most
+/// `capacity` of them, so a replayed or retried payload isn't delivered
+/// twice. Keyed by endpoint and message id, messages whose id was derived
+/// from their content are left alone as identical records share it.
+#[derive(Debug)]
+pub struct Dedupe {
+    window: Duration,
+    capacity: usize,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    // the latest entry of each key
+    keys: HashMap<String, u64>,
+    // entries in the order they were accepted, stale once their key was
+    // accepted again
+    order: VecDeque<Entry>,
+    next: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    seq: u64,
+    at: Instant,
+    key: String,
+}
+
+impl Dedupe {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity: capacity.max(1),
+            seen: Default::default(),
+        }
+    }
+
+    /// The keys `drop_seen` reserves for `messages`.
+    pub fn keys(messages: &[Message]) -> Vec<String> {
+        messages
+            .iter()
+            .filter(|message| !message.id_derived)
+            .map(Self::key)
+            .collect()
+    }
+
+    pub fn key(message: &Message) -> String {
+        format!(
+            "{}/{}",
+            message.endpoint.as_deref().unwrap_or_default(),
+            message.id
+        )
+    }
+
+    /// Removes the messages seen within the window, along with repeats
+    /// within `messages`, returning how many were removed. The rest are
+    /// reserved under the same lock, so a payload sent twice at once is only
+    /// let through once; `release` them if the payload is refused after all.
+    pub fn drop_seen(&self, messages: &mut Vec<Message>, now: Instant) -> usize {
+        let mut seen = self.seen.lock().unwrap();
+        seen.expire(now.checked_sub(self.window));
+        let before = messages.len();
+        messages.retain(|message| message.id_derived || seen.insert(Self::key(message), now));
+        seen.evict(self.capacity);
+        return before - messages.len();
+    }
+
+    /// Forgets the keys `drop_seen` reserved for a refused payload, so its
+    /// retry is let through.
+    pub fn release(&self, keys: &[String]) {
+        let mut seen = self.seen.lock().unwrap();
+        for key in keys {
+            seen.keys.remove(key);
+        }
+    }
+}
+
+impl Seen {
+    /// Records `key` unless it is already known, returning whether it was new.
+    fn insert(&mut self, key: String, at: Instant) -> bool {
+        if self.keys.contains_key(&key) {
+            return false;
+        }
+        let seq = self.next;
+        self.next += 1;
+        self.keys.insert(key.clone(), seq);
+        self.order.push_back(Entry { seq, at, key });
+        return true;
+    }
+
+    /// Evicts the oldest keys beyond `capacity`.
+    fn evict(&mut self, capacity: usize) {
+        while self.keys.len() > capacity {
+            let Some(entry) = self.order.pop_front() else {
+                break;
+            };
+            self.forget(&entry);
+        }
+        // stale entries pile up when keys are released and accepted again
+        if self.order.len() > capacity * 2 {
+            let Seen { keys, order, .. } = self;
+            order.retain(|entry| keys.get(&entry.key) == Some(&entry.seq));
+        }
+    }
+
+    fn expire(&mut self, before: Option<Instant>) {
+        let Some(before) = before else {
+            return;
+        };
+        while let Some(entry) = self.order.front() {
+            if entry.at > before {
+                break;
+            }
+            let entry = self.order.pop_front().unwrap();
+            self.forget(&entry);
+        }
+    }
+
+    fn forget(&mut self, entry: &Entry) {
+        if self.keys.get(&entry.key) == Some(&entry.seq) {
+            self.keys.remove(&entry.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, timestamp: i64) -> Message {
+        Message {
+            id: id.to_owned(),
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|message| message.id.as_str()).collect()
+    }
+
+    #[test]
+    fn drops_messages_outside_the_window() {
+        let skew = Duration::from_secs(60);
+        for (now, dropped, kept) in [
+            (1_000_000, 0, vec!["a", "b"]),
+            (1_055_000, 0, vec!["a", "b"]),
+            (1_061_000, 1, vec!["b"]),
+            (989_000, 1, vec!["a"]),
+        ] {
+            let mut messages = vec![message("a", 1_000_000), message("b", 1_050_000)];
+            assert_eq!(drop_outside_window(&mut messages, skew, now), dropped);
+            assert_eq!(ids(&messages), kept);
+        }
+    }
+
+    #[test]
+    fn drops_messages_seen_within_the_window() {
+        let dedupe = Dedupe::new(Duration::from_secs(60), 100);
+        let start = Instant::now();
+
+        let mut messages = vec![message("a", 1), message("b", 1), message("a", 1)];
+        assert_eq!(dedupe.drop_seen(&mut messages, start), 1);
+        assert_eq!(ids(&messages), vec!["a", "b"]);
+        // reserved right away, a concurrent copy of the payload is dropped
+        let mut copy = vec![message("a", 1), message("b", 1)];
+        assert_eq!(dedupe.drop_seen(&mut copy, start), 2);
+        // until the payload is refused
+        let keys: Vec<String> = messages.iter().map(Dedupe::key).collect();
+        dedupe.release(&keys);
+        assert_eq!(dedupe.drop_seen(&mut messages, start), 0);
+
+        let mut messages = vec![message("a", 1), message("c", 1)];
+        let mut other_endpoint = message("a", 1);
+        other_endpoint.endpoint = Some(String::from("team-a"));
+        messages.push(other_endpoint);
+        assert_eq!(dedupe.drop_seen(&mut messages, start), 1);
+        assert_eq!(ids(&messages), vec!["c", "a"]);
+
+        let mut messages = vec![message("a", 1), message("b", 1)];
+        let later = start + Duration::from_secs(61);
+        assert_eq!(dedupe.drop_seen(&mut messages, later), 0);
+    }
+
+    #[test]
+    fn leaves_derived_ids_alone() {
+        let dedupe = Dedupe::new(Duration::from_secs(60), 100);
+        let now = Instant::now();
+        let derived = || Message {
+            id_derived: true,
+            ..message("d", 1)
+        };
+        let mut messages = vec![derived(), derived(), message("a", 1)];
+        assert_eq!(dedupe.drop_seen(&mut messages, now), 0);
+        assert_eq!(Dedupe::keys(&messages), vec![Dedupe::key(&message("a", 1))]);
+        let mut messages = vec![derived()];
+        assert_eq!(dedupe.drop_seen(&mut messages, now), 0);
+    }
+
+    #[test]
+    fn evicts_the_oldest_messages_beyond_capacity() {
+        let dedupe = Dedupe::new(Duration::from_secs(60), 2);
+        let now = Instant::now();
+        for id in ["a", "b", "c"] {
+            dedupe.drop_seen(&mut vec![message(id, 1)], now);
+        }
+        let mut messages = vec![message("a", 1), message("b", 1), message("c", 1)];
+        assert_eq!(dedupe.drop_seen(&mut messages, now), 2);
+        assert_eq!(ids(&messages), vec!["a"]);
+
+        // accepting the same key again keeps the bookkeeping bounded
+        for _ in 0..10 {
+            let key = Dedupe::key(&message("b", 1));
+            dedupe.release(&[key]);
+            dedupe.drop_seen(&mut vec![message("b", 1)], now);
+        }
+        assert!(dedupe.seen.lock().unwrap().order.len() <= 4);
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use reqwest::RequestBuilder;
+use std::path::PathBuf;
+
+/// A credential given inline, or read from a file every time it is used so
+/// mounted secrets can be rotated without a restart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Secret {
+    Value(String),
+    File(PathBuf),
+}
+
+impl Secret {
+    pub async fn read(&self) -> Result<String> {
+        match self {
+            Secret::Value(value) => Ok(value.clone()),
+            Secret::File(path) => {
+                let value = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("reading secret from {}", path.display()))?;
+                Ok(value.trim_end_matches(['\r', '\n']).to_owned())
+            }
+        }
+    }
+}
+
+/// How HTTP based drivers authenticate their requests.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum HttpAuth {
+    #[default]
+    None,
+    Basic {
+        username: String,
+        password: Secret,
+    },
+    Bearer(Secret),
+    Headers(Vec<(String, Secret)>),
+    // basic or bearer auth along with headers of their own, e.g. an API
+    // gateway key
+    WithHeaders(Box<HttpAuth>, Vec<(String, Secret)>),
+}
+
+impl HttpAuth {
+    /// Parses a comma separated list of `Name=value` pairs, `file` decides if
+    /// the values are inline secrets or paths to files holding them.
+    pub fn parse_headers(headers: &str, file: bool) -> Result<Vec<(String, Secret)>> {
+        headers
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let Some((name, value)) = pair.split_once('=') else {
+                    anyhow::bail!("invalid header {:?}, expected Name=value", pair);
+                };
+                let name = name.trim();
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                    .with_context(|| format!("invalid header name {:?}", name))?;
+                let value = match file {
+                    true => Secret::File(PathBuf::from(value.trim())),
+                    false => Secret::Value(value.trim().to_owned()),
+                };
+                Ok((name.to_owned(), value))
+            })
+            .collect()
+    }
+
+    pub async fn apply(&self, req: RequestBuilder) -> Result<RequestBuilder> {
+        Ok(match self {
+            HttpAuth::None => req,
+            HttpAuth::Basic { username, password } => {
+                req.basic_auth(username, Some(password.read().await?))
+            }
+            HttpAuth::Bearer(token) => req.bearer_auth(token.read().await?),
+            HttpAuth::Headers(headers) => add_headers(req, headers).await?,
+            HttpAuth::WithHeaders(auth, headers) => {
+                add_headers(Box::pin(auth.apply(req)).await?, headers).await?
+            }
+        })
+    }
+}
+
+async fn add_headers(
+    mut req: RequestBuilder,
+    headers: &[(String, Secret)],
+) -> Result<RequestBuilder> {
+    for (name, value) in headers {
+        req = req.header(name, value.read().await?);
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn headers(auth: &HttpAuth) -> Result<reqwest::header::HeaderMap> {
+        let req = auth
+            .apply(reqwest::Client::new().post("http://localhost/"))
+            .await?;
+        Ok(req.build()?.headers().clone())
+    }
+
+    #[tokio::test]
+    async fn none_adds_no_headers() -> Result<()> {
+        assert!(headers(&HttpAuth::None).await?.is_empty());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn basic_and_bearer_set_authorization() -> Result<()> {
+        let basic = HttpAuth::Basic {
+            username: "user".to_owned(),
+            password: Secret::Value("pass".to_owned()),
+        };
+        assert_eq!(
+            headers(&basic).await?["authorization"],
+            "Basic dXNlcjpwYXNz"
+        );
+
+        let bearer = HttpAuth::Bearer(Secret::Value("token".to_owned()));
+        assert_eq!(headers(&bearer).await?["authorization"], "Bearer token");
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn custom_headers() -> Result<()> {
+        let auth = HttpAuth::Headers(HttpAuth::parse_headers("X-Api-Key=abc, X-Org=1", false)?);
+        let headers = headers(&auth).await?;
+        assert_eq!(headers["x-api-key"], "abc");
+        assert_eq!(headers["x-org"], "1");
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn custom_headers_along_with_basic_auth() -> Result<()> {
+        let auth = HttpAuth::WithHeaders(
+            Box::new(HttpAuth::Basic {
+                username: "user".to_owned(),
+                password: Secret::Value("pass".to_owned()),
+            }),
+            HttpAuth::parse_headers("X-Api-Key=abc", false)?,
+        );
+        let headers = headers(&auth).await?;
+        assert_eq!(headers["authorization"], "Basic dXNlcjpwYXNz");
+        assert_eq!(headers["x-api-key"], "abc");
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn file_secrets_are_reread() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        let auth = HttpAuth::Bearer(Secret::File(file.path().to_owned()));
+
+        std::fs::write(file.path(), "first\n")?;
+        assert_eq!(headers(&auth).await?["authorization"], "Bearer first");
+        std::fs::write(file.path(), "second\n")?;
+        assert_eq!(headers(&auth).await?["authorization"], "Bearer second");
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(HttpAuth::parse_headers("X-Api-Key", false).is_err());
+        assert!(HttpAuth::parse_headers("bad header=1", false).is_err());
+    }
+}
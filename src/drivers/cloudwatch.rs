@@ -1,6 +1,8 @@
-use crate::types::{LogDriver, Message};
+use super::{GaveUp, RetryError, RetryPolicy};
+use crate::types::{LogDriver, Message, Settled};
 use anyhow::Result;
 use async_trait::async_trait;
+use aws_sdk_cloudwatchlogs::error::{ProvideErrorMetadata, SdkError};
 use aws_sdk_cloudwatchlogs::{
     operation::{
         create_log_group::CreateLogGroupError, create_log_stream::CreateLogStreamError,
@@ -8,24 +10,131 @@ use aws_sdk_cloudwatchlogs::{
     },
     types::InputLogEvent,
 };
+use axum_prometheus::metrics::counter;
 use core::result::Result::Ok;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, warn};
 
+// PutLogEvents limits, see:
+// https://docs.aws.amazon.com/AmazonCloudWatchLogs/latest/APIReference/API_PutLogEvents.html
+const MAX_BATCH_EVENTS: usize = 10_000;
+const MAX_BATCH_BYTES: usize = 1_048_576;
+const EVENT_OVERHEAD_BYTES: usize = 26;
+const MAX_BATCH_SPAN_MS: i64 = 24 * 60 * 60 * 1000;
+
+// a log event and the number it was buffered under, see `Settled`
+#[derive(Clone)]
+struct PendingEvent {
+    seq: u64,
+    event: InputLogEvent,
+}
+
+#[derive(Clone)]
+struct PendingBatch {
+    events: Vec<PendingEvent>,
+    bytes: usize,
+    started: Instant,
+}
+
+impl PendingBatch {
+    fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct CloudWatchDriver {
     client: aws_sdk_cloudwatchlogs::Client,
     groups: HashSet<String>,
     streams: HashSet<String>,
+    linger: Duration,
+    // pending events keyed by (log group, log stream)
+    batches: HashMap<(String, String), PendingBatch>,
+    // why the most recent PutLogEvents call failed, cleared once one succeeds
+    last_error: Option<String>,
+    // the number the next buffered event gets
+    next_seq: u64,
+    settled: Settled,
+    retry: RetryPolicy,
+}
+
+/// Throttling, server side errors and requests that never got a response are
+/// worth retrying, anything else won't succeed on a second try.
+fn is_retryable<E: ProvideErrorMetadata>(error: &SdkError<E>) -> bool {
+    match error {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(e) => {
+            let status = e.raw().status().as_u16();
+            status == 429
+                || status >= 500
+                || matches!(
+                    e.err().code(),
+                    Some("ThrottlingException" | "ServiceUnavailableException")
+                )
+        }
+        _ => false,
+    }
+}
+
+fn event_size(event: &InputLogEvent) -> usize {
+    return event.message().len() + EVENT_OVERHEAD_BYTES;
+}
+
+/// Sorts events by timestamp and splits them into chunks that each fit in a
+/// single PutLogEvents call.
+fn split_batch(mut events: Vec<PendingEvent>) -> Vec<Vec<PendingEvent>> {
+    events.sort_by_key(|pending| pending.event.timestamp());
+
+    let mut chunks = Vec::new();
+    let mut chunk: Vec<PendingEvent> = Vec::new();
+    let mut chunk_bytes = 0;
+    for pending in events {
+        let size = event_size(&pending.event);
+        let full = chunk.len() >= MAX_BATCH_EVENTS
+            || chunk_bytes + size > MAX_BATCH_BYTES
+            || chunk.first().is_some_and(|first| {
+                pending.event.timestamp() - first.event.timestamp() >= MAX_BATCH_SPAN_MS
+            });
+        if full && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_bytes = 0;
+        }
+        chunk_bytes += size;
+        chunk.push(pending);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    return chunks;
 }
 
 impl CloudWatchDriver {
-    pub fn new(client: aws_sdk_cloudwatchlogs::Client) -> Self {
+    pub fn new(client: aws_sdk_cloudwatchlogs::Client, linger: Duration) -> Self {
         Self {
             client,
             groups: HashSet::new(),
             streams: HashSet::new(),
+            linger,
+            batches: HashMap::new(),
+            last_error: None,
+            next_seq: 0,
+            settled: Settled::default(),
+            retry: RetryPolicy::default(),
         }
     }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
     async fn create_group(&mut self, group_name: &str) -> Result<()> {
         match self
             .client
@@ -108,6 +217,148 @@ impl CloudWatchDriver {
         self.check_or_create_stream(group_name, stream_name).await?;
         return Ok(());
     }
+    async fn flush_stream(&mut self, group_name: &str, stream_name: &str) -> Result<()> {
+        let Some(batch) = self
+            .batches
+            .remove(&(group_name.to_owned(), stream_name.to_owned()))
+        else {
+            return Ok(());
+        };
+        debug!(
+            ?group_name,
+            ?stream_name,
+            events = batch.events.len(),
+            age = ?batch.started.elapsed(),
+            "flushing log events"
+        );
+        let mut chunks = split_batch(batch.events).into_iter();
+        while let Some(chunk) = chunks.next() {
+            let events: Vec<InputLogEvent> =
+                chunk.iter().map(|pending| pending.event.clone()).collect();
+            let seqs: Vec<u64> = chunk.iter().map(|pending| pending.seq).collect();
+            match self.put_events(group_name, stream_name, &events).await {
+                Ok(()) => self.settled.delivered.extend(seqs),
+                // cloudwatch will refuse the chunk however often it is sent
+                Err(e) if GaveUp::is_permanent(&e) => self.settled.failed.push((seqs, Arc::new(e))),
+                Err(e) => {
+                    // the failed chunk and everything after it wait for the next flush
+                    let events: Vec<PendingEvent> =
+                        std::iter::once(chunk).chain(chunks).flatten().collect();
+                    self.batches.insert(
+                        (group_name.to_owned(), stream_name.to_owned()),
+                        PendingBatch {
+                            bytes: events
+                                .iter()
+                                .map(|pending| event_size(&pending.event))
+                                .sum(),
+                            events,
+                            started: batch.started,
+                        },
+                    );
+                    self.last_error = Some(e.to_string());
+                    return Err(e);
+                }
+            }
+        }
+        self.last_error = None;
+        return Ok(());
+    }
+
+    /// The stream a message goes to and its log event, creating the log
+    /// group and stream when they don't exist yet.
+    async fn prepare(&mut self, message: &Message) -> Result<((String, String), InputLogEvent)> {
+        let group_name = format!("/vercel/{}/{}", message.project_name, message.source);
+        #[allow(clippy::useless_format)]
+        let stream_name = format!("{}", message.deployment_id);
+        self.check_or_create(&group_name, &stream_name).await?;
+
+        let payload = serde_json::to_string(&message)?;
+        let log_event = InputLogEvent::builder()
+            .timestamp(message.timestamp)
+            .message(payload)
+            .build()?;
+        return Ok(((group_name, stream_name), log_event));
+    }
+
+    /// Adds the event to its stream's pending batch, flushing the stream
+    /// first if the event doesn't fit. After a failed flush retrying is left
+    /// to the next `flush`, and events that don't fit in the batch are given
+    /// up on until then.
+    async fn buffer(&mut self, key: (String, String), log_event: InputLogEvent) {
+        let size = event_size(&log_event);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let full = |batches: &HashMap<(String, String), PendingBatch>| {
+            batches.get(&key).is_some_and(|batch| {
+                batch.events.len() >= MAX_BATCH_EVENTS || batch.bytes + size > MAX_BATCH_BYTES
+            })
+        };
+        if full(&self.batches) && self.last_error.is_none() {
+            let _ = self.flush_stream(&key.0, &key.1).await;
+        }
+        if full(&self.batches) {
+            counter!("drain_driver_dropped_messages", "driver" => "cloudwatch").increment(1);
+            let error = anyhow::anyhow!("cloudwatch is failing and the stream's batch is full")
+                .context(GaveUp {
+                    attempts: 0,
+                    permanent: false,
+                });
+            self.settled.failed.push((vec![seq], Arc::new(error)));
+            return;
+        }
+        let batch = self.batches.entry(key).or_insert_with(PendingBatch::new);
+        batch.bytes += size;
+        batch.events.push(PendingEvent {
+            seq,
+            event: log_event,
+        });
+    }
+    async fn put_events(
+        &mut self,
+        group_name: &str,
+        stream_name: &str,
+        log_events: &[InputLogEvent],
+    ) -> Result<()> {
+        let retry_policy = self.retry.clone();
+        let mut retry = retry_policy.start("cloudwatch");
+        loop {
+            let e = match self
+                .client
+                .put_log_events()
+                .log_group_name(group_name)
+                .log_stream_name(stream_name)
+                .set_log_events(Some(log_events.to_vec()))
+                .send()
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+            let retryable = is_retryable(&e);
+            let error = match e.into_service_error() {
+                PutLogEventsError::ResourceNotFoundException(_) => {
+                    warn!("log group or stream not found, trying to create again...");
+                    self.create_group(group_name).await?;
+                    self.create_stream(group_name, stream_name).await?;
+                    RetryError::Retryable(anyhow::anyhow!("log group or stream not found"))
+                }
+                inner_err => {
+                    error!(
+                        ?group_name,
+                        ?stream_name,
+                        events = log_events.len(),
+                        "failed to put log events: {:?}",
+                        inner_err
+                    );
+                    match retryable {
+                        true => RetryError::Retryable(inner_err.into()),
+                        false => RetryError::Permanent(inner_err.into()),
+                    }
+                }
+            };
+            retry.failed(error).await?;
+        }
+    }
 }
 
 #[async_trait]
@@ -175,55 +426,327 @@ impl LogDriver for CloudWatchDriver {
     }
 
     async fn send_log(&mut self, message: &Message) -> Result<()> {
-        let mut retries: usize = 0;
-        let group_name = format!("/vercel/{}/{}", message.project_name, message.source);
-        #[allow(clippy::useless_format)]
-        let stream_name = format!("{}", message.deployment_id);
-        self.check_or_create(&group_name, &stream_name).await?;
+        return self.send_batch(&[message]).await;
+    }
 
-        let payload = serde_json::to_string(&message)?;
-        let log_event = InputLogEvent::builder()
-            .timestamp(message.timestamp)
-            .message(payload)
-            .build()?;
+    /// Buffers every message or, when one can't be turned into a log event,
+    /// none of them. `take_settled` reports whether they reached cloudwatch.
+    async fn send_batch(&mut self, messages: &[&Message]) -> Result<()> {
+        let mut events = Vec::with_capacity(messages.len());
+        for message in messages {
+            events.push(self.prepare(message).await?);
+        }
+        for (key, log_event) in events {
+            self.buffer(key, log_event).await;
+        }
+        return Ok(());
+    }
 
-        while let Err(e) = self
-            .client
-            .put_log_events()
-            .log_group_name(&group_name)
-            .log_stream_name(&stream_name)
-            .log_events(log_event.clone())
-            .send()
-            .await
-        {
-            retries += 1;
-            if retries > 5 {
-                warn!(
-                    ?group_name,
-                    ?stream_name,
-                    ?log_event,
-                    error = ?e,
-                    "failed to put log event after 5 retries"
-                );
-                return Err(e.into());
+    async fn flush(&mut self) -> Result<()> {
+        let keys: Vec<(String, String)> = self.batches.keys().cloned().collect();
+        let mut result = Ok(());
+        for (group_name, stream_name) in keys {
+            if let Err(e) = self.flush_stream(&group_name, &stream_name).await {
+                result = Err(e);
             }
-            match e.into_service_error() {
-                PutLogEventsError::ResourceNotFoundException(_) => {
-                    warn!("log group or stream not found, trying to create again...");
-                    self.create_group(&group_name).await?;
-                    self.create_stream(&group_name, &stream_name).await?;
-                }
-                inner_err => {
-                    error!(
-                        ?group_name,
-                        ?stream_name,
-                        "failed to put log event: {:?}",
-                        inner_err
-                    );
+        }
+        return result;
+    }
+
+    fn flush_interval(&self) -> Option<Duration> {
+        return Some(self.linger);
+    }
+
+    fn take_settled(&mut self) -> Settled {
+        return std::mem::take(&mut self.settled);
+    }
+
+    async fn health(&self) -> Result<()> {
+        if let Some(e) = &self.last_error {
+            anyhow::bail!("last put to cloudwatch failed: {}", e);
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_cloudwatchlogs::config::{BehaviorVersion, Credentials, Region};
+    use aws_smithy_runtime::client::http::test_util::infallible_client_fn;
+    use std::sync::Mutex;
+
+    type PutCalls = Arc<Mutex<Vec<Vec<(i64, usize)>>>>;
+
+    // a client that accepts every call, recording (timestamp, message length)
+    // for each event of every PutLogEvents request
+    fn mock_client() -> (aws_sdk_cloudwatchlogs::Client, PutCalls) {
+        return refusing_client(None);
+    }
+
+    // like `mock_client`, but refuses the PutLogEvents request with the given
+    // index with a status and error code, the refused request isn't recorded
+    fn refusing_client(
+        refused: Option<(usize, u16, &'static str)>,
+    ) -> (aws_sdk_cloudwatchlogs::Client, PutCalls) {
+        let calls: PutCalls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let puts = Arc::new(Mutex::new(0));
+        let http_client = infallible_client_fn(move |request| {
+            let target = request
+                .headers()
+                .get("x-amz-target")
+                .map(|value| value.to_str().unwrap().to_owned())
+                .unwrap_or_default();
+            if target.ends_with("PutLogEvents") {
+                let mut puts = puts.lock().unwrap();
+                *puts += 1;
+                if let Some((_, status, code)) = refused.filter(|(index, ..)| *index == *puts - 1) {
+                    return http::Response::builder()
+                        .status(status)
+                        .body(format!(r#"{{"__type":"{}"}}"#, code))
+                        .unwrap();
                 }
+                let body: serde_json::Value =
+                    serde_json::from_slice(request.body().bytes().unwrap()).unwrap();
+                let events = body["logEvents"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|event| {
+                        (
+                            event["timestamp"].as_i64().unwrap(),
+                            event["message"].as_str().unwrap().len(),
+                        )
+                    })
+                    .collect();
+                recorded.lock().unwrap().push(events);
             }
-            info!(id = message.id, ?retries, "retrying message...");
+            http::Response::builder()
+                .status(200)
+                .body("{}".to_string())
+                .unwrap()
+        });
+        let config = aws_sdk_cloudwatchlogs::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .http_client(http_client)
+            .build();
+        return (aws_sdk_cloudwatchlogs::Client::from_conf(config), calls);
+    }
+
+    fn message(timestamp: i64, text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "message": text,
+            "timestamp": timestamp,
+            "source": "lambda",
+            "projectName": "project",
+            "projectId": "prj_1",
+            "deploymentId": "dpl_1",
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    fn driver(client: aws_sdk_cloudwatchlogs::Client) -> CloudWatchDriver {
+        let mut driver = CloudWatchDriver::new(client, Duration::from_secs(1));
+        driver.groups.insert("/vercel/project/lambda".to_owned());
+        driver.streams.insert("dpl_1".to_owned());
+        return driver;
+    }
+
+    fn event(timestamp: i64, size: usize) -> PendingEvent {
+        let event = InputLogEvent::builder()
+            .timestamp(timestamp)
+            .message("x".repeat(size))
+            .build()
+            .unwrap();
+        return PendingEvent { seq: 0, event };
+    }
+
+    #[test]
+    fn split_batch_respects_event_count() {
+        let events = (0..25_000).map(|i| event(i, 1)).collect();
+        let chunks = split_batch(events);
+        let sizes: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![10_000, 10_000, 5_000]);
+    }
+
+    #[test]
+    fn split_batch_respects_byte_size() {
+        let events = (0..10).map(|i| event(i, 200_000)).collect();
+        let chunks = split_batch(events);
+        assert_eq!(chunks.len(), 2);
+        for chunk in chunks {
+            let bytes: usize = chunk.iter().map(|pending| event_size(&pending.event)).sum();
+            assert!(bytes <= MAX_BATCH_BYTES);
         }
+    }
+
+    #[test]
+    fn split_batch_respects_time_span() {
+        let hour = 60 * 60 * 1000;
+        let events = vec![event(0, 1), event(23 * hour, 1), event(25 * hour, 1)];
+        let chunks = split_batch(events);
+        assert_eq!(chunks.len(), 2);
+        for chunk in chunks {
+            let span =
+                chunk.last().unwrap().event.timestamp() - chunk.first().unwrap().event.timestamp();
+            assert!(span < MAX_BATCH_SPAN_MS);
+        }
+    }
+
+    #[test]
+    fn split_batch_sorts_by_timestamp() {
+        let events = vec![event(3, 1), event(1, 1), event(2, 1)];
+        let chunks = split_batch(events);
+        let timestamps: Vec<i64> = chunks[0].iter().map(|e| e.event.timestamp()).collect();
+        assert_eq!(timestamps, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn buffers_until_flush() -> Result<()> {
+        let (client, calls) = mock_client();
+        let mut driver = driver(client);
+        for timestamp in [3, 1, 2] {
+            driver.send_log(&message(timestamp, "hello")).await?;
+        }
+        assert!(calls.lock().unwrap().is_empty());
+
+        driver.flush().await?;
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        let timestamps: Vec<i64> = calls[0].iter().map(|(ts, _)| *ts).collect();
+        assert_eq!(timestamps, vec![1, 2, 3]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn flushes_when_batch_is_full() -> Result<()> {
+        let (client, calls) = mock_client();
+        let mut driver = driver(client);
+        let total = 2 * MAX_BATCH_EVENTS;
+        for i in 0..total {
+            driver.send_log(&message(i as i64, "hello")).await?;
+        }
+        let flushed_early = calls.lock().unwrap().len();
+        assert!(flushed_early > 0);
+        // the early flush is reported without waiting for the next one
+        let sent_early: usize = calls.lock().unwrap().iter().map(Vec::len).sum();
+        assert_eq!(driver.take_settled().delivered.len(), sent_early);
+
+        driver.flush().await?;
+        let calls = calls.lock().unwrap();
+        assert!(calls.len() > flushed_early);
+        for call in calls.iter() {
+            assert!(call.len() <= MAX_BATCH_EVENTS);
+            let bytes: usize = call.iter().map(|(_, len)| len + EVENT_OVERHEAD_BYTES).sum();
+            assert!(bytes <= MAX_BATCH_BYTES);
+        }
+        assert_eq!(calls.iter().map(Vec::len).sum::<usize>(), total);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn flush_splits_on_every_limit() -> Result<()> {
+        let (client, calls) = mock_client();
+        let mut driver = driver(client);
+        let day = MAX_BATCH_SPAN_MS;
+        let big = "x".repeat(300_000);
+        for i in 0..4 {
+            driver.send_log(&message(i, &big)).await?;
+        }
+        driver.send_log(&message(day + 10, "late")).await?;
+        driver.flush().await?;
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 3);
+        for call in calls.iter() {
+            assert!(call.len() <= MAX_BATCH_EVENTS);
+            let bytes: usize = call.iter().map(|(_, len)| len + EVENT_OVERHEAD_BYTES).sum();
+            assert!(bytes <= MAX_BATCH_BYTES);
+            assert!(call.last().unwrap().0 - call.first().unwrap().0 < MAX_BATCH_SPAN_MS);
+        }
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_chunks_a_failed_flush_did_not_send() -> Result<()> {
+        let (client, calls) = refusing_client(Some((1, 503, "ServiceUnavailableException")));
+        let mut driver = driver(client).with_retry(RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        });
+        // a chunk per day
+        let timestamps: Vec<i64> = (0..3).map(|day| day * MAX_BATCH_SPAN_MS).collect();
+        for timestamp in &timestamps {
+            driver.send_log(&message(*timestamp, "hello")).await?;
+        }
+        assert!(driver.flush().await.is_err());
+        assert!(driver.health().await.is_err());
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        assert_eq!(driver.take_settled().delivered, vec![0]);
+
+        driver.flush().await?;
+        let calls = calls.lock().unwrap();
+        let sent: Vec<i64> = calls.iter().flatten().map(|(ts, _)| *ts).collect();
+        assert_eq!(sent, timestamps);
+        assert!(driver.batches.is_empty());
+        assert_eq!(driver.take_settled().delivered, vec![1, 2]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_chunks_cloudwatch_refuses_as_invalid() -> Result<()> {
+        let (client, calls) = refusing_client(Some((1, 400, "InvalidParameterException")));
+        let mut driver = driver(client);
+        let timestamps: Vec<i64> = (0..3).map(|day| day * MAX_BATCH_SPAN_MS).collect();
+        for timestamp in &timestamps {
+            driver.send_log(&message(*timestamp, "hello")).await?;
+        }
+        driver.flush().await?;
+        assert!(driver.health().await.is_ok());
+        assert!(driver.batches.is_empty());
+        assert_eq!(calls.lock().unwrap().len(), 2);
+
+        let settled = driver.take_settled();
+        assert_eq!(settled.delivered, vec![0, 2]);
+        assert_eq!(settled.failed.len(), 1);
+        let (seqs, error) = &settled.failed[0];
+        assert_eq!(seqs, &vec![1]);
+        assert!(GaveUp::is_permanent(error));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_events_that_do_not_fit_while_failing() -> Result<()> {
+        let (client, calls) = refusing_client(Some((0, 503, "ServiceUnavailableException")));
+        let mut driver = driver(client).with_retry(RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        });
+        let total = MAX_BATCH_EVENTS + 2;
+        for i in 0..total {
+            driver.send_log(&message(i as i64, "hello")).await?;
+        }
+        // the early flush failed, so the events after it had nowhere to go
+        assert!(driver.health().await.is_err());
+        let settled = driver.take_settled();
+        assert!(settled.delivered.is_empty());
+        let given_up: Vec<u64> = settled
+            .failed
+            .iter()
+            .flat_map(|(seqs, _)| seqs.clone())
+            .collect();
+        let kept = total - given_up.len();
+        assert!(kept < total);
+        assert_eq!(given_up, (kept as u64..total as u64).collect::<Vec<_>>());
+
+        driver.flush().await?;
+        assert_eq!(calls.lock().unwrap()[0].len(), kept);
+        assert_eq!(driver.take_settled().delivered.len(), kept);
         return Ok(());
     }
 }
@@ -0,0 +1,742 @@
+mod proto;
+mod template;
+mod tenant;
+
+use super::{GaveUp, HttpAuth, RetryError, RetryPolicy};
+use crate::types::{LogDriver, Message, Settled};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum_prometheus::metrics::counter;
+use flate2::{write::GzEncoder, Compression};
+use prost::Message as _;
+use reqwest::Client as HttpClient;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+pub use template::{LokiTemplate, DEFAULT_LABELS, DEFAULT_STRUCTURED_METADATA};
+pub use tenant::LokiTenants;
+
+type Labels = BTreeMap<String, String>;
+type Streams = HashMap<Labels, Vec<Entry>>;
+
+#[derive(Clone)]
+struct Entry {
+    // the number the entry was buffered under, see `Settled`
+    seq: u64,
+    timestamp: i64,
+    line: String,
+    structured_metadata: Labels,
+}
+
+/// Wire format used for pushes to loki.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LokiEncoding {
+    /// gzip compressed JSON
+    Json,
+    /// snappy compressed `logproto.PushRequest`
+    Protobuf,
+}
+
+/// Thresholds that trigger a push of the buffered log lines.
+#[derive(Clone)]
+pub struct LokiBatchConfig {
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub linger: Duration,
+}
+
+#[derive(Clone)]
+pub struct LokiDriver {
+    client: HttpClient,
+    url: String,
+    auth: HttpAuth,
+    encoding: LokiEncoding,
+    template: LokiTemplate,
+    tenants: LokiTenants,
+    batch: LokiBatchConfig,
+    // pending entries keyed by tenant, then by their stream labels
+    streams: HashMap<Option<String>, Streams>,
+    pending_entries: usize,
+    pending_bytes: usize,
+    // why the most recent push failed, cleared once one succeeds
+    last_error: Option<String>,
+    // the number the next buffered entry gets
+    next_seq: u64,
+    settled: Settled,
+    retry: RetryPolicy,
+}
+
+impl LokiDriver {
+    pub fn new(
+        url: String,
+        auth: HttpAuth,
+        encoding: LokiEncoding,
+        template: LokiTemplate,
+        tenants: LokiTenants,
+        batch: LokiBatchConfig,
+    ) -> Self {
+        Self {
+            client: HttpClient::new(),
+            url,
+            auth,
+            encoding,
+            template,
+            tenants,
+            batch,
+            streams: HashMap::new(),
+            pending_entries: 0,
+            pending_bytes: 0,
+            last_error: None,
+            next_seq: 0,
+            settled: Settled::default(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    fn json_payload(streams: Streams) -> serde_json::Value {
+        let streams: Vec<serde_json::Value> = streams
+            .into_iter()
+            .map(|(labels, mut entries)| {
+                entries.sort_by_key(|entry| entry.timestamp);
+                let values: Vec<serde_json::Value> = entries
+                    .into_iter()
+                    .map(|entry| {
+                        let timestamp = (entry.timestamp * 1000000).to_string();
+                        if entry.structured_metadata.is_empty() {
+                            json!([timestamp, entry.line])
+                        } else {
+                            json!([timestamp, entry.line, entry.structured_metadata])
+                        }
+                    })
+                    .collect();
+                json!({ "stream": labels, "values": values })
+            })
+            .collect();
+        return json!({ "streams": streams });
+    }
+
+    fn protobuf_payload(streams: Streams) -> proto::PushRequest {
+        let streams = streams
+            .into_iter()
+            .map(|(labels, mut entries)| {
+                entries.sort_by_key(|entry| entry.timestamp);
+                let entries = entries
+                    .into_iter()
+                    .map(|entry| proto::EntryAdapter {
+                        timestamp: Some(prost_types::Timestamp {
+                            seconds: entry.timestamp.div_euclid(1000),
+                            nanos: (entry.timestamp.rem_euclid(1000) * 1000000) as i32,
+                        }),
+                        line: entry.line,
+                        structured_metadata: entry
+                            .structured_metadata
+                            .into_iter()
+                            .map(|(name, value)| proto::LabelPairAdapter { name, value })
+                            .collect(),
+                    })
+                    .collect();
+                proto::StreamAdapter {
+                    labels: format_labels(&labels),
+                    entries,
+                    hash: 0,
+                }
+            })
+            .collect();
+        return proto::PushRequest { streams };
+    }
+
+    fn encode(&self, streams: Streams) -> Result<Vec<u8>> {
+        match self.encoding {
+            LokiEncoding::Json => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                serde_json::to_writer(&mut encoder, &Self::json_payload(streams))?;
+                Ok(encoder.finish()?)
+            }
+            LokiEncoding::Protobuf => {
+                let request = Self::protobuf_payload(streams).encode_to_vec();
+                Ok(snap::raw::Encoder::new().compress_vec(&request)?)
+            }
+        }
+    }
+
+    /// Pushes all pending entries, one request per tenant. The entries of a
+    /// tenant whose push failed stay pending for the next one, unless loki
+    /// refused them for good, e.g. as out of order.
+    async fn push(&mut self) -> Result<()> {
+        if self.streams.is_empty() {
+            return Ok(());
+        }
+        let tenants = std::mem::take(&mut self.streams);
+        debug!(
+            tenants = tenants.len(),
+            entries = self.pending_entries,
+            "pushing batch to loki"
+        );
+        self.pending_entries = 0;
+        self.pending_bytes = 0;
+
+        let mut result = Ok(());
+        for (tenant, streams) in tenants {
+            let seqs: Vec<u64> = streams.values().flatten().map(|entry| entry.seq).collect();
+            match self.push_tenant(tenant.as_deref(), streams.clone()).await {
+                Ok(()) => self.settled.delivered.extend(seqs),
+                Err(e) if GaveUp::is_permanent(&e) => self.settled.failed.push((seqs, Arc::new(e))),
+                Err(e) => {
+                    for entries in streams.values() {
+                        self.pending_entries += entries.len();
+                        self.pending_bytes +=
+                            entries.iter().map(|entry| entry.line.len()).sum::<usize>();
+                    }
+                    self.streams.insert(tenant, streams);
+                    result = Err(e);
+                }
+            }
+        }
+        self.last_error = result.as_ref().err().map(|e| e.to_string());
+        result
+    }
+
+    /// The tenant, stream labels and entry a message is pushed as.
+    fn render(&self, message: &Message) -> Result<(Option<String>, Labels, Entry)> {
+        let (labels, structured_metadata, line) = self.template.render(message)?;
+        let tenant = self.tenants.tenant(message).map(String::from);
+        let entry = Entry {
+            seq: 0,
+            timestamp: message.timestamp,
+            line,
+            structured_metadata,
+        };
+        Ok((tenant, labels, entry))
+    }
+
+    /// Adds the entry to its stream, unless loki is failing and the buffer
+    /// is already full, in which case it is given up on until a push succeeds.
+    fn buffer(&mut self, tenant: Option<String>, labels: Labels, mut entry: Entry) {
+        entry.seq = self.next_seq;
+        self.next_seq += 1;
+        if self.batch_full() && self.last_error.is_some() {
+            counter!("drain_driver_dropped_messages", "driver" => "loki").increment(1);
+            let error = anyhow::anyhow!("loki is failing and the buffer is full").context(GaveUp {
+                attempts: 0,
+                permanent: false,
+            });
+            self.settled.failed.push((vec![entry.seq], Arc::new(error)));
+            return;
+        }
+        self.pending_entries += 1;
+        self.pending_bytes += entry.line.len();
+        let streams = self.streams.entry(tenant).or_default();
+        streams.entry(labels).or_default().push(entry);
+    }
+
+    fn batch_full(&self) -> bool {
+        self.pending_entries >= self.batch.max_entries || self.pending_bytes >= self.batch.max_bytes
+    }
+
+    async fn push_tenant(&self, tenant: Option<&str>, streams: Streams) -> Result<()> {
+        debug!(?tenant, streams = streams.len(), "pushing streams to loki");
+        let body = self.encode(streams)?;
+        let mut retry = self.retry.start("loki");
+        loop {
+            match self.send_push(tenant, body.clone()).await {
+                Ok(_) => return Ok(()),
+                Err(e) => retry.failed(e).await?,
+            }
+        }
+    }
+
+    async fn send_push(&self, tenant: Option<&str>, body: Vec<u8>) -> Result<(), RetryError> {
+        let mut req = self.client.post(&self.url).body(body);
+        req = match self.encoding {
+            LokiEncoding::Json => req
+                .header("Content-Type", "application/json")
+                .header("Content-Encoding", "gzip"),
+            LokiEncoding::Protobuf => req.header("Content-Type", "application/x-protobuf"),
+        };
+        if let Some(tenant) = tenant {
+            req = req.header("X-Scope-OrgID", tenant);
+        }
+
+        req = self.auth.apply(req).await.map_err(RetryError::Permanent)?;
+        let response = req.send().await.map_err(RetryError::from_reqwest)?;
+        debug!("sent request");
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(RetryError::from_status(
+                status,
+                anyhow::anyhow!("Failed to send logs: {}", status),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders labels in the prometheus selector format loki expects in
+/// protobuf pushes, e.g. `{project="web", source="lambda"}`.
+fn format_labels(labels: &Labels) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    return format!("{{{}}}", pairs.join(", "));
+}
+
+#[async_trait]
+impl LogDriver for LokiDriver {
+    async fn init(&mut self) -> Result<()> {
+        debug!("init loki");
+        Ok(())
+    }
+
+    async fn send_log(&mut self, message: &Message) -> Result<()> {
+        self.send_batch(&[message]).await
+    }
+
+    /// Buffers every message or, when one can't be rendered, none of them.
+    /// Pushes the buffer whenever it is full, but a failed push keeps its
+    /// entries, so only `take_settled` reports whether they reached loki.
+    async fn send_batch(&mut self, messages: &[&Message]) -> Result<()> {
+        let rendered = messages
+            .iter()
+            .map(|message| self.render(message))
+            .collect::<Result<Vec<_>>>()?;
+        for (tenant, labels, entry) in rendered {
+            self.buffer(tenant, labels, entry);
+            // after a failed push retrying is left to the next flush
+            if self.batch_full() && self.last_error.is_none() {
+                let _ = self.push().await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.push().await
+    }
+
+    fn flush_interval(&self) -> Option<Duration> {
+        Some(self.batch.linger)
+    }
+
+    fn take_settled(&mut self) -> Settled {
+        std::mem::take(&mut self.settled)
+    }
+
+    async fn health(&self) -> Result<()> {
+        match &self.last_error {
+            Some(e) => anyhow::bail!("last push to loki failed: {}", e),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::HeaderMap, routing::post, Router};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    type Pushes = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    // a stand-in for loki's push endpoint that records each decoded body, protobuf
+    // pushes are converted to the equivalent JSON push and the tenant header is
+    // recorded as a "tenant" field
+    async fn loki_stand_in() -> (String, Pushes) {
+        async fn push(
+            State(pushes): State<Pushes>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> axum::http::StatusCode {
+            let mut push: serde_json::Value =
+                match headers.get("content-type").unwrap().to_str().unwrap() {
+                    "application/json" => {
+                        assert_eq!(headers.get("content-encoding").unwrap(), "gzip");
+                        let mut json = String::new();
+                        GzDecoder::new(&body[..]).read_to_string(&mut json).unwrap();
+                        serde_json::from_str(&json).unwrap()
+                    }
+                    "application/x-protobuf" => {
+                        let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+                        let request = proto::PushRequest::decode(&body[..]).unwrap();
+                        let streams: Vec<serde_json::Value> = request
+                            .streams
+                            .iter()
+                            .map(|stream| {
+                                let values: Vec<serde_json::Value> = stream
+                                    .entries
+                                    .iter()
+                                    .map(|entry| {
+                                        let ts = entry.timestamp.unwrap();
+                                        let nanos = ts.seconds * 1_000_000_000 + ts.nanos as i64;
+                                        let metadata: Labels = entry
+                                            .structured_metadata
+                                            .iter()
+                                            .map(|pair| (pair.name.clone(), pair.value.clone()))
+                                            .collect();
+                                        json!([nanos.to_string(), entry.line, metadata])
+                                    })
+                                    .collect();
+                                json!({ "labels": stream.labels, "values": values })
+                            })
+                            .collect();
+                        json!({ "streams": streams })
+                    }
+                    other => panic!("unexpected content type {}", other),
+                };
+            if let Some(tenant) = headers.get("x-scope-orgid") {
+                push["tenant"] = json!(tenant.to_str().unwrap());
+            }
+            pushes.lock().unwrap().push(push);
+            axum::http::StatusCode::NO_CONTENT
+        }
+        let pushes: Pushes = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new()
+            .route("/loki/api/v1/push", post(push))
+            .with_state(pushes.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        return (url, pushes);
+    }
+
+    fn message(deployment: &str, timestamp: i64) -> Message {
+        project_message("prj_1", deployment, timestamp)
+    }
+
+    fn project_message(project_id: &str, deployment: &str, timestamp: i64) -> Message {
+        serde_json::from_value(json!({
+            "id": "1",
+            "message": "hello",
+            "timestamp": timestamp,
+            "source": "lambda",
+            "projectName": "project",
+            "projectId": project_id,
+            "deploymentId": deployment,
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    fn driver(url: String, max_entries: usize, max_bytes: usize) -> LokiDriver {
+        encoded_driver(url, LokiEncoding::Json, max_entries, max_bytes)
+    }
+
+    fn encoded_driver(
+        url: String,
+        encoding: LokiEncoding,
+        max_entries: usize,
+        max_bytes: usize,
+    ) -> LokiDriver {
+        LokiDriver::new(
+            url,
+            HttpAuth::None,
+            encoding,
+            LokiTemplate::new("project=projectName,deployment=deploymentId,source", "host")
+                .unwrap(),
+            LokiTenants::default(),
+            LokiBatchConfig {
+                max_entries,
+                max_bytes,
+                linger: Duration::from_secs(1),
+            },
+        )
+        .with_retry(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            max_elapsed: Duration::from_secs(1),
+        })
+    }
+
+    #[tokio::test]
+    async fn groups_messages_by_label_set() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.send_log(&message("dpl_1", 2)).await?;
+        driver.send_log(&message("dpl_2", 1)).await?;
+        driver.send_log(&message("dpl_1", 1)).await?;
+        assert!(pushes.lock().unwrap().is_empty());
+
+        driver.flush().await?;
+        let pushes = pushes.lock().unwrap();
+        assert_eq!(pushes.len(), 1);
+        let streams = pushes[0]["streams"].as_array().unwrap();
+        assert_eq!(streams.len(), 2);
+        let dpl_1 = streams
+            .iter()
+            .find(|stream| stream["stream"]["deployment"] == "dpl_1")
+            .unwrap();
+        let timestamps: Vec<&str> = dpl_1["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|value| value[0].as_str().unwrap())
+            .collect();
+        assert_eq!(timestamps, vec!["1000000", "2000000"]);
+        assert_eq!(dpl_1["values"][0][2]["host"], "example.vercel.app");
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn pushes_when_entry_limit_is_reached() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 3, 1_000_000);
+        for timestamp in 0..7 {
+            driver.send_log(&message("dpl_1", timestamp)).await?;
+        }
+        assert_eq!(pushes.lock().unwrap().len(), 2);
+
+        driver.flush().await?;
+        assert_eq!(pushes.lock().unwrap().len(), 3);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn pushes_when_byte_limit_is_reached() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 100, 1);
+        driver.send_log(&message("dpl_1", 1)).await?;
+        driver.send_log(&message("dpl_1", 2)).await?;
+        assert_eq!(pushes.lock().unwrap().len(), 2);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_at_the_entry_limit() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 3, 1_000_000);
+        let messages: Vec<Message> = (0..7)
+            .map(|timestamp| message("dpl_1", timestamp))
+            .collect();
+        driver
+            .send_batch(&messages.iter().collect::<Vec<_>>())
+            .await?;
+        assert_eq!(pushes.lock().unwrap().len(), 2);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors() -> Result<()> {
+        let attempts = Arc::new(Mutex::new(0));
+        let app = Router::new()
+            .route(
+                "/loki/api/v1/push",
+                post(|State(attempts): State<Arc<Mutex<usize>>>| async move {
+                    let mut attempts = attempts.lock().unwrap();
+                    *attempts += 1;
+                    match *attempts {
+                        1 => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                        _ => axum::http::StatusCode::NO_CONTENT,
+                    }
+                }),
+            )
+            .with_state(attempts.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.send_log(&message("dpl_1", 1)).await?;
+        driver.flush().await?;
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn health_reflects_the_last_push() -> Result<()> {
+        // nothing listens on a port that was just released
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr()?);
+        drop(listener);
+        let mut driver = driver(url, 100, 1_000_000);
+        assert!(driver.health().await.is_ok());
+
+        driver.send_log(&message("dpl_1", 1)).await?;
+        assert!(driver.flush().await.is_err());
+        assert!(driver.health().await.is_err());
+
+        let (url, _) = loki_stand_in().await;
+        driver.url = url;
+        driver.send_log(&message("dpl_1", 2)).await?;
+        driver.flush().await?;
+        assert!(driver.health().await.is_ok());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn keeps_entries_of_failed_pushes() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr()?);
+        drop(listener);
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.send_log(&message("dpl_1", 1)).await?;
+        driver.send_log(&message("dpl_2", 2)).await?;
+        assert!(driver.flush().await.is_err());
+        assert_eq!(driver.pending_entries, 2);
+
+        let (url, pushes) = loki_stand_in().await;
+        driver.url = url;
+        driver.flush().await?;
+        let pushes = pushes.lock().unwrap();
+        assert_eq!(pushes.len(), 1);
+        assert_eq!(pushes[0]["streams"].as_array().unwrap().len(), 2);
+        assert_eq!((driver.pending_entries, driver.pending_bytes), (0, 0));
+        let mut delivered = driver.take_settled().delivered;
+        delivered.sort();
+        assert_eq!(delivered, vec![0, 1]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_entries_beyond_the_limits_while_failing() -> Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr()?);
+        drop(listener);
+        let mut driver = driver(url, 2, 1_000_000);
+        for timestamp in 0..4 {
+            driver.send_log(&message("dpl_1", timestamp)).await?;
+        }
+        assert_eq!(driver.pending_entries, 2);
+        let given_up: Vec<u64> = driver
+            .take_settled()
+            .failed
+            .iter()
+            .flat_map(|(seqs, _)| seqs.clone())
+            .collect();
+        assert_eq!(given_up, vec![2, 3]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_entries_loki_refuses() -> Result<()> {
+        let app = Router::new().route(
+            "/loki/api/v1/push",
+            post(|| async { axum::http::StatusCode::BAD_REQUEST }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.send_log(&message("dpl_1", 1)).await?;
+        driver.send_log(&message("dpl_2", 2)).await?;
+        driver.flush().await?;
+        assert!(driver.health().await.is_ok());
+        assert_eq!((driver.pending_entries, driver.pending_bytes), (0, 0));
+
+        let settled = driver.take_settled();
+        assert!(settled.delivered.is_empty());
+        let (seqs, error) = &settled.failed[0];
+        let mut seqs = seqs.clone();
+        seqs.sort();
+        assert_eq!(seqs, vec![0, 1]);
+        assert!(GaveUp::is_permanent(error));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn flush_without_pending_logs_is_a_no_op() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.flush().await?;
+        assert!(pushes.lock().unwrap().is_empty());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn pushes_snappy_compressed_protobuf() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = encoded_driver(url, LokiEncoding::Protobuf, 100, 1_000_000);
+        driver
+            .send_log(&message("dpl_1", 1_700_000_000_123))
+            .await?;
+        driver
+            .send_log(&message("dpl_1", 1_700_000_000_001))
+            .await?;
+        driver.flush().await?;
+
+        let pushes = pushes.lock().unwrap();
+        assert_eq!(pushes.len(), 1);
+        let stream = &pushes[0]["streams"][0];
+        assert_eq!(
+            stream["labels"],
+            r#"{deployment="dpl_1", project="project", source="lambda"}"#
+        );
+        assert_eq!(stream["values"][0][0], "1700000000001000000");
+        assert_eq!(stream["values"][1][0], "1700000000123000000");
+        assert_eq!(stream["values"][0][2]["host"], "example.vercel.app");
+        let line: serde_json::Value =
+            serde_json::from_str(stream["values"][0][1].as_str().unwrap())?;
+        assert_eq!(line["message"], "hello");
+        assert!(line.get("deploymentId").is_none());
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn pushes_each_tenant_separately() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.tenants = LokiTenants::new("shared", "prj_1=team-a,prj_2=team-b")?;
+        driver
+            .send_log(&project_message("prj_1", "dpl_1", 1))
+            .await?;
+        driver
+            .send_log(&project_message("prj_2", "dpl_2", 2))
+            .await?;
+        driver
+            .send_log(&project_message("prj_3", "dpl_3", 3))
+            .await?;
+        driver
+            .send_log(&project_message("prj_1", "dpl_4", 4))
+            .await?;
+        driver.flush().await?;
+
+        let pushes = pushes.lock().unwrap();
+        assert_eq!(pushes.len(), 3);
+        let streams = |tenant: &str| {
+            let push = pushes.iter().find(|push| push["tenant"] == tenant).unwrap();
+            push["streams"].as_array().unwrap().len()
+        };
+        assert_eq!(streams("team-a"), 2);
+        assert_eq!(streams("team-b"), 1);
+        assert_eq!(streams("shared"), 1);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn omits_tenant_header_by_default() -> Result<()> {
+        let (url, pushes) = loki_stand_in().await;
+        let mut driver = driver(url, 100, 1_000_000);
+        driver.send_log(&message("dpl_1", 1)).await?;
+        driver.flush().await?;
+        assert!(pushes.lock().unwrap()[0].get("tenant").is_none());
+        return Ok(());
+    }
+
+    #[test]
+    fn formats_and_escapes_labels() {
+        let labels = BTreeMap::from([
+            ("b".to_owned(), "say \"hi\"\n".to_owned()),
+            ("a".to_owned(), "c:\\".to_owned()),
+        ]);
+        assert_eq!(format_labels(&labels), r#"{a="c:\\", b="say \"hi\"\n"}"#);
+    }
+}
@@ -0,0 +1,181 @@
+use super::Labels;
+use crate::types::Message;
+use anyhow::Result;
+use serde_json::Value;
+
+pub const DEFAULT_LABELS: &str = "project=projectName,source,environment";
+// structured metadata needs loki 3.0 or later with `allow_structured_metadata`
+// enabled, older setups refuse every push that carries it, so it is opt-in
+pub const DEFAULT_STRUCTURED_METADATA: &str = "";
+
+/// A message field, addressed by its dotted JSON path (e.g. `proxy.statusCode`),
+/// and the name it is given in loki.
+#[derive(Debug, Clone, PartialEq)]
+struct FieldMapping {
+    name: String,
+    path: Vec<String>,
+}
+
+impl FieldMapping {
+    /// Parses `name=path` or a bare `path`, in which case the name is the path
+    /// with dots replaced by underscores.
+    fn parse(spec: &str) -> Result<Self> {
+        let (name, path) = match spec.split_once('=') {
+            Some((name, path)) => (name.trim().to_owned(), path.trim()),
+            None => (spec.trim().replace('.', "_"), spec.trim()),
+        };
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            anyhow::bail!("invalid loki label name {:?} in {:?}", name, spec);
+        }
+        if path.is_empty() || path.split('.').any(str::is_empty) {
+            anyhow::bail!("invalid message field {:?} in {:?}", path, spec);
+        }
+        Ok(Self {
+            name,
+            path: path.split('.').map(String::from).collect(),
+        })
+    }
+
+    /// Removes the field from the message, returning it as a string if it was
+    /// set to a scalar value.
+    fn take(&self, message: &mut Value) -> Option<String> {
+        let (last, parents) = self.path.split_last()?;
+        let mut object = message;
+        for key in parents {
+            object = object.get_mut(key)?;
+        }
+        match object.as_object_mut()?.remove(last)? {
+            Value::String(value) if !value.is_empty() => Some(value),
+            Value::Number(value) => Some(value.to_string()),
+            Value::Bool(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn parse_fields(specs: &str) -> Result<Vec<FieldMapping>> {
+    specs
+        .split(',')
+        .filter(|spec| !spec.trim().is_empty())
+        .map(FieldMapping::parse)
+        .collect()
+}
+
+/// Decides which message fields become stream labels, which become structured
+/// metadata, and leaves everything else in the log line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LokiTemplate {
+    labels: Vec<FieldMapping>,
+    structured_metadata: Vec<FieldMapping>,
+}
+
+impl LokiTemplate {
+    /// Builds a template from comma separated `name=path` lists.
+    pub fn new(labels: &str, structured_metadata: &str) -> Result<Self> {
+        Ok(Self {
+            labels: parse_fields(labels)?,
+            structured_metadata: parse_fields(structured_metadata)?,
+        })
+    }
+
+    /// Splits a message into its labels, structured metadata and log line.
+    pub fn render(&self, message: &Message) -> Result<(Labels, Labels, String)> {
+        let mut body = serde_json::to_value(message)?;
+        let mut take_all = |fields: &[FieldMapping]| -> Labels {
+            fields
+                .iter()
+                .filter_map(|field| Some((field.name.clone(), field.take(&mut body)?)))
+                .collect()
+        };
+        let labels = take_all(&self.labels);
+        let structured_metadata = take_all(&self.structured_metadata);
+        Ok((labels, structured_metadata, body.to_string()))
+    }
+}
+
+impl Default for LokiTemplate {
+    fn default() -> Self {
+        Self::new(DEFAULT_LABELS, DEFAULT_STRUCTURED_METADATA)
+            .expect("default loki template is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> Message {
+        serde_json::from_str::<Vec<Message>>(include_str!("../../fixtures/sample_2.json"))
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn default_template_splits_message() -> Result<()> {
+        let (labels, metadata, line) = LokiTemplate::default().render(&message())?;
+        assert_eq!(
+            labels,
+            Labels::from([
+                ("project".to_owned(), "code4rena-com".to_owned()),
+                ("source".to_owned(), "lambda".to_owned()),
+                ("environment".to_owned(), "production".to_owned()),
+            ])
+        );
+        assert!(metadata.is_empty());
+
+        let line: Value = serde_json::from_str(&line)?;
+        assert!(line.get("projectName").is_none());
+        assert_eq!(line["deploymentId"], "dpl_xxxxxxxxxxxxxxxxxxxxxxxxxxxx");
+        assert_eq!(line["proxy"]["statusCode"], 200);
+        assert_eq!(line["proxy"]["method"], "GET");
+        assert_eq!(line["type"], "stdout");
+        return Ok(());
+    }
+
+    #[test]
+    fn structured_metadata_is_taken_out_of_the_line() -> Result<()> {
+        let template = LokiTemplate::new(
+            DEFAULT_LABELS,
+            "deployment=deploymentId,host,status_code=proxy.statusCode",
+        )?;
+        let (_, metadata, line) = template.render(&message())?;
+        assert_eq!(metadata["deployment"], "dpl_xxxxxxxxxxxxxxxxxxxxxxxxxxxx");
+        assert_eq!(metadata["status_code"], "200");
+        assert_eq!(metadata["host"], "code4rena.com");
+
+        let line: Value = serde_json::from_str(&line)?;
+        assert!(line.get("deploymentId").is_none());
+        assert!(line["proxy"].get("statusCode").is_none());
+        return Ok(());
+    }
+
+    #[test]
+    fn missing_fields_are_skipped() -> Result<()> {
+        let template = LokiTemplate::new("build=buildId,type", "")?;
+        let (labels, metadata, _) = template.render(&message())?;
+        assert_eq!(
+            labels,
+            Labels::from([("type".to_owned(), "stdout".to_owned())])
+        );
+        assert!(metadata.is_empty());
+        return Ok(());
+    }
+
+    #[test]
+    fn bare_paths_name_the_label() -> Result<()> {
+        let template = LokiTemplate::new("proxy.region", "")?;
+        let (labels, _, _) = template.render(&message())?;
+        assert_eq!(labels["proxy_region"], "bom1");
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        assert!(LokiTemplate::new("1st=source", "").is_err());
+        assert!(LokiTemplate::new("a-b=source", "").is_err());
+        assert!(LokiTemplate::new("status=proxy.", "").is_err());
+        assert!(LokiTemplate::new("", "name=").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::types::Message;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Picks the `X-Scope-OrgID` a message is pushed under, by project id or
+/// name, falling back to a static tenant.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LokiTenants {
+    default: Option<String>,
+    projects: HashMap<String, String>,
+}
+
+impl LokiTenants {
+    /// Builds the routing from the static tenant (empty for none) and a comma
+    /// separated list of `project=tenant` pairs, where `project` is either a
+    /// project id or a project name.
+    pub fn new(default: &str, projects: &str) -> Result<Self> {
+        let projects = projects
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| match pair.split_once('=') {
+                Some((project, tenant))
+                    if !project.trim().is_empty() && !tenant.trim().is_empty() =>
+                {
+                    Ok((project.trim().to_owned(), tenant.trim().to_owned()))
+                }
+                _ => anyhow::bail!(
+                    "invalid loki tenant mapping {:?}, expected project=tenant",
+                    pair
+                ),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            default: Some(default.trim().to_owned()).filter(|tenant| !tenant.is_empty()),
+            projects,
+        })
+    }
+
+    pub fn tenant(&self, message: &Message) -> Option<&str> {
+        self.projects
+            .get(&message.project_id)
+            .or_else(|| self.projects.get(&message.project_name))
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(project_id: &str, project_name: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "timestamp": 1,
+            "source": "lambda",
+            "projectName": project_name,
+            "projectId": project_id,
+            "deploymentId": "dpl_1",
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn routes_by_project_id_then_name_then_default() -> Result<()> {
+        let tenants = LokiTenants::new("shared", "prj_1=team-a, web=team-b")?;
+        assert_eq!(tenants.tenant(&message("prj_1", "web")), Some("team-a"));
+        assert_eq!(tenants.tenant(&message("prj_2", "web")), Some("team-b"));
+        assert_eq!(tenants.tenant(&message("prj_3", "docs")), Some("shared"));
+        return Ok(());
+    }
+
+    #[test]
+    fn no_tenant_without_default() -> Result<()> {
+        let tenants = LokiTenants::new("", "prj_1=team-a")?;
+        assert_eq!(tenants.tenant(&message("prj_2", "web")), None);
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        assert!(LokiTenants::new("", "prj_1").is_err());
+        assert!(LokiTenants::new("", "prj_1=").is_err());
+        assert!(LokiTenants::new("", "=team-a").is_err());
+    }
+}
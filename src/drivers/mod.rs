@@ -1,5 +1,16 @@
+mod auth;
 mod cloudwatch;
 mod loki;
+mod otlp;
+mod retry;
+mod trace_file;
 
+pub use auth::{HttpAuth, Secret};
 pub use cloudwatch::CloudWatchDriver;
-pub use loki::LokiDriver;
+pub use loki::{
+    LokiBatchConfig, LokiDriver, LokiEncoding, LokiTemplate, LokiTenants, DEFAULT_LABELS,
+    DEFAULT_STRUCTURED_METADATA,
+};
+pub use otlp::OtlpTraceDriver;
+pub use retry::{GaveUp, RetryError, RetryPolicy};
+pub use trace_file::TraceFileDriver;
@@ -0,0 +1,134 @@
+use super::{HttpAuth, RetryError, RetryPolicy};
+use crate::traces::ExportTraceServiceRequest;
+use crate::types::TraceDriver;
+use anyhow::Result;
+use async_trait::async_trait;
+use flate2::{write::GzEncoder, Compression};
+use prost::Message as _;
+use reqwest::Client as HttpClient;
+use std::io::Write;
+use tracing::debug;
+
+/// Exports spans to an OTLP/HTTP endpoint, e.g. an OpenTelemetry collector's
+/// `http://collector:4318/v1/traces`, as gzip compressed protobuf.
+#[derive(Clone)]
+pub struct OtlpTraceDriver {
+    client: HttpClient,
+    url: String,
+    auth: HttpAuth,
+    retry: RetryPolicy,
+}
+
+impl OtlpTraceDriver {
+    pub fn new(url: String, auth: HttpAuth) -> Self {
+        Self {
+            client: HttpClient::new(),
+            url,
+            auth,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn send_export(&self, body: Vec<u8>) -> Result<(), RetryError> {
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header("Content-Type", "application/x-protobuf")
+            .header("Content-Encoding", "gzip")
+            .body(body);
+        req = self.auth.apply(req).await.map_err(RetryError::Permanent)?;
+        let response = req.send().await.map_err(RetryError::from_reqwest)?;
+        debug!("sent trace export");
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(RetryError::from_status(
+                status,
+                anyhow::anyhow!("Failed to export spans: {}", status),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TraceDriver for OtlpTraceDriver {
+    async fn send_traces(&mut self, request: &ExportTraceServiceRequest) -> Result<()> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&request.encode_to_vec())?;
+        let body = encoder.finish()?;
+        let mut retry = self.retry.start("otlp");
+        loop {
+            match self.send_export(body.clone()).await {
+                Ok(_) => return Ok(()),
+                Err(e) => retry.failed(e).await?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, extract::State, http::HeaderMap, routing::post, Router};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    type Exports = Arc<Mutex<Vec<ExportTraceServiceRequest>>>;
+
+    #[tokio::test]
+    async fn exports_protobuf_and_retries() -> Result<()> {
+        // fails the first attempt, then records every decoded export
+        async fn export(
+            State(exports): State<(Exports, Arc<Mutex<usize>>)>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> axum::http::StatusCode {
+            let (exports, attempts) = exports;
+            *attempts.lock().unwrap() += 1;
+            if *attempts.lock().unwrap() == 1 {
+                return axum::http::StatusCode::SERVICE_UNAVAILABLE;
+            }
+            assert_eq!(
+                headers.get("content-type").unwrap(),
+                "application/x-protobuf"
+            );
+            assert_eq!(headers.get("authorization").unwrap(), "Bearer token");
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+            let request = ExportTraceServiceRequest::decode(&decoded[..]).unwrap();
+            exports.lock().unwrap().push(request);
+            axum::http::StatusCode::OK
+        }
+        let exports = Exports::default();
+        let attempts = Arc::new(Mutex::new(0));
+        let app = Router::new()
+            .route("/v1/traces", post(export))
+            .with_state((exports.clone(), attempts.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/v1/traces", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let request: ExportTraceServiceRequest =
+            serde_json::from_str(include_str!("../fixtures/traces.json"))?;
+        let mut driver = OtlpTraceDriver::new(
+            url,
+            HttpAuth::Bearer(super::super::Secret::Value("token".to_owned())),
+        )
+        .with_retry(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+        driver.send_traces(&request).await?;
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        assert_eq!(*exports.lock().unwrap(), vec![request]);
+        return Ok(());
+    }
+}
@@ -0,0 +1,258 @@
+use anyhow::Result;
+use axum_prometheus::metrics::counter;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How a driver retries failed requests: exponential backoff with full jitter,
+/// bounded by both an attempt count and the total time spent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_elapsed: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Attached as context to the error once an operation is given up on, so
+/// callers can tell how many attempts were made and whether trying again
+/// later could still help.
+#[derive(Debug)]
+pub struct GaveUp {
+    pub attempts: usize,
+    pub permanent: bool,
+}
+
+impl GaveUp {
+    /// Whether `error` was given up on because it can never succeed, rather
+    /// than because the retries ran out.
+    pub fn is_permanent(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<GaveUp>()
+            .is_some_and(|gave_up| gave_up.permanent)
+    }
+}
+
+impl std::fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "gave up after {} attempt(s)", self.attempts)
+    }
+}
+
+/// Why an attempt failed, and whether trying again could help.
+#[derive(Debug)]
+pub enum RetryError {
+    Retryable(anyhow::Error),
+    Permanent(anyhow::Error),
+}
+
+impl RetryError {
+    /// Classifies an HTTP response status: 429 and 5xx are worth retrying.
+    pub fn from_status(status: reqwest::StatusCode, error: anyhow::Error) -> Self {
+        match status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            true => RetryError::Retryable(error),
+            false => RetryError::Permanent(error),
+        }
+    }
+
+    /// Classifies a request that didn't produce a response: timeouts and
+    /// connection failures (refused, reset) are worth retrying.
+    pub fn from_reqwest(error: reqwest::Error) -> Self {
+        if let Some(status) = error.status() {
+            return Self::from_status(status, error.into());
+        }
+        match error.is_timeout() || error.is_connect() || error.is_request() {
+            true => RetryError::Retryable(error.into()),
+            false => RetryError::Permanent(error.into()),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Starts retrying one operation on behalf of `driver`.
+    pub fn start(&self, driver: &'static str) -> Retry<'_> {
+        Retry {
+            policy: self,
+            driver,
+            attempts: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// The longest wait before retry `attempt` (counting from 0), the actual
+    /// wait is picked uniformly below it.
+    fn backoff_ceiling(&self, attempt: usize) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.min(31) as u32);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Tracks the attempts of one operation, see `RetryPolicy::start`.
+pub struct Retry<'a> {
+    policy: &'a RetryPolicy,
+    driver: &'static str,
+    attempts: usize,
+    started: Instant,
+}
+
+impl Retry<'_> {
+    /// Records a failed attempt. Waits out the backoff and returns `Ok` when
+    /// the operation should be tried again, otherwise gives up with the error.
+    pub async fn failed(&mut self, error: RetryError) -> Result<()> {
+        self.attempts += 1;
+        let error = match error {
+            RetryError::Permanent(e) => {
+                counter!("drain_driver_retry_give_ups", "driver" => self.driver, "reason" => "permanent")
+                    .increment(1);
+                return Err(self.give_up(e, true));
+            }
+            RetryError::Retryable(e) => e,
+        };
+        let backoff = jitter(self.policy.backoff_ceiling(self.attempts - 1));
+        if self.attempts >= self.policy.max_attempts {
+            warn!(
+                driver = self.driver,
+                attempts = self.attempts,
+                "giving up after max attempts"
+            );
+            counter!("drain_driver_retry_give_ups", "driver" => self.driver, "reason" => "attempts")
+                .increment(1);
+            return Err(self.give_up(error, false));
+        }
+        if self.started.elapsed() + backoff > self.policy.max_elapsed {
+            warn!(
+                driver = self.driver,
+                attempts = self.attempts,
+                elapsed = ?self.started.elapsed(),
+                "giving up after max elapsed time"
+            );
+            counter!("drain_driver_retry_give_ups", "driver" => self.driver, "reason" => "elapsed")
+                .increment(1);
+            return Err(self.give_up(error, false));
+        }
+        warn!(
+            driver = self.driver,
+            attempt = self.attempts,
+            ?backoff,
+            "retrying after error: {:?}",
+            error
+        );
+        counter!("drain_driver_retries", "driver" => self.driver).increment(1);
+        tokio::time::sleep(backoff).await;
+        Ok(())
+    }
+
+    fn give_up(&self, error: anyhow::Error, permanent: bool) -> anyhow::Error {
+        error.context(GaveUp {
+            attempts: self.attempts,
+            permanent,
+        })
+    }
+}
+
+/// Full jitter: a random duration between zero and `ceiling`.
+fn jitter(ceiling: Duration) -> Duration {
+    let mut bytes = [0u8; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return ceiling;
+    }
+    let fraction = u64::from_le_bytes(bytes) as f64 / u64::MAX as f64;
+    ceiling.mul_f64(fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: usize, max_elapsed: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            max_elapsed,
+        }
+    }
+
+    fn retryable() -> RetryError {
+        RetryError::Retryable(anyhow::anyhow!("unavailable"))
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let policy = policy(10, Duration::from_secs(1));
+        let ceilings: Vec<u128> = (0..5)
+            .map(|attempt| policy.backoff_ceiling(attempt).as_millis())
+            .collect();
+        assert_eq!(ceilings, vec![1, 2, 4, 4, 4]);
+        assert_eq!(policy.backoff_ceiling(1000), Duration::from_millis(4));
+    }
+
+    #[test]
+    fn jitter_stays_below_the_ceiling() {
+        for _ in 0..100 {
+            assert!(jitter(Duration::from_millis(10)) <= Duration::from_millis(10));
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() -> Result<()> {
+        let policy = policy(3, Duration::from_secs(10));
+        let mut retry = policy.start("test");
+        retry.failed(retryable()).await?;
+        retry.failed(retryable()).await?;
+        let error = retry.failed(retryable()).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<GaveUp>().unwrap().attempts, 3);
+        assert!(!GaveUp::is_permanent(&error));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let policy = policy(3, Duration::from_secs(10));
+        let mut retry = policy.start("test");
+        let error = RetryError::Permanent(anyhow::anyhow!("bad request"));
+        let error = retry.failed(error).await.unwrap_err();
+        assert!(GaveUp::is_permanent(&error));
+        assert_eq!(retry.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_elapsed_time() -> Result<()> {
+        let mut policy = policy(100, Duration::from_millis(20));
+        policy.initial_backoff = Duration::from_millis(10);
+        policy.max_backoff = Duration::from_millis(10);
+        let mut retry = policy.start("test");
+        let started = Instant::now();
+        while retry.failed(retryable()).await.is_ok() {}
+        assert!(retry.attempts < 100);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        return Ok(());
+    }
+
+    #[test]
+    fn classifies_http_statuses() {
+        let classify = |status| {
+            matches!(
+                RetryError::from_status(status, anyhow::anyhow!("failed")),
+                RetryError::Retryable(_)
+            )
+        };
+        assert!(classify(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(classify(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(!classify(reqwest::StatusCode::BAD_REQUEST));
+        assert!(!classify(reqwest::StatusCode::UNAUTHORIZED));
+    }
+}
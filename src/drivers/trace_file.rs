@@ -0,0 +1,50 @@
+use crate::deadletter::DeadLetterFile;
+use crate::traces::ExportTraceServiceRequest;
+use crate::types::TraceDriver;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::Path;
+
+/// Appends every export to a rotated file as one line of OTLP/JSON, the
+/// format the collector's `otlpjsonfile` receiver reads.
+pub struct TraceFileDriver {
+    file: DeadLetterFile,
+}
+
+impl TraceFileDriver {
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> Result<Self> {
+        Ok(Self {
+            file: DeadLetterFile::open(path, max_bytes, max_files)?,
+        })
+    }
+}
+
+#[async_trait]
+impl TraceDriver for TraceFileDriver {
+    async fn send_traces(&mut self, request: &ExportTraceServiceRequest) -> Result<()> {
+        self.file.write(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_one_export_per_line() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("traces.ndjson");
+        let mut driver = TraceFileDriver::open(&path, u64::MAX, 1)?;
+        let request: ExportTraceServiceRequest =
+            serde_json::from_str(include_str!("../fixtures/traces.json"))?;
+        driver.send_traces(&request).await?;
+        driver.send_traces(&request).await?;
+
+        let lines: Vec<ExportTraceServiceRequest> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines, vec![request.clone(), request]);
+        return Ok(());
+    }
+}
@@ -0,0 +1,170 @@
+use crate::secrets::Secrets;
+use crate::signature::SignatureScheme;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Path segments under `/vercel` that belong to the drain's own routes.
+const RESERVED_NAMES: &[&str] = &["speed-insights", "web-analytics"];
+
+/// A named ingest endpoint, served at `/vercel/<name>` with a verify token,
+/// secrets and drivers of its own. Lets one drain serve several vercel teams.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub name: String,
+    pub vercel_verify: String,
+    pub vercel_secrets: Secrets,
+    // `None` keeps the scheme of the route
+    pub signature: Option<SignatureScheme>,
+    // drivers its messages go to, every driver when empty
+    pub drivers: Vec<String>,
+}
+
+/// One endpoint as written in the endpoints file.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct EndpointConfig {
+    name: String,
+    verify: String,
+    secret: Option<String>,
+    #[serde(default)]
+    secrets: Vec<String>,
+    secret_file: Option<PathBuf>,
+    signature: Option<String>,
+    #[serde(default)]
+    drivers: Vec<String>,
+}
+
+/// Every configured endpoint by name.
+#[derive(Debug, Clone, Default)]
+pub struct Endpoints(Arc<HashMap<String, Endpoint>>);
+
+impl Endpoints {
+    /// Reads a JSON array of endpoints, refusing drivers that aren't in
+    /// `drivers`, the names of the enabled ones.
+    pub fn load(path: &Path, drivers: &[&str]) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed reading endpoints file {}", path.display()))?;
+        let configs: Vec<EndpointConfig> = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid endpoints file {}", path.display()))?;
+        let mut endpoints = HashMap::new();
+        for config in configs {
+            let name = config.name;
+            if name.is_empty()
+                || RESERVED_NAMES.contains(&name.as_str())
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                anyhow::bail!("invalid endpoint name {:?}", name);
+            }
+            if let Some(driver) = config
+                .drivers
+                .iter()
+                .find(|driver| !drivers.contains(&driver.as_str()))
+            {
+                anyhow::bail!(
+                    "endpoint {:?} uses driver {:?}, which is not enabled",
+                    name,
+                    driver
+                );
+            }
+            let endpoint = Endpoint {
+                vercel_verify: config.verify,
+                vercel_secrets: Secrets::new(
+                    config.secret.into_iter().chain(config.secrets).collect(),
+                    config.secret_file,
+                )
+                .with_context(|| format!("endpoint {:?}", name))?,
+                signature: config
+                    .signature
+                    .map(|spec| spec.parse())
+                    .transpose()
+                    .with_context(|| format!("endpoint {:?}", name))?,
+                drivers: config.drivers,
+                name: name.clone(),
+            };
+            if endpoints.insert(name.clone(), endpoint).is_some() {
+                anyhow::bail!("endpoint {:?} is defined twice", name);
+            }
+        }
+        return Ok(Self(Arc::new(endpoints)));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Endpoint> {
+        self.0.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Endpoint> {
+        self.0.values()
+    }
+
+    /// The drivers of every endpoint that doesn't go to all of them.
+    pub fn routes(&self) -> HashMap<String, Vec<String>> {
+        self.iter()
+            .filter(|endpoint| !endpoint.drivers.is_empty())
+            .map(|endpoint| (endpoint.name.clone(), endpoint.drivers.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn load(contents: &str) -> Result<Endpoints> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(contents.as_bytes())?;
+        return Endpoints::load(file.path(), &["loki", "cloudwatch"]);
+    }
+
+    #[test]
+    fn loads_endpoints() -> Result<()> {
+        let endpoints = load(
+            r#"[
+                {"name": "team-a", "verify": "a", "secrets": ["one", "two"], "drivers": ["loki"]},
+                {"name": "team_b", "verify": "b", "secret": "th,ree",
+                 "signature": "sha256:x-signature:base64"}
+            ]"#,
+        )?;
+        let team_a = endpoints.get("team-a").unwrap();
+        assert_eq!(team_a.vercel_verify, "a");
+        assert_eq!(team_a.drivers, vec!["loki"]);
+        assert!(team_a.signature.is_none());
+        let team_b = endpoints.get("team_b").unwrap();
+        assert_eq!(team_b.signature.as_ref().unwrap().header, "x-signature");
+        let sha256 = ring::hmac::HMAC_SHA256;
+        let signature = ring::hmac::sign(&ring::hmac::Key::new(sha256, b"th,ree"), b"[]");
+        assert!(team_b
+            .vercel_secrets
+            .verify(sha256, b"[]", signature.as_ref())
+            .is_some());
+        assert!(endpoints.get("team-c").is_none());
+        assert_eq!(
+            endpoints.routes(),
+            HashMap::from([(String::from("team-a"), vec![String::from("loki")])])
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn refuses_invalid_endpoints() {
+        let invalid = [
+            r#"[{"name": "speed-insights", "verify": "a", "secret": "s"}]"#,
+            r#"[{"name": "team/a", "verify": "a", "secret": "s"}]"#,
+            r#"[{"name": "team-a", "verify": "a"}]"#,
+            r#"[{"name": "team-a", "verify": "a", "secret": "s", "drivers": ["file"]}]"#,
+            r#"[{"name": "team-a", "verify": "a", "secret": "s", "signature": "md5:x:hex"}]"#,
+            r#"[{"name": "team-a", "verify": "a", "secret": "s", "secretFiles": "t"}]"#,
+            r#"[{"name": "team-a", "verify": "a", "secrets": "s, t"}]"#,
+            r#"[{"name": "a", "verify": "a", "secret": "s"}, {"name": "a", "verify": "b", "secret": "t"}]"#,
+        ];
+        for contents in invalid {
+            assert!(load(contents).is_err(), "{}", contents);
+        }
+    }
+}
@@ -1,12 +1,18 @@
-use crate::types;
+use crate::compression::{self, DecompressError};
+use crate::deadletter::now_millis;
+use crate::dedupe::{self, Dedupe};
+use crate::secrets::Secrets;
+use crate::signature::{SignatureError, SignatureScheme, SignedBody};
+use crate::{traces, types};
 use axum::{
     body::{Body, Bytes},
-    extract::State,
-    http::{header::HeaderMap, Response, StatusCode},
+    extract::{MatchedPath, Path, State},
+    http::{header, header::HeaderMap, Response, StatusCode},
     response::IntoResponse,
+    Json,
 };
 use axum_prometheus::metrics::counter;
-use ring::hmac;
+use std::time::Instant;
 use tracing::{debug, error, warn};
 
 pub async fn root() -> impl IntoResponse {
@@ -20,23 +26,84 @@ pub async fn health_check() -> impl IntoResponse {
     return StatusCode::OK;
 }
 
-pub async fn ingest(
+/// `503 Service Unavailable` while the pod can't make progress, the body
+/// reports on the controller, the ingest queue and every driver.
+pub async fn ready_check(State(state): State<types::AppState>) -> impl IntoResponse {
+    let report = state.readiness.report(&state.log_queue);
+    let status = match report.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    return (status, Json(report));
+}
+
+/// Who a payload is received for, checked against their verify token,
+/// secrets and signature scheme.
+struct Recipient<'a> {
+    vercel_verify: &'a str,
+    vercel_secrets: &'a Secrets,
+    signature: &'a SignatureScheme,
+    // `None` for the default routes
+    endpoint: Option<&'a str>,
+}
+
+/// Receives a drain payload of records of type `T`, the log messages of a
+/// log drain or the records of another drain kind.
+pub async fn ingest<T: types::Record>(
     State(state): State<types::AppState>,
+    route: MatchedPath,
     headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
-    debug!("received payload");
+    let recipient = Recipient {
+        vercel_verify: &state.vercel_verify,
+        vercel_secrets: &state.vercel_secrets,
+        signature: state.signatures.for_route(route.as_str()),
+        endpoint: None,
+    };
+    return receive::<T>(&state, &recipient, &headers, body).await;
+}
 
-    let signature = match headers.get("x-vercel-signature") {
-        Some(signature) => signature.to_str().unwrap(),
-        None => {
-            warn!("received payload without signature");
-            counter!("drain_recv_invalid_signature").increment(1);
-            return Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::empty())
-                .expect("Defined Responses to be infalliable.");
-        }
+/// Like `ingest`, for the named endpoint in the path. `404 Not Found` when
+/// there is no such endpoint.
+pub async fn ingest_endpoint<T: types::Record>(
+    State(state): State<types::AppState>,
+    Path(name): Path<String>,
+    route: MatchedPath,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let Some(endpoint) = state.endpoints.get(&name) else {
+        warn!(endpoint = name, "received payload for an unknown endpoint");
+        counter!("drain_recv_unknown_endpoint").increment(1);
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("Defined Responses to be infalliable.");
+    };
+    let recipient = Recipient {
+        vercel_verify: &endpoint.vercel_verify,
+        vercel_secrets: &endpoint.vercel_secrets,
+        signature: endpoint
+            .signature
+            .as_ref()
+            .unwrap_or_else(|| state.signatures.for_route(route.as_str())),
+        endpoint: Some(&endpoint.name),
+    };
+    return receive::<T>(&state, &recipient, &headers, body).await;
+}
+
+async fn receive<T: types::Record>(
+    state: &types::AppState,
+    recipient: &Recipient<'_>,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    debug!(endpoint = recipient.endpoint, "received payload");
+
+    let body = match verified_body(state, recipient, headers, body).await {
+        Ok(body) => body,
+        Err(response) => return *response,
     };
     let body_string = match String::from_utf8(body.to_vec()) {
         Ok(body_string) => body_string,
@@ -49,39 +116,339 @@ pub async fn ingest(
                 .expect("Defined Responses to be infalliable.");
         }
     };
-    let mut sig_bytes = [0u8; 20];
-    hex::decode_to_slice(signature, &mut sig_bytes).unwrap();
-    match hmac::verify(&state.vercel_secret, body_string.as_bytes(), &sig_bytes) {
-        Ok(_) => {}
+    let parsed = match is_ndjson(headers, &body_string) {
+        true => Ok(types::ParsedPayload::parse_ndjson::<T>(&body_string)),
+        false => types::ParsedPayload::parse::<T>(&body_string),
+    };
+    let mut payload = match parsed {
+        Ok(payload) => payload,
         Err(e) => {
-            error!("failed verifying signature: {:?}", e);
-            counter!("drain_failed_verify_signature").increment(1);
+            error!(payload = ?body_string, "failed parsing: {:?}", e);
+            counter!("drain_recv_invalid_payloads").increment(1);
             return Response::builder()
-                .status(StatusCode::UNPROCESSABLE_ENTITY)
-                .header("x-vercel-verify", state.vercel_verify)
+                .status(StatusCode::OK)
+                .header("x-vercel-verify", recipient.vercel_verify)
                 .body(Body::empty())
                 .expect("Defined Responses to be infalliable.");
         }
+    };
+    // always set, a payload can't pick its own endpoint
+    for message in &mut payload.messages {
+        message.endpoint = recipient.endpoint.map(str::to_owned);
     }
-    match serde_json::from_str::<types::VercelPayload>(&body_string) {
-        Ok(payload) => {
-            debug!("parsed payload, OK");
-            for message in payload.0 {
-                match state.log_queue.send(message) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("failed to queue log message to be sent to outputs: {:?}", e);
-                    }
-                }
-            }
+    if let Some(max_skew) = state.max_timestamp_skew {
+        let stale = dedupe::drop_outside_window(&mut payload.messages, max_skew, now_millis());
+        if stale > 0 {
+            warn!(
+                endpoint = recipient.endpoint,
+                stale, "dropped messages with a timestamp outside the allowed skew"
+            );
+            counter!(
+                "drain_recv_stale_messages",
+                "endpoint" => recipient.endpoint.unwrap_or_default().to_owned()
+            )
+            .increment(stale as u64);
+        }
+    }
+    match forward(state, payload).await {
+        Ok(_) => {}
+        Err(Refused::Wal) => {
+            return Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("x-vercel-verify", recipient.vercel_verify)
+                .body(Body::empty())
+                .expect("Defined Responses to be infalliable.");
+        }
+        Err(Refused::QueueFull) => {
+            return Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("retry-after", "1")
+                .header("x-vercel-verify", recipient.vercel_verify)
+                .body(Body::empty())
+                .expect("Defined Responses to be infalliable.");
         }
+    }
+    return Response::builder()
+        .status(StatusCode::OK)
+        .header("x-vercel-verify", recipient.vercel_verify)
+        .body(Body::empty())
+        .expect("Defined Responses to be infalliable.");
+}
+
+/// Receives OTLP/HTTP trace exports, protobuf or JSON, and hands them to
+/// the trace drivers. `404 Not Found` when no trace driver is enabled.
+pub async fn ingest_traces(
+    State(state): State<types::AppState>,
+    route: MatchedPath,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    debug!("received trace export");
+
+    let Some(traces) = &state.traces else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .expect("Defined Responses to be infalliable.");
+    };
+    let recipient = Recipient {
+        vercel_verify: &state.vercel_verify,
+        vercel_secrets: &state.vercel_secrets,
+        signature: state.signatures.for_route(route.as_str()),
+        endpoint: None,
+    };
+    let body = match verified_body(&state, &recipient, &headers, body).await {
+        Ok(body) => body,
+        Err(response) => return *response,
+    };
+    let Some(encoding) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(traces::Encoding::from_content_type)
+    else {
+        warn!("received trace export with an unsupported content type");
+        counter!("drain_recv_invalid_traces").increment(1);
+        return Response::builder()
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .header("x-vercel-verify", state.vercel_verify)
+            .body(Body::empty())
+            .expect("Defined Responses to be infalliable.");
+    };
+    let mut request = match encoding.decode(&body) {
+        Ok(request) => request,
         Err(e) => {
-            error!(payload = ?body_string, "failed parsing: {:?}", e);
+            error!("failed decoding trace export: {:?}", e);
+            counter!("drain_recv_invalid_traces").increment(1);
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header("x-vercel-verify", state.vercel_verify)
+                .body(Body::empty())
+                .expect("Defined Responses to be infalliable.");
         }
+    };
+    traces::correlate(&mut request);
+    let spans = traces::span_count(&request);
+    debug!(spans, "decoded trace export, OK");
+    if let Err(e) = traces.send(request) {
+        warn!("refusing trace export: {}", e);
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("retry-after", "1")
+            .header("x-vercel-verify", state.vercel_verify)
+            .body(Body::empty())
+            .expect("Defined Responses to be infalliable.");
     }
+    counter!("drain_recv_spans").increment(spans as u64);
     return Response::builder()
         .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, encoding.content_type())
         .header("x-vercel-verify", state.vercel_verify)
-        .body(Body::empty())
+        .body(Body::from(encoding.accepted()))
         .expect("Defined Responses to be infalliable.");
 }
+
+/// Checks the body's signature and undoes its `Content-Encoding`, in the
+/// order the recipient's scheme says it was signed in, returning the body to
+/// parse or the response to send instead.
+async fn verified_body(
+    state: &types::AppState,
+    recipient: &Recipient<'_>,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Bytes, Box<Response<Body>>> {
+    // checked first, so unsigned bodies are never decompressed
+    let signed_raw = recipient.signature.body == SignedBody::Raw;
+    if signed_raw {
+        if let Some(response) = verify_signature(recipient, headers, &body) {
+            return Err(Box::new(response));
+        }
+    }
+    let body = decompressed_body(state, recipient, headers, body).await?;
+    if !signed_raw {
+        if let Some(response) = verify_signature(recipient, headers, &body) {
+            return Err(Box::new(response));
+        }
+    }
+    return Ok(body);
+}
+
+/// Undoes the body's `Content-Encoding` on the blocking pool, returning the
+/// response to send when it can't be undone.
+async fn decompressed_body(
+    state: &types::AppState,
+    recipient: &Recipient<'_>,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Bytes, Box<Response<Body>>> {
+    if !headers.contains_key(header::CONTENT_ENCODING) {
+        return Ok(body);
+    }
+    let max_bytes = state.max_decompressed_bytes;
+    let decompressed = {
+        let (headers, body) = (headers.clone(), body.clone());
+        tokio::task::spawn_blocking(move || compression::decompress(&headers, &body, max_bytes))
+            .await
+            .unwrap_or_else(|e| Err(DecompressError::Invalid(std::io::Error::other(e))))
+    };
+    match decompressed {
+        Ok(decompressed) => return Ok(decompressed.map(Bytes::from).unwrap_or(body)),
+        Err(e) => {
+            warn!("refusing body: {}", e);
+            let (status, reason) = match e {
+                DecompressError::Unsupported(_) => {
+                    (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported")
+                }
+                DecompressError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, "too_large"),
+                DecompressError::Invalid(_) => (StatusCode::BAD_REQUEST, "invalid"),
+            };
+            counter!("drain_recv_undecodable_bodies", "reason" => reason).increment(1);
+            return Err(Box::new(
+                Response::builder()
+                    .status(status)
+                    .header("x-vercel-verify", recipient.vercel_verify)
+                    .body(Body::empty())
+                    .expect("Defined Responses to be infalliable."),
+            ));
+        }
+    }
+}
+
+/// Checks the recipient's signature header, `x-vercel-signature` unless
+/// configured otherwise, against `body` and every secret, returning the
+/// response to send when none matches.
+fn verify_signature(
+    recipient: &Recipient,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Option<Response<Body>> {
+    let scheme = recipient.signature;
+    let verified = match scheme.signature(headers) {
+        Ok(signature) => recipient
+            .vercel_secrets
+            .verify(scheme.algorithm.hmac(), body, &signature)
+            .ok_or_else(|| String::from("signature mismatch")),
+        Err(SignatureError::Missing) => {
+            warn!(header = %scheme.header, "received payload without signature");
+            counter!("drain_recv_invalid_signature").increment(1);
+            return Some(
+                Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::empty())
+                    .expect("Defined Responses to be infalliable."),
+            );
+        }
+        Err(SignatureError::Malformed(e)) => Err(e),
+    };
+    match verified {
+        Ok(key) => {
+            counter!(
+                "drain_recv_verified_signatures",
+                "key" => key,
+                "endpoint" => recipient.endpoint.unwrap_or_default().to_owned()
+            )
+            .increment(1);
+            return None;
+        }
+        Err(e) => {
+            error!("failed verifying signature: {}", e);
+            counter!("drain_failed_verify_signature").increment(1);
+            return Some(
+                Response::builder()
+                    .status(StatusCode::UNPROCESSABLE_ENTITY)
+                    .header("x-vercel-verify", recipient.vercel_verify)
+                    .body(Body::empty())
+                    .expect("Defined Responses to be infalliable."),
+            );
+        }
+    }
+}
+
+/// Why a parsed payload wasn't accepted, already logged by `forward`.
+#[derive(Debug, PartialEq)]
+pub enum Refused {
+    Wal,
+    QueueFull,
+}
+
+/// Queues the valid messages of a payload, through the write-ahead log when
+/// there is one, then reports and quarantines the invalid ones. Shared by
+/// every ingest path.
+pub async fn forward(
+    state: &types::AppState,
+    mut payload: types::ParsedPayload<'_>,
+) -> Result<(), Refused> {
+    let mut reserved = Vec::new();
+    if let Some(dedupe) = &state.dedupe {
+        let duplicates = dedupe.drop_seen(&mut payload.messages, Instant::now());
+        if duplicates > 0 {
+            debug!(duplicates, "dropped messages seen before");
+            counter!("drain_recv_duplicate_messages").increment(duplicates as u64);
+        }
+        reserved = Dedupe::keys(&payload.messages);
+    }
+    // vercel retries a refused payload, which mustn't be taken for a replay
+    let release = || {
+        if let Some(dedupe) = &state.dedupe {
+            dedupe.release(&reserved);
+        }
+    };
+    if !payload.messages.is_empty() {
+        debug!(
+            messages = payload.messages.len(),
+            invalid = payload.invalid.len(),
+            "parsed payload, OK"
+        );
+        let batch = match &state.wal {
+            Some(wal) => match wal.append(payload.messages).await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("failed writing payload to the write-ahead log: {:?}", e);
+                    counter!("drain_wal_failed_appends").increment(1);
+                    release();
+                    return Err(Refused::Wal);
+                }
+            },
+            None => payload
+                .messages
+                .into_iter()
+                .map(types::Envelope::from)
+                .collect(),
+        };
+        if let Err(e) = state.log_queue.send_batch(batch) {
+            warn!("refusing payload: {}", e);
+            release();
+            return Err(Refused::QueueFull);
+        }
+    }
+    // only once the payload is accepted, a retried payload would be
+    // quarantined twice
+    for invalid in &payload.invalid {
+        warn!(
+            field = invalid.field,
+            message = invalid.raw,
+            "failed parsing message: {}",
+            invalid.error
+        );
+        counter!("drain_recv_invalid_messages", "field" => invalid.field.clone()).increment(1);
+    }
+    if let Some(quarantine) = &state.quarantine {
+        quarantine.write(&payload.invalid).await;
+    }
+    return Ok(());
+}
+
+/// Vercel delivers either a JSON array or newline delimited JSON, told apart
+/// by the content type and otherwise by whether the body opens an object.
+/// Anything else is read as JSON, so garbage is refused as a whole instead of
+/// line by line.
+fn is_ndjson(headers: &HeaderMap, body: &str) -> bool {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase());
+    match content_type.as_deref() {
+        Some("application/x-ndjson" | "application/ndjson" | "application/jsonl") => true,
+        _ => body.trim_start().starts_with('{'),
+    }
+}
@@ -1,22 +1,54 @@
+mod analytics;
 mod app;
+mod breaker;
+mod compression;
 mod controller;
+mod deadletter;
+mod dedupe;
 mod drivers;
+mod endpoints;
 mod handlers;
+mod quarantine;
+mod queue;
+mod readiness;
+mod secrets;
+mod signature;
+mod syslog;
+mod traces;
 mod types;
+mod wal;
 
-use crate::drivers::{CloudWatchDriver, LokiDriver};
-use crate::types::LogDriver;
+use crate::breaker::{BreakerConfig, OpenAction};
+use crate::controller::DriverSpec;
+use crate::deadletter::{DeadLetterFile, DeadLetterTarget};
+use crate::dedupe::Dedupe;
+use crate::drivers::{
+    CloudWatchDriver, HttpAuth, LokiBatchConfig, LokiDriver, LokiEncoding, LokiTemplate,
+    LokiTenants, OtlpTraceDriver, RetryPolicy, Secret, TraceFileDriver, DEFAULT_LABELS,
+    DEFAULT_STRUCTURED_METADATA,
+};
+use crate::endpoints::Endpoints;
+use crate::quarantine::Quarantine;
+use crate::queue::OverflowPolicy;
+use crate::secrets::Secrets;
+use crate::signature::RouteSignatures;
+use crate::wal::{FsyncPolicy, Wal, WalConfig};
 use axum::routing::get;
 use axum_prometheus::PrometheusMetricLayerBuilder;
-use clap::Parser;
-use ring::hmac;
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::signal::{unix, unix::SignalKind};
-use tokio::sync::mpsc;
-use tracing::{debug, info, Level};
+use tracing::{debug, info, warn, Level};
 
 #[derive(Debug, Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, env = "VERCEL_LOG_DRAIN_LOG_LEVEL", default_value_t = Level::INFO)]
     log: Level,
     #[arg(short, long, env = "VERCEL_LOG_DRAIN_IP", default_value = "0.0.0.0")]
@@ -24,10 +56,172 @@ struct Args {
     #[arg(short, long, env = "VERCEL_LOG_DRAIN_PORT", default_value_t = 8000)]
     port: u16,
 
-    #[arg(long, env = "VERCEL_VERIFY")]
-    vercel_verify: String,
-    #[arg(long, env = "VERCEL_SECRET")]
-    vercel_secret: String,
+    #[arg(long, env = "VERCEL_VERIFY", required = true)]
+    vercel_verify: Option<String>,
+    #[arg(
+        long,
+        env = "VERCEL_SECRET",
+        required_unless_present_any = ["vercel_secrets", "vercel_secret_file"]
+    )]
+    vercel_secret: Option<String>,
+    #[arg(long, env = "VERCEL_SECRETS")]
+    vercel_secrets: Option<String>,
+    #[arg(long, env = "VERCEL_SECRET_FILE")]
+    vercel_secret_file: Option<PathBuf>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_ROUTE_SIGNATURES", default_value = "")]
+    route_signatures: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_ENDPOINTS_FILE")]
+    endpoints_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_MAX_DECOMPRESSED_BYTES",
+        default_value_t = 16_777_216
+    )]
+    max_decompressed_bytes: usize,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_MAX_TIMESTAMP_SKEW_MS")]
+    max_timestamp_skew_ms: Option<u64>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_DEDUPE_WINDOW_MS")]
+    dedupe_window_ms: Option<u64>,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_DEDUPE_CAPACITY",
+        default_value_t = 100_000
+    )]
+    dedupe_capacity: usize,
+
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_QUEUE_CAPACITY",
+        default_value_t = 10_000
+    )]
+    queue_capacity: usize,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_QUEUE_OVERFLOW", value_enum, default_value_t = OverflowPolicy::Reject)]
+    queue_overflow: OverflowPolicy,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_QUEUE_SPILL_DIR")]
+    queue_spill_dir: Option<PathBuf>,
+
+    #[arg(long, env = "VERCEL_LOG_DRAIN_WAL_DIR")]
+    wal_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_WAL_SEGMENT_BYTES",
+        default_value_t = 16_777_216
+    )]
+    wal_segment_bytes: u64,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_WAL_FSYNC", value_enum, default_value_t = FsyncPolicy::Interval)]
+    wal_fsync: FsyncPolicy,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_WAL_FSYNC_INTERVAL_MS",
+        default_value_t = 1000
+    )]
+    wal_fsync_interval_ms: u64,
+
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_SHUTDOWN_TIMEOUT_MS",
+        default_value_t = 20_000
+    )]
+    shutdown_timeout_ms: u64,
+
+    #[arg(long, env = "VERCEL_LOG_DRAIN_DEAD_LETTER_FILE")]
+    dead_letter_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_DEAD_LETTER_MAX_BYTES",
+        default_value_t = 104_857_600
+    )]
+    dead_letter_max_bytes: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_DEAD_LETTER_MAX_FILES",
+        default_value_t = 5
+    )]
+    dead_letter_max_files: usize,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_DEAD_LETTER_DRIVER")]
+    dead_letter_driver: Option<String>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_SYSLOG_PORT")]
+    syslog_port: Option<u16>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_SYSLOG_TLS_CERT")]
+    syslog_tls_cert: Option<PathBuf>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_SYSLOG_TLS_KEY")]
+    syslog_tls_key: Option<PathBuf>,
+
+    #[arg(long, env = "VERCEL_LOG_DRAIN_QUARANTINE_FILE")]
+    quarantine_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_QUARANTINE_MAX_BYTES",
+        default_value_t = 104_857_600
+    )]
+    quarantine_max_bytes: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_QUARANTINE_MAX_FILES",
+        default_value_t = 5
+    )]
+    quarantine_max_files: usize,
+
+    #[arg(long, env = "VERCEL_LOG_DRAIN_TRACE_OTLP_URL")]
+    trace_otlp_url: Option<String>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_TRACE_OTLP_HEADERS", default_value = "")]
+    trace_otlp_headers: String,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_OTLP_HEADER_FILES",
+        default_value = ""
+    )]
+    trace_otlp_header_files: String,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_OTLP_RETRY_MAX_ATTEMPTS",
+        default_value_t = 6
+    )]
+    trace_otlp_retry_max_attempts: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_OTLP_RETRY_INITIAL_BACKOFF_MS",
+        default_value_t = 100
+    )]
+    trace_otlp_retry_initial_backoff_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_OTLP_RETRY_MAX_BACKOFF_MS",
+        default_value_t = 10_000
+    )]
+    trace_otlp_retry_max_backoff_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_OTLP_RETRY_MAX_ELAPSED_MS",
+        default_value_t = 60_000
+    )]
+    trace_otlp_retry_max_elapsed_ms: u64,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_TRACE_FILE")]
+    trace_file: Option<PathBuf>,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_FILE_MAX_BYTES",
+        default_value_t = 104_857_600
+    )]
+    trace_file_max_bytes: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_FILE_MAX_FILES",
+        default_value_t = 5
+    )]
+    trace_file_max_files: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_TRACE_QUEUE_CAPACITY",
+        default_value_t = 1000
+    )]
+    trace_queue_capacity: usize,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_TRACE_DEAD_LETTER_FILE")]
+    trace_dead_letter_file: Option<PathBuf>,
+
+    #[arg(long, env = "VERCEL_LOG_DRAIN_BREAKER_OPEN_ACTION", value_enum, default_value_t = OpenAction::Buffer)]
+    breaker_open_action: OpenAction,
 
     #[arg(long, env = "VERCEL_LOG_DRAIN_ENABLE_METRICS")]
     enable_metrics: bool,
@@ -36,6 +230,60 @@ struct Args {
 
     #[arg(long, env = "VERCEL_LOG_DRAIN_ENABLE_CLOUDWATCH")]
     enable_cloudwatch: bool,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_LINGER_MS",
+        default_value_t = 1000
+    )]
+    cloudwatch_linger_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_QUEUE_CAPACITY",
+        default_value_t = 10_000
+    )]
+    cloudwatch_queue_capacity: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_CONCURRENCY",
+        default_value_t = 1
+    )]
+    cloudwatch_concurrency: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_RETRY_MAX_ATTEMPTS",
+        default_value_t = 6
+    )]
+    cloudwatch_retry_max_attempts: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_RETRY_INITIAL_BACKOFF_MS",
+        default_value_t = 100
+    )]
+    cloudwatch_retry_initial_backoff_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_RETRY_MAX_BACKOFF_MS",
+        default_value_t = 10_000
+    )]
+    cloudwatch_retry_max_backoff_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_RETRY_MAX_ELAPSED_MS",
+        default_value_t = 60_000
+    )]
+    cloudwatch_retry_max_elapsed_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_BREAKER_FAILURE_THRESHOLD",
+        default_value_t = 5
+    )]
+    cloudwatch_breaker_failure_threshold: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_CLOUDWATCH_BREAKER_COOLDOWN_MS",
+        default_value_t = 30_000
+    )]
+    cloudwatch_breaker_cooldown_ms: u64,
 
     #[arg(long, env = "VERCEL_LOG_DRAIN_ENABLE_LOKI")]
     enable_loki: bool,
@@ -45,6 +293,95 @@ struct Args {
     loki_basic_auth_user: String,
     #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_PASS", default_value = "")]
     loki_basic_auth_pass: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_PASS_FILE")]
+    loki_basic_auth_pass_file: Option<PathBuf>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_BEARER_TOKEN", default_value = "")]
+    loki_bearer_token: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_BEARER_TOKEN_FILE")]
+    loki_bearer_token_file: Option<PathBuf>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_HEADERS", default_value = "")]
+    loki_headers: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_HEADER_FILES", default_value = "")]
+    loki_header_files: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_ENCODING", value_enum, default_value_t = LokiEncoding::Json)]
+    loki_encoding: LokiEncoding,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_LABELS", default_value = DEFAULT_LABELS)]
+    loki_labels: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_STRUCTURED_METADATA", default_value = DEFAULT_STRUCTURED_METADATA)]
+    loki_structured_metadata: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_TENANT", default_value = "")]
+    loki_tenant: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_TENANT_MAP", default_value = "")]
+    loki_tenant_map: String,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_BATCH_MAX_ENTRIES",
+        default_value_t = 1000
+    )]
+    loki_batch_max_entries: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_BATCH_MAX_BYTES",
+        default_value_t = 1_048_576
+    )]
+    loki_batch_max_bytes: usize,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_LINGER_MS", default_value_t = 1000)]
+    loki_linger_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_QUEUE_CAPACITY",
+        default_value_t = 10_000
+    )]
+    loki_queue_capacity: usize,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_CONCURRENCY", default_value_t = 1)]
+    loki_concurrency: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_RETRY_MAX_ATTEMPTS",
+        default_value_t = 6
+    )]
+    loki_retry_max_attempts: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_RETRY_INITIAL_BACKOFF_MS",
+        default_value_t = 100
+    )]
+    loki_retry_initial_backoff_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_RETRY_MAX_BACKOFF_MS",
+        default_value_t = 10_000
+    )]
+    loki_retry_max_backoff_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_RETRY_MAX_ELAPSED_MS",
+        default_value_t = 60_000
+    )]
+    loki_retry_max_elapsed_ms: u64,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_BREAKER_FAILURE_THRESHOLD",
+        default_value_t = 5
+    )]
+    loki_breaker_failure_threshold: usize,
+    #[arg(
+        long,
+        env = "VERCEL_LOG_DRAIN_LOKI_BREAKER_COOLDOWN_MS",
+        default_value_t = 30_000
+    )]
+    loki_breaker_cooldown_ms: u64,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Send the messages in a dead-letter file through the enabled drivers
+    Replay {
+        file: PathBuf,
+        /// Send every message to these drivers, instead of the one that gave up on it
+        #[arg(long, value_delimiter = ',')]
+        drivers: Vec<String>,
+    },
 }
 
 #[tokio::main]
@@ -55,40 +392,153 @@ async fn main() -> anyhow::Result<()> {
         .with_max_level(args.log)
         .init();
 
-    let (tx, rx) = mpsc::unbounded_channel::<types::Message>();
+    let drivers = driver_specs(&args).await?;
+    if let Some(Command::Replay {
+        file,
+        drivers: targets,
+    }) = &args.command
+    {
+        return replay_dead_letters(file, drivers, targets).await;
+    }
 
-    let mut drivers: Vec<Box<dyn LogDriver>> = Vec::new();
+    let mut values: Vec<String> = args.vercel_secret.iter().cloned().collect();
+    values.extend(Secrets::parse_values(
+        args.vercel_secrets.as_deref().unwrap_or_default(),
+    ));
+    let secrets = Secrets::new(values, args.vercel_secret_file.clone())?;
+    let endpoints = match &args.endpoints_file {
+        Some(path) => {
+            let names: Vec<&str> = drivers.iter().map(|driver| driver.name.as_str()).collect();
+            Endpoints::load(path, &names)?
+        }
+        None => Endpoints::default(),
+    };
 
-    if args.enable_cloudwatch {
-        let config = aws_config::load_defaults(aws_config::BehaviorVersion::v2024_03_28()).await;
-        let cwl_client = aws_sdk_cloudwatchlogs::Client::new(&config);
-        drivers.push(Box::new(CloudWatchDriver::new(cwl_client)));
-        debug!("added cloudwatch driver");
-    }
+    let (wal, replay) = match &args.wal_dir {
+        Some(dir) => {
+            if args.queue_overflow == OverflowPolicy::Spill {
+                anyhow::bail!(
+                    "the spill overflow policy can't be combined with the write-ahead log"
+                );
+            }
+            let (wal, replay) = Wal::open(WalConfig {
+                dir: dir.clone(),
+                max_segment_bytes: args.wal_segment_bytes,
+                fsync: args.wal_fsync,
+            })?;
+            wal.spawn_sync_task(Duration::from_millis(args.wal_fsync_interval_ms));
+            (Some(wal), Some(replay))
+        }
+        None => (None, None),
+    };
 
-    if args.enable_loki {
-        drivers.push(Box::new(LokiDriver::new(
-            args.loki_url,
-            args.loki_basic_auth_user,
-            args.loki_basic_auth_pass,
-        )));
-        debug!("added loki driver");
-    }
+    let (tx, rx) = queue::ingest_queue(
+        args.queue_capacity,
+        args.queue_overflow,
+        args.queue_spill_dir.as_deref(),
+    )?;
 
-    let mut controller = controller::Controller::new(tx.clone(), rx, drivers);
+    let mut controller = controller::Controller::new(rx, drivers);
+    controller.routes(endpoints.routes());
+    if let (Some(wal), Some(replay)) = (wal.clone(), replay) {
+        controller.wal(wal, replay);
+    }
+    controller.shutdown_timeout(Duration::from_millis(args.shutdown_timeout_ms));
+    match (&args.dead_letter_file, &args.dead_letter_driver) {
+        (Some(_), Some(_)) => {
+            anyhow::bail!("only one of a dead-letter file or driver can be configured")
+        }
+        (Some(path), None) => controller.dead_letter(DeadLetterTarget::File {
+            path: path.clone(),
+            max_bytes: args.dead_letter_max_bytes,
+            max_files: args.dead_letter_max_files,
+        }),
+        (None, Some(driver)) => controller.dead_letter(DeadLetterTarget::Driver(driver.clone())),
+        (None, None) => {}
+    }
 
     controller.init().await?;
+    let readiness = controller.readiness();
 
-    tokio::spawn(async move {
+    let controller_task = tokio::spawn(async move {
         controller.run().await;
     });
+    let trace_drivers = trace_specs(&args)?;
+    let (traces, trace_task) = match trace_drivers.is_empty() {
+        true => (None, None),
+        false => {
+            let dead_letter = match &args.trace_dead_letter_file {
+                Some(path) => Some(DeadLetterFile::open(
+                    path,
+                    args.dead_letter_max_bytes,
+                    args.dead_letter_max_files,
+                )?),
+                None => None,
+            };
+            let (traces, task) =
+                traces::spawn(trace_drivers, args.trace_queue_capacity, dead_letter);
+            (Some(traces), Some(task))
+        }
+    };
+    tokio::spawn(secrets.clone().reload_on_hangup());
+    for endpoint in endpoints.iter() {
+        tokio::spawn(endpoint.vercel_secrets.clone().reload_on_hangup());
+    }
     let state = types::AppState {
-        vercel_verify: args.vercel_verify,
-        vercel_secret: hmac::Key::new(
-            hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
-            args.vercel_secret.as_bytes(),
-        ),
+        vercel_verify: args.vercel_verify.unwrap_or_default(),
+        vercel_secrets: secrets,
+        signatures: RouteSignatures::parse(&args.route_signatures)?,
+        endpoints,
         log_queue: tx,
+        wal,
+        readiness,
+        quarantine: args
+            .quarantine_file
+            .as_deref()
+            .map(|path| {
+                Quarantine::open(path, args.quarantine_max_bytes, args.quarantine_max_files)
+            })
+            .transpose()?
+            .map(Arc::new),
+        traces,
+        max_decompressed_bytes: args.max_decompressed_bytes,
+        max_timestamp_skew: args.max_timestamp_skew_ms.map(Duration::from_millis),
+        dedupe: args.dedupe_window_ms.map(|window_ms| {
+            Arc::new(Dedupe::new(
+                Duration::from_millis(window_ms),
+                args.dedupe_capacity,
+            ))
+        }),
+    };
+
+    // fired once by the first signal, every listener stops on it
+    let (shutdown_tx, mut shutdown) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        shutdown_for_signals().await;
+        let _ = shutdown_tx.send(());
+    });
+
+    let syslog_task = match args.syslog_port {
+        Some(port) => {
+            let tls = match (&args.syslog_tls_cert, &args.syslog_tls_key) {
+                (Some(cert), Some(key)) => Some(syslog::tls_acceptor(cert, key)?),
+                (None, None) => None,
+                _ => anyhow::bail!("the syslog TLS certificate and key have to be set together"),
+            };
+            let syslog_address = format!("{}:{}", args.ip, port);
+            let listener = tokio::net::TcpListener::bind(&syslog_address).await?;
+            info!(
+                tls = tls.is_some(),
+                "Listening for syslog on {}", syslog_address
+            );
+            Some(tokio::spawn(syslog::serve(
+                listener,
+                tls,
+                state.clone(),
+                shutdown.clone(),
+            )))
+        }
+        None => None,
     };
 
     let listen_address = format!("{}:{}", args.ip, args.port);
@@ -111,12 +561,228 @@ async fn main() -> anyhow::Result<()> {
         listener,
         app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
     )
-    .with_graceful_shutdown(shutdown_for_signals())
+    .with_graceful_shutdown(async move {
+        let _ = shutdown.changed().await;
+    })
     .await?;
+    if let Some(syslog_task) = syslog_task {
+        syslog_task.await?;
+    }
+
+    // the servers owned the last senders, so the controller drains what is
+    // left in the queue and shuts the drivers down before returning
+    info!("server stopped, draining queued messages...");
+    if let Some(trace_task) = trace_task {
+        let timeout = Duration::from_millis(args.shutdown_timeout_ms);
+        if tokio::time::timeout(timeout, trace_task).await.is_err() {
+            warn!("trace drivers didn't finish within the shutdown timeout");
+        }
+    }
+    controller_task.await?;
 
     Ok(())
 }
 
+/// Builds a spec for every enabled driver.
+async fn driver_specs(args: &Args) -> anyhow::Result<Vec<DriverSpec>> {
+    let mut drivers: Vec<DriverSpec> = Vec::new();
+
+    if args.enable_cloudwatch {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::v2024_03_28()).await;
+        let cwl_client = aws_sdk_cloudwatchlogs::Client::new(&config);
+        let driver =
+            CloudWatchDriver::new(cwl_client, Duration::from_millis(args.cloudwatch_linger_ms))
+                .with_retry(retry_policy(
+                    args.cloudwatch_retry_max_attempts,
+                    args.cloudwatch_retry_initial_backoff_ms,
+                    args.cloudwatch_retry_max_backoff_ms,
+                    args.cloudwatch_retry_max_elapsed_ms,
+                ));
+        drivers.push(DriverSpec {
+            name: String::from("cloudwatch"),
+            factory: Box::new(move || Box::new(driver.clone())),
+            queue_capacity: args.cloudwatch_queue_capacity,
+            concurrency: args.cloudwatch_concurrency,
+            breaker: BreakerConfig {
+                failure_threshold: args.cloudwatch_breaker_failure_threshold,
+                cooldown: Duration::from_millis(args.cloudwatch_breaker_cooldown_ms),
+                open_action: args.breaker_open_action,
+            },
+        });
+        debug!("added cloudwatch driver");
+    }
+
+    if args.enable_loki {
+        let driver = LokiDriver::new(
+            args.loki_url.clone(),
+            loki_auth(args)?,
+            args.loki_encoding,
+            LokiTemplate::new(&args.loki_labels, &args.loki_structured_metadata)?,
+            LokiTenants::new(&args.loki_tenant, &args.loki_tenant_map)?,
+            LokiBatchConfig {
+                max_entries: args.loki_batch_max_entries,
+                max_bytes: args.loki_batch_max_bytes,
+                linger: Duration::from_millis(args.loki_linger_ms),
+            },
+        )
+        .with_retry(retry_policy(
+            args.loki_retry_max_attempts,
+            args.loki_retry_initial_backoff_ms,
+            args.loki_retry_max_backoff_ms,
+            args.loki_retry_max_elapsed_ms,
+        ));
+        drivers.push(DriverSpec {
+            name: String::from("loki"),
+            factory: Box::new(move || Box::new(driver.clone())),
+            queue_capacity: args.loki_queue_capacity,
+            concurrency: args.loki_concurrency,
+            breaker: BreakerConfig {
+                failure_threshold: args.loki_breaker_failure_threshold,
+                cooldown: Duration::from_millis(args.loki_breaker_cooldown_ms),
+                open_action: args.breaker_open_action,
+            },
+        });
+        debug!("added loki driver");
+    }
+
+    return Ok(drivers);
+}
+
+/// Builds every enabled trace driver.
+fn trace_specs(args: &Args) -> anyhow::Result<Vec<traces::TraceSpec>> {
+    let mut drivers = Vec::new();
+    if let Some(url) = &args.trace_otlp_url {
+        let mut headers = HttpAuth::parse_headers(&args.trace_otlp_headers, false)?;
+        headers.extend(HttpAuth::parse_headers(
+            &args.trace_otlp_header_files,
+            true,
+        )?);
+        let auth = match headers.is_empty() {
+            true => HttpAuth::None,
+            false => HttpAuth::Headers(headers),
+        };
+        drivers.push(traces::TraceSpec {
+            name: String::from("otlp"),
+            driver: Box::new(
+                OtlpTraceDriver::new(url.clone(), auth).with_retry(retry_policy(
+                    args.trace_otlp_retry_max_attempts,
+                    args.trace_otlp_retry_initial_backoff_ms,
+                    args.trace_otlp_retry_max_backoff_ms,
+                    args.trace_otlp_retry_max_elapsed_ms,
+                )),
+            ),
+        });
+        debug!("added otlp trace driver");
+    }
+    if let Some(path) = &args.trace_file {
+        drivers.push(traces::TraceSpec {
+            name: String::from("file"),
+            driver: Box::new(TraceFileDriver::open(
+                path,
+                args.trace_file_max_bytes,
+                args.trace_file_max_files,
+            )?),
+        });
+        debug!("added trace file driver");
+    }
+    return Ok(drivers);
+}
+
+/// Sends the entries of a dead-letter file through freshly initialized
+/// drivers, writing whatever fails again to `<file>.failed`.
+async fn replay_dead_letters(
+    file: &Path,
+    specs: Vec<DriverSpec>,
+    targets: &[String],
+) -> anyhow::Result<()> {
+    let mut drivers = HashMap::new();
+    for spec in specs {
+        if !targets.is_empty() && !targets.contains(&spec.name) {
+            continue;
+        }
+        let mut driver = (spec.factory)();
+        driver.init().await?;
+        drivers.insert(spec.name, driver);
+    }
+    for target in targets {
+        if !drivers.contains_key(target) {
+            anyhow::bail!("driver {:?} is not enabled", target);
+        }
+    }
+
+    let mut failed_path = file.as_os_str().to_owned();
+    failed_path.push(".failed");
+    let mut failed = DeadLetterFile::open(Path::new(&failed_path), u64::MAX, 1)?;
+    let (_, failures) = deadletter::replay(file, drivers, !targets.is_empty(), &mut failed).await?;
+    if failures > 0 {
+        anyhow::bail!(
+            "{} message(s) failed again, see {}",
+            failures,
+            Path::new(&failed_path).display()
+        );
+    }
+    return Ok(());
+}
+
+fn retry_policy(
+    max_attempts: usize,
+    initial_backoff_ms: u64,
+    max_backoff_ms: u64,
+    max_elapsed_ms: u64,
+) -> RetryPolicy {
+    return RetryPolicy {
+        max_attempts: max_attempts.max(1),
+        initial_backoff: Duration::from_millis(initial_backoff_ms),
+        max_backoff: Duration::from_millis(max_backoff_ms),
+        max_elapsed: Duration::from_millis(max_elapsed_ms),
+    };
+}
+
+/// Picks the loki auth scheme from whichever credentials were given, refusing
+/// to guess when more than one scheme is configured.
+fn loki_auth(args: &Args) -> anyhow::Result<HttpAuth> {
+    let mut schemes = Vec::new();
+    if !args.loki_basic_auth_user.is_empty() {
+        let password = match &args.loki_basic_auth_pass_file {
+            Some(path) => Secret::File(path.clone()),
+            None => Secret::Value(args.loki_basic_auth_pass.clone()),
+        };
+        schemes.push(HttpAuth::Basic {
+            username: args.loki_basic_auth_user.clone(),
+            password,
+        });
+    }
+    if let Some(path) = &args.loki_bearer_token_file {
+        schemes.push(HttpAuth::Bearer(Secret::File(path.clone())));
+    } else if !args.loki_bearer_token.is_empty() {
+        schemes.push(HttpAuth::Bearer(Secret::Value(
+            args.loki_bearer_token.clone(),
+        )));
+    }
+    if schemes.len() > 1 {
+        anyhow::bail!("only one of loki basic auth or bearer token can be configured");
+    }
+    let mut headers = HttpAuth::parse_headers(&args.loki_headers, false)?;
+    headers.extend(HttpAuth::parse_headers(&args.loki_header_files, true)?);
+    let auth = match (schemes.pop(), headers.is_empty()) {
+        (None, true) => HttpAuth::None,
+        (None, false) => HttpAuth::Headers(headers),
+        (Some(auth), true) => auth,
+        (Some(auth), false) => {
+            if headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+            {
+                anyhow::bail!(
+                    "loki headers can't set Authorization along with basic auth or a bearer token"
+                );
+            }
+            HttpAuth::WithHeaders(Box::new(auth), headers)
+        }
+    };
+    return Ok(auth);
+}
+
 async fn shutdown_for_signals() {
     tokio::select! {
         _interrupt = async {
@@ -0,0 +1,102 @@
+use crate::deadletter::{now_millis, DeadLetterFile};
+use crate::types::InvalidMessage;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+/// A message that failed to parse, as written to the quarantine file.
+#[derive(Serialize)]
+struct Quarantined {
+    field: String,
+    error: String,
+    // unix milliseconds when the payload was received
+    received_at: i64,
+    // exactly as received, which may span several lines
+    raw: String,
+}
+
+/// Keeps the raw JSON of messages that failed to parse, in the same rotated
+/// newline delimited file as dead letters.
+#[derive(Debug)]
+pub struct Quarantine {
+    file: Mutex<DeadLetterFile>,
+}
+
+impl Quarantine {
+    pub fn open(path: &Path, max_bytes: u64, max_files: usize) -> Result<Self> {
+        Ok(Self {
+            file: Mutex::new(DeadLetterFile::open(path, max_bytes, max_files)?),
+        })
+    }
+
+    /// Writes the messages on the blocking pool, copied out of the request
+    /// body they borrow from.
+    pub async fn write(self: &Arc<Self>, invalid: &[InvalidMessage<'_>]) {
+        if invalid.is_empty() {
+            return;
+        }
+        let received_at = now_millis();
+        let entries: Vec<Quarantined> = invalid
+            .iter()
+            .map(|message| Quarantined {
+                field: message.field.clone(),
+                error: message.error.clone(),
+                received_at,
+                raw: message.raw.to_owned(),
+            })
+            .collect();
+        let quarantine = self.clone();
+        let written = tokio::task::spawn_blocking(move || quarantine.write_blocking(&entries));
+        if let Err(e) = written.await {
+            error!("failed writing quarantined messages: {:?}", e);
+        }
+    }
+
+    fn write_blocking(&self, entries: &[Quarantined]) {
+        let mut file = self.file.lock().unwrap();
+        for entry in entries {
+            if let Err(e) = file.write(entry) {
+                error!("failed writing quarantined message: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ParsedPayload;
+
+    #[tokio::test]
+    async fn keeps_the_raw_message() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("quarantine.ndjson");
+        let quarantine = Arc::new(Quarantine::open(&path, u64::MAX, 1)?);
+        let payload = ParsedPayload::parse::<crate::types::Message>(include_str!(
+            "fixtures/partially_invalid.json"
+        ))?;
+        quarantine.write(&payload.invalid).await;
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["field"], "host");
+        assert!(lines[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("missing field `host`"));
+        let raw: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line["raw"].as_str().unwrap()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(raw[0]["message"], "missing its host");
+        assert_eq!(raw[1]["proxy"]["userAgent"], "curl/8.4.0");
+        return Ok(());
+    }
+}
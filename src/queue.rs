@@ -0,0 +1,605 @@
+use crate::types::{Envelope, Message};
+
+use anyhow::{Context, Result};
+use axum_prometheus::metrics::{counter, gauge};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing::{error, warn};
+
+const SPILL_FILE: &str = "ingest-queue.ndjson";
+
+/// What to do with incoming messages once the ingest queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverflowPolicy {
+    /// refuse the whole batch so vercel retries it later
+    Reject,
+    /// make room by dropping the oldest queued messages
+    DropOldest,
+    /// drop the incoming messages
+    DropNewest,
+    /// write the overflow to disk and read it back once the queue has room
+    Spill,
+}
+
+/// Returned when a batch is refused under `OverflowPolicy::Reject`.
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ingest queue is full")
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+// spilled messages read back ahead of the receiver taking them
+const SPILL_READ_AHEAD: usize = 64;
+
+enum SpillCommand {
+    Push(Vec<Envelope>),
+    // the receiver took a message that was read back
+    Taken,
+}
+
+/// Overflow written as newline delimited JSON by a dedicated thread, so the
+/// file IO stays off the runtime. The thread reads messages back a few at a
+/// time ahead of the receiver, and truncates the file once all of them have
+/// been taken.
+struct Spill {
+    commands: Option<std::sync::mpsc::Sender<SpillCommand>>,
+    read_back: tokio::sync::mpsc::Receiver<Message>,
+    // spilled messages the receiver hasn't taken yet
+    pending: Arc<AtomicUsize>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Spill {
+    fn open(dir: &Path, notify: Arc<Notify>) -> Result<Self> {
+        let pending = Arc::new(AtomicUsize::new(0));
+        let file = SpillFile::open(dir, pending.clone(), notify)?;
+        let (commands, receiver) = std::sync::mpsc::channel();
+        let (read_back_sender, read_back) = tokio::sync::mpsc::channel(SPILL_READ_AHEAD);
+        let thread = std::thread::Builder::new()
+            .name("spill".to_owned())
+            .spawn(move || file.run(receiver, read_back_sender))
+            .context("starting the spill writer")?;
+        Ok(Self {
+            commands: Some(commands),
+            read_back,
+            pending,
+            thread: Some(thread),
+        })
+    }
+
+    fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Hands the batch to the spill thread, or back if the thread is gone.
+    fn push(&self, batch: Vec<Envelope>) -> Result<(), Vec<Envelope>> {
+        let count = batch.len();
+        self.pending.fetch_add(count, Ordering::SeqCst);
+        let Some(commands) = &self.commands else {
+            return Err(batch);
+        };
+        commands.send(SpillCommand::Push(batch)).map_err(|unsent| {
+            self.pending.fetch_sub(count, Ordering::SeqCst);
+            match unsent.0 {
+                SpillCommand::Push(batch) => batch,
+                SpillCommand::Taken => Vec::new(),
+            }
+        })
+    }
+
+    /// The next spilled message, if the thread has read one back already.
+    fn take(&mut self) -> Option<Message> {
+        let message = self.read_back.try_recv().ok()?;
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+        if let Some(commands) = &self.commands {
+            let _ = commands.send(SpillCommand::Taken);
+        }
+        Some(message)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        // the thread finishes the writes it was handed, then stops
+        self.commands.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("spill writer panicked");
+            }
+        }
+    }
+}
+
+/// The spill file, owned by the spill thread.
+struct SpillFile {
+    writer: File,
+    reader: BufReader<File>,
+    // lines written but not read back yet
+    unread: usize,
+    // lines read back that the receiver hasn't taken yet
+    in_flight: usize,
+    // whether the file may hold lines, and needs truncating once consumed
+    dirty: bool,
+    pending: Arc<AtomicUsize>,
+    notify: Arc<Notify>,
+}
+
+impl SpillFile {
+    fn open(dir: &Path, pending: Arc<AtomicUsize>, notify: Arc<Notify>) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("creating spill directory {}", dir.display()))?;
+        let path = dir.join(SPILL_FILE);
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening spill file {}", path.display()))?;
+        let reader = BufReader::new(File::open(&path)?);
+        // pick up whatever a previous run left behind
+        let unread = BufReader::new(File::open(&path)?).lines().count();
+        pending.store(unread, Ordering::SeqCst);
+        Ok(Self {
+            writer,
+            reader,
+            unread,
+            in_flight: 0,
+            dirty: true,
+            pending,
+            notify,
+        })
+    }
+
+    fn run(
+        mut self,
+        commands: std::sync::mpsc::Receiver<SpillCommand>,
+        read_back: tokio::sync::mpsc::Sender<Message>,
+    ) {
+        loop {
+            // reading back only goes on while no command is waiting
+            let reading = self.unread > 0 && read_back.capacity() > 0;
+            let command = match reading {
+                true => match commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(std::sync::mpsc::TryRecvError::Empty) => None,
+                    Err(std::sync::mpsc::TryRecvError::Disconnected) => break,
+                },
+                false => match commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => break,
+                },
+            };
+            match command {
+                Some(SpillCommand::Push(batch)) => self.write(batch),
+                Some(SpillCommand::Taken) => self.in_flight = self.in_flight.saturating_sub(1),
+                None => {
+                    if !self.read(&read_back) {
+                        break;
+                    }
+                }
+            }
+            if let Err(e) = self.truncate_if_consumed() {
+                error!("failed to truncate the spill file: {:?}", e);
+            }
+        }
+    }
+
+    fn write(&mut self, batch: Vec<Envelope>) {
+        for envelope in batch {
+            let written = serde_json::to_vec(&envelope.message)
+                .map_err(anyhow::Error::from)
+                .and_then(|mut line| {
+                    line.push(b'\n');
+                    Ok(self.writer.write_all(&line)?)
+                });
+            match written {
+                Ok(()) => {
+                    self.unread += 1;
+                    self.dirty = true;
+                    counter!("drain_queue_spilled_messages").increment(1);
+                }
+                Err(e) => {
+                    error!("failed to spill message to disk, dropping it: {:?}", e);
+                    counter!("drain_queue_dropped_messages").increment(1);
+                    self.lost(1);
+                }
+            }
+        }
+    }
+
+    /// Reads back the next message for the receiver, returns false once the
+    /// receiver is gone.
+    fn read(&mut self, read_back: &tokio::sync::mpsc::Sender<Message>) -> bool {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => {
+                error!(
+                    missing = self.unread,
+                    "spill file ended before all messages were read back"
+                );
+                let missing = std::mem::take(&mut self.unread);
+                self.lost(missing);
+                return true;
+            }
+            Ok(_) => self.unread -= 1,
+            Err(e) => {
+                error!("failed reading spilled messages: {:?}", e);
+                let missing = std::mem::take(&mut self.unread);
+                self.lost(missing);
+                return true;
+            }
+        }
+        match serde_json::from_str(&line) {
+            Ok(message) => {
+                if read_back.try_send(message).is_err() {
+                    return false;
+                }
+                self.in_flight += 1;
+                self.notify.notify_one();
+            }
+            Err(e) => {
+                error!("skipping unreadable spilled message: {:?}", e);
+                self.lost(1);
+            }
+        }
+        true
+    }
+
+    /// Stops counting messages that won't reach the receiver.
+    fn lost(&self, count: usize) {
+        self.pending.fetch_sub(count, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    fn truncate_if_consumed(&mut self) -> Result<()> {
+        if !self.dirty || self.unread > 0 || self.in_flight > 0 {
+            return Ok(());
+        }
+        self.writer.set_len(0)?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+struct State {
+    messages: VecDeque<Envelope>,
+    spill: Option<Spill>,
+}
+
+impl State {
+    fn len(&self) -> usize {
+        self.messages.len() + self.spill.as_ref().map_or(0, Spill::pending)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    senders: AtomicUsize,
+    notify: Arc<Notify>,
+    // woken once the last sender is gone
+    closed: Notify,
+}
+
+/// The handler side of the ingest queue.
+pub struct IngestSender {
+    shared: Arc<Shared>,
+}
+
+/// The controller side of the ingest queue, yields `None` once every sender
+/// is gone and the queue is drained.
+pub struct IngestReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Creates a queue holding up to `capacity` messages in memory, `spill_dir`
+/// is required for `OverflowPolicy::Spill`.
+pub fn ingest_queue(
+    capacity: usize,
+    policy: OverflowPolicy,
+    spill_dir: Option<&Path>,
+) -> Result<(IngestSender, IngestReceiver)> {
+    let notify = Arc::new(Notify::new());
+    let spill = match (policy, spill_dir) {
+        (OverflowPolicy::Spill, Some(dir)) => Some(Spill::open(dir, notify.clone())?),
+        (OverflowPolicy::Spill, None) => anyhow::bail!("the spill policy needs a spill directory"),
+        _ => None,
+    };
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::with_capacity(capacity),
+            spill,
+        }),
+        capacity: capacity.max(1),
+        policy,
+        senders: AtomicUsize::new(1),
+        notify,
+        closed: Notify::new(),
+    });
+    Ok((
+        IngestSender {
+            shared: shared.clone(),
+        },
+        IngestReceiver { shared },
+    ))
+}
+
+impl IngestSender {
+    /// Queues a batch of messages, applying the overflow policy when there
+    /// isn't room for all of them.
+    pub fn send_batch(&self, batch: Vec<Envelope>) -> Result<(), QueueFull> {
+        let shared = &self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let free = shared.capacity.saturating_sub(state.messages.len());
+        let spilling = state
+            .spill
+            .as_ref()
+            .is_some_and(|spill| spill.pending() > 0);
+        if batch.len() <= free && !spilling {
+            state.messages.extend(batch);
+        } else {
+            match shared.policy {
+                // vercel would retry a payload larger than the whole queue
+                // forever, so an empty queue takes it in whole
+                OverflowPolicy::Reject if state.messages.is_empty() => {
+                    state.messages.extend(batch);
+                }
+                OverflowPolicy::Reject => {
+                    counter!("drain_queue_rejected_batches").increment(1);
+                    return Err(QueueFull);
+                }
+                OverflowPolicy::DropOldest => {
+                    let overflow =
+                        (state.messages.len() + batch.len()).saturating_sub(shared.capacity);
+                    let from_queue = overflow.min(state.messages.len());
+                    state.messages.drain(..from_queue);
+                    let skip = overflow - from_queue;
+                    state.messages.extend(batch.into_iter().skip(skip));
+                    warn!(
+                        dropped = overflow,
+                        "ingest queue full, dropped oldest messages"
+                    );
+                    counter!("drain_queue_dropped_messages").increment(overflow as u64);
+                }
+                OverflowPolicy::DropNewest => {
+                    let dropped = batch.len() - free;
+                    state.messages.extend(batch.into_iter().take(free));
+                    warn!(dropped, "ingest queue full, dropped newest messages");
+                    counter!("drain_queue_dropped_messages").increment(dropped as u64);
+                }
+                OverflowPolicy::Spill => {
+                    let mut batch = batch.into_iter();
+                    if !spilling {
+                        state.messages.extend(batch.by_ref().take(free));
+                    }
+                    let spill = state.spill.as_ref().expect("spill policy has a spill file");
+                    if let Err(unsent) = spill.push(batch.collect()) {
+                        error!("spill writer stopped, dropping messages");
+                        counter!("drain_queue_dropped_messages").increment(unsent.len() as u64);
+                    }
+                }
+            }
+        }
+        gauge!("drain_queue_depth").set(state.len() as f64);
+        drop(state);
+        shared.notify.notify_one();
+        Ok(())
+    }
+
+    /// Messages waiting in memory, spilled messages don't count against the
+    /// capacity.
+    pub fn depth(&self) -> usize {
+        self.shared.state.lock().unwrap().messages.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+}
+
+impl Clone for IngestSender {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for IngestSender {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.notify.notify_one();
+            self.shared.closed.notify_waiters();
+        }
+    }
+}
+
+impl std::fmt::Debug for IngestSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IngestSender")
+            .field("capacity", &self.shared.capacity)
+            .field("policy", &self.shared.policy)
+            .finish()
+    }
+}
+
+impl IngestReceiver {
+    pub async fn recv(&mut self) -> Option<Envelope> {
+        loop {
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            if self.is_closed() && self.is_empty() {
+                return None;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<Envelope> {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut message = state.messages.pop_front();
+        if message.is_none() {
+            message = state
+                .spill
+                .as_mut()
+                .and_then(Spill::take)
+                .map(Envelope::from);
+        }
+        gauge!("drain_queue_depth").set(state.len() as f64);
+        message
+    }
+
+    /// Whether every sender is gone, so nothing new will arrive.
+    pub fn is_closed(&self) -> bool {
+        self.shared.senders.load(Ordering::SeqCst) == 0
+    }
+
+    /// Waits until every sender is gone.
+    pub async fn closed(&self) {
+        let notified = self.shared.closed.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !self.is_closed() {
+            notified.await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: usize) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "timestamp": 1,
+            "source": "lambda",
+            "projectName": "project",
+            "projectId": "prj_1",
+            "deploymentId": "dpl_1",
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    fn batch(ids: std::ops::Range<usize>) -> Vec<Envelope> {
+        ids.map(|id| message(id).into()).collect()
+    }
+
+    async fn drain(mut receiver: IngestReceiver) -> Vec<String> {
+        let mut ids = Vec::new();
+        while let Some(message) = receiver.recv().await {
+            ids.push(message.message.id);
+        }
+        ids
+    }
+
+    fn ids(ids: std::ops::Range<usize>) -> Vec<String> {
+        ids.map(|id| id.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_whole_batch() -> Result<()> {
+        let (sender, receiver) = ingest_queue(3, OverflowPolicy::Reject, None)?;
+        sender.send_batch(batch(0..2))?;
+        assert!(sender.send_batch(batch(2..4)).is_err());
+        sender.send_batch(batch(2..3))?;
+        drop(sender);
+        assert_eq!(drain(receiver).await, ids(0..3));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn reject_takes_an_oversized_batch_into_an_empty_queue() -> Result<()> {
+        let (sender, receiver) = ingest_queue(2, OverflowPolicy::Reject, None)?;
+        sender.send_batch(batch(0..3))?;
+        assert!(sender.send_batch(batch(3..4)).is_err());
+        drop(sender);
+        assert_eq!(drain(receiver).await, ids(0..3));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_newest() -> Result<()> {
+        let (sender, receiver) = ingest_queue(3, OverflowPolicy::DropOldest, None)?;
+        sender.send_batch(batch(0..2))?;
+        sender.send_batch(batch(2..4))?;
+        sender.send_batch(batch(4..9))?;
+        drop(sender);
+        assert_eq!(drain(receiver).await, ids(6..9));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_oldest() -> Result<()> {
+        let (sender, receiver) = ingest_queue(3, OverflowPolicy::DropNewest, None)?;
+        sender.send_batch(batch(0..2))?;
+        sender.send_batch(batch(2..5))?;
+        drop(sender);
+        assert_eq!(drain(receiver).await, ids(0..3));
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn spill_keeps_everything_in_order() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (sender, mut receiver) = ingest_queue(2, OverflowPolicy::Spill, Some(dir.path()))?;
+        sender.send_batch(batch(0..5))?;
+        assert_eq!(receiver.len(), 5);
+        assert_eq!(receiver.recv().await.unwrap().message.id, "0");
+        sender.send_batch(batch(5..7))?;
+        drop(sender);
+        assert_eq!(drain(receiver).await, ids(1..7));
+        assert_eq!(std::fs::metadata(dir.path().join(SPILL_FILE))?.len(), 0);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn spill_is_replayed_after_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (sender, receiver) = ingest_queue(1, OverflowPolicy::Spill, Some(dir.path()))?;
+        sender.send_batch(batch(0..3))?;
+        drop((sender, receiver));
+
+        let (sender, receiver) = ingest_queue(1, OverflowPolicy::Spill, Some(dir.path()))?;
+        drop(sender);
+        assert_eq!(drain(receiver).await, ids(1..3));
+        return Ok(());
+    }
+
+    #[test]
+    fn spill_requires_directory() {
+        assert!(ingest_queue(1, OverflowPolicy::Spill, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn recv_waits_for_messages() -> Result<()> {
+        let (sender, mut receiver) = ingest_queue(2, OverflowPolicy::Reject, None)?;
+        let waiting = tokio::spawn(async move { receiver.recv().await.map(|m| m.message.id) });
+        tokio::task::yield_now().await;
+        sender.send_batch(batch(0..1))?;
+        assert_eq!(waiting.await?, Some("0".to_owned()));
+        return Ok(());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::breaker::{BreakerState, CircuitBreaker};
+use crate::deadletter::now_millis;
+use crate::queue::IngestSender;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// How one driver is doing, updated by its workers and reported by `/ready`.
+#[derive(Debug)]
+pub struct DriverStatus {
+    pub name: String,
+    pub breaker: Arc<CircuitBreaker>,
+    workers: usize,
+    initialized: AtomicUsize,
+    // unix milliseconds, 0 until the first success
+    last_success: AtomicI64,
+    last_error: Mutex<Option<DriverError>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DriverError {
+    // unix milliseconds
+    pub at: i64,
+    pub error: String,
+}
+
+impl DriverStatus {
+    pub fn new(name: &str, workers: usize, breaker: Arc<CircuitBreaker>) -> Self {
+        Self {
+            name: name.to_owned(),
+            breaker,
+            workers,
+            initialized: AtomicUsize::new(0),
+            last_success: AtomicI64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    /// Called once for every worker whose driver instance finished `init`.
+    pub fn initialized(&self) {
+        self.initialized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of a request that went out to the destination,
+    /// feeding the breaker along the way.
+    pub fn record(&self, result: &Result<()>) {
+        match result {
+            Ok(_) => self.last_success.store(now_millis(), Ordering::Relaxed),
+            Err(e) => {
+                *self.last_error.lock().unwrap() = Some(DriverError {
+                    at: now_millis(),
+                    error: format!("{:#}", e),
+                })
+            }
+        }
+        self.breaker.record(result.is_ok());
+    }
+
+    fn report(&self) -> DriverReport {
+        let last_success = self.last_success.load(Ordering::Relaxed);
+        DriverReport {
+            initialized: self.initialized.load(Ordering::Relaxed) >= self.workers,
+            breaker: self.breaker.state(),
+            last_success: (last_success > 0).then_some(last_success),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DriverReport {
+    initialized: bool,
+    breaker: BreakerState,
+    last_success: Option<i64>,
+    last_error: Option<DriverError>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueReport {
+    depth: usize,
+    capacity: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ControllerReport {
+    running: bool,
+}
+
+/// The body of `/ready`, `reasons` says why the pod isn't ready.
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    reasons: Vec<String>,
+    controller: ControllerReport,
+    queue: QueueReport,
+    drivers: BTreeMap<String, DriverReport>,
+}
+
+/// Everything `/ready` needs to decide whether the pod can make progress.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    drivers: Vec<Arc<DriverStatus>>,
+    // dropped along with the controller, whether it returned or panicked
+    controller: Weak<()>,
+}
+
+impl Readiness {
+    pub fn new(drivers: Vec<Arc<DriverStatus>>, controller: Weak<()>) -> Self {
+        Self {
+            drivers,
+            controller,
+        }
+    }
+
+    /// The pod is not ready while the controller isn't running, a driver
+    /// failed to initialize, every driver's breaker is open or the ingest
+    /// queue is full.
+    pub fn report(&self, queue: &IngestSender) -> ReadinessReport {
+        let mut reasons = Vec::new();
+        let running = self.controller.strong_count() > 0;
+        if !running {
+            reasons.push("controller is not running".to_owned());
+        }
+        let drivers: BTreeMap<String, DriverReport> = self
+            .drivers
+            .iter()
+            .map(|driver| (driver.name.clone(), driver.report()))
+            .collect();
+        for (name, driver) in &drivers {
+            if !driver.initialized {
+                reasons.push(format!("driver {} is not initialized", name));
+            }
+        }
+        if !drivers.is_empty()
+            && drivers
+                .values()
+                .all(|driver| driver.breaker == BreakerState::Open)
+        {
+            reasons.push("every driver's circuit breaker is open".to_owned());
+        }
+        let (depth, capacity) = (queue.depth(), queue.capacity());
+        if depth >= capacity {
+            reasons.push("ingest queue is full".to_owned());
+        }
+        ReadinessReport {
+            ready: reasons.is_empty(),
+            reasons,
+            controller: ControllerReport { running },
+            queue: QueueReport { depth, capacity },
+            drivers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::BreakerConfig;
+    use crate::queue::{ingest_queue, OverflowPolicy};
+
+    fn status(name: &str) -> Arc<DriverStatus> {
+        let breaker = CircuitBreaker::new(
+            name,
+            BreakerConfig {
+                failure_threshold: 1,
+                ..BreakerConfig::default()
+            },
+        );
+        let status = DriverStatus::new(name, 1, Arc::new(breaker));
+        status.initialized();
+        return Arc::new(status);
+    }
+
+    #[test]
+    fn ready_while_a_driver_can_deliver() -> Result<()> {
+        let (tx, _rx) = ingest_queue(10, OverflowPolicy::Reject, None)?;
+        let controller = Arc::new(());
+        let (loki, cloudwatch) = (status("loki"), status("cloudwatch"));
+        let readiness = Readiness::new(
+            vec![loki.clone(), cloudwatch.clone()],
+            Arc::downgrade(&controller),
+        );
+        assert!(readiness.report(&tx).ready);
+
+        loki.record(&Err(anyhow::anyhow!("unavailable")));
+        let report = readiness.report(&tx);
+        assert!(report.ready);
+        assert_eq!(
+            report.drivers["loki"].last_error.as_ref().unwrap().error,
+            "unavailable"
+        );
+
+        cloudwatch.record(&Err(anyhow::anyhow!("throttled")));
+        let report = readiness.report(&tx);
+        assert!(!report.ready);
+        assert_eq!(
+            report.reasons,
+            vec!["every driver's circuit breaker is open"]
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn not_ready_once_the_controller_stops() -> Result<()> {
+        let (tx, _rx) = ingest_queue(10, OverflowPolicy::Reject, None)?;
+        let controller = Arc::new(());
+        let readiness = Readiness::new(vec![status("loki")], Arc::downgrade(&controller));
+        drop(controller);
+        let report = readiness.report(&tx);
+        assert!(!report.ready);
+        assert_eq!(report.reasons, vec!["controller is not running"]);
+        return Ok(());
+    }
+
+    #[test]
+    fn not_ready_while_the_queue_is_full() -> Result<()> {
+        let (tx, _rx) = ingest_queue(2, OverflowPolicy::Reject, None)?;
+        let controller = Arc::new(());
+        let readiness = Readiness::new(Vec::new(), Arc::downgrade(&controller));
+        assert!(readiness.report(&tx).ready);
+        let messages = (0..2)
+            .map(|id| {
+                serde_json::from_value::<crate::types::Message>(serde_json::json!({
+                    "id": id.to_string(),
+                    "timestamp": 1,
+                    "source": "lambda",
+                    "projectName": "project",
+                    "projectId": "prj_1",
+                    "deploymentId": "dpl_1",
+                    "host": "example.vercel.app",
+                }))
+                .unwrap()
+                .into()
+            })
+            .collect();
+        tx.send_batch(messages)?;
+        let report = readiness.report(&tx);
+        assert!(!report.ready);
+        assert_eq!(report.reasons, vec!["ingest queue is full"]);
+        return Ok(());
+    }
+}
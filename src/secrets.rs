@@ -0,0 +1,185 @@
+use anyhow::{Context, Result};
+use axum_prometheus::metrics::{counter, gauge};
+use ring::{digest, hmac};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio::signal::{unix, unix::SignalKind};
+use tracing::{error, info};
+
+/// The drain secrets payloads may be signed with, so the secret can be
+/// rotated in vercel without rejecting payloads in between. Keys are tried in
+/// order, the ones given directly first and then the lines of the secret file.
+#[derive(Debug, Clone)]
+pub struct Secrets {
+    values: Vec<String>,
+    file: Option<PathBuf>,
+    keys: Arc<RwLock<Arc<Vec<Key>>>>,
+}
+
+#[derive(Debug)]
+struct Key {
+    // raw, since routes may sign with different algorithms
+    secret: Vec<u8>,
+    fingerprint: String,
+}
+
+/// Names a secret in metrics without giving it away: the first 8 hex
+/// characters of its SHA-256.
+fn fingerprint(secret: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, secret);
+    return hex::encode(&digest.as_ref()[..4]);
+}
+
+impl Secrets {
+    pub fn new(values: Vec<String>, file: Option<PathBuf>) -> Result<Self> {
+        let secrets = Self {
+            values,
+            file,
+            keys: Default::default(),
+        };
+        secrets.reload()?;
+        return Ok(secrets);
+    }
+
+    /// Comma separated secrets, as given in `VERCEL_SECRETS`. Secrets holding
+    /// a comma go in `VERCEL_SECRET` or the secret file instead.
+    pub fn parse_values(values: &str) -> Vec<String> {
+        values
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Re-reads the secret file, keeping the current keys when it can't be
+    /// read or leaves no secret at all. Returns the number of keys.
+    pub fn reload(&self) -> Result<usize> {
+        let mut values = self.values.clone();
+        if let Some(path) = &self.file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("failed reading secret file {}", path.display()))?;
+            values.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .map(str::to_owned),
+            );
+        }
+        if values.is_empty() {
+            anyhow::bail!("no vercel secret is configured");
+        }
+        let keys: Vec<Key> = values
+            .into_iter()
+            .map(|value| Key {
+                fingerprint: fingerprint(value.as_bytes()),
+                secret: value.into_bytes(),
+            })
+            .collect();
+        let count = keys.len();
+        *self.keys.write().unwrap() = Arc::new(keys);
+        gauge!("drain_secrets").set(count as f64);
+        return Ok(count);
+    }
+
+    /// The fingerprint of the first key `signature` verifies against for
+    /// `body`, using the HMAC `algorithm`.
+    pub fn verify(
+        &self,
+        algorithm: hmac::Algorithm,
+        body: &[u8],
+        signature: &[u8],
+    ) -> Option<String> {
+        let keys = self.keys.read().unwrap().clone();
+        keys.iter()
+            .find(|key| {
+                let hmac_key = hmac::Key::new(algorithm, &key.secret);
+                hmac::verify(&hmac_key, body, signature).is_ok()
+            })
+            .map(|key| key.fingerprint.clone())
+    }
+
+    /// Reloads the secrets on every `SIGHUP`, for as long as the drain runs.
+    pub async fn reload_on_hangup(self) {
+        let mut hangup = unix::signal(SignalKind::hangup()).expect("able to listen for signals");
+        while hangup.recv().await.is_some() {
+            match self.reload() {
+                Ok(keys) => {
+                    info!(keys, "reloaded vercel secrets");
+                    counter!("drain_secret_reloads").increment(1);
+                }
+                Err(e) => {
+                    error!(
+                        "failed reloading vercel secrets, keeping the current ones: {:#}",
+                        e
+                    );
+                    counter!("drain_failed_secret_reloads").increment(1);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: hmac::Algorithm = hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY;
+
+    fn sign(secret: &str, body: &[u8]) -> Vec<u8> {
+        let key = hmac::Key::new(SHA1, secret.as_bytes());
+        return hmac::sign(&key, body).as_ref().to_vec();
+    }
+
+    #[test]
+    fn verifies_against_every_secret() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), "third\n\n  fourth \n")?;
+        let mut values = vec![String::from("a,b")];
+        values.extend(Secrets::parse_values("first, second,"));
+        let secrets = Secrets::new(values, Some(file.path().to_owned()))?;
+        let body: &[u8] = b"[]";
+        for secret in ["a,b", "first", "second", "third", "fourth"] {
+            assert_eq!(
+                secrets.verify(SHA1, body, &sign(secret, body)),
+                Some(fingerprint(secret.as_bytes()))
+            );
+        }
+        assert_eq!(fingerprint(b"first"), "a7937b64");
+        assert_eq!(secrets.verify(SHA1, body, &sign("fifth", body)), None);
+        return Ok(());
+    }
+
+    #[test]
+    fn reloads_the_secret_file() -> Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), "old\n")?;
+        let secrets = Secrets::new(vec![], Some(file.path().to_owned()))?;
+        let body: &[u8] = b"[]";
+
+        std::fs::write(file.path(), "old\nnew\n")?;
+        assert_eq!(secrets.reload()?, 2);
+        assert_eq!(
+            secrets.verify(SHA1, body, &sign("new", body)),
+            Some(fingerprint(b"new"))
+        );
+
+        std::fs::write(file.path(), "new\n")?;
+        assert_eq!(secrets.reload()?, 1);
+        assert_eq!(secrets.verify(SHA1, body, &sign("old", body)), None);
+
+        // an emptied or missing file keeps the keys that were loaded
+        std::fs::write(file.path(), "\n")?;
+        assert!(secrets.reload().is_err());
+        std::fs::remove_file(file.path())?;
+        assert!(secrets.reload().is_err());
+        assert_eq!(
+            secrets.verify(SHA1, body, &sign("new", body)),
+            Some(fingerprint(b"new"))
+        );
+
+        assert!(Secrets::new(vec![], None).is_err());
+        return Ok(());
+    }
+}
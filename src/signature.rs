@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use axum::http::{HeaderMap, HeaderName};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ring::hmac;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The HMAC a sender signs its payloads with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    pub fn hmac(&self) -> hmac::Algorithm {
+        match self {
+            Algorithm::Sha1 => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            Algorithm::Sha256 => hmac::HMAC_SHA256,
+            Algorithm::Sha512 => hmac::HMAC_SHA512,
+        }
+    }
+}
+
+/// How the signature is written into its header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+/// Which bytes of a compressed request the signature covers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignedBody {
+    // the body as sent, checked before it is decompressed
+    Raw,
+    // the body once its `Content-Encoding` is undone
+    Decoded,
+}
+
+/// Where and how a sender puts the signature of a payload. Defaults to
+/// vercel's, a hex HMAC-SHA1 of the body as sent in `x-vercel-signature`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignatureScheme {
+    pub algorithm: Algorithm,
+    pub header: HeaderName,
+    pub encoding: Encoding,
+    // stripped from the header when present, e.g. `sha256=`
+    pub prefix: String,
+    pub body: SignedBody,
+}
+
+impl Default for SignatureScheme {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Sha1,
+            header: HeaderName::from_static("x-vercel-signature"),
+            encoding: Encoding::Hex,
+            prefix: String::new(),
+            body: SignedBody::Raw,
+        }
+    }
+}
+
+/// Why a request's signature couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+    Missing,
+    Malformed(String),
+}
+
+impl SignatureScheme {
+    /// The signature bytes sent in `headers`.
+    pub fn signature(&self, headers: &HeaderMap) -> Result<Vec<u8>, SignatureError> {
+        let value = headers
+            .get(&self.header)
+            .ok_or(SignatureError::Missing)?
+            .to_str()
+            .map_err(|e| SignatureError::Malformed(e.to_string()))?
+            .trim();
+        let value = value.strip_prefix(self.prefix.as_str()).unwrap_or(value);
+        let decoded = match self.encoding {
+            Encoding::Hex => hex::decode(value).map_err(|e| e.to_string()),
+            Encoding::Base64 => STANDARD.decode(value).map_err(|e| e.to_string()),
+        };
+        return decoded.map_err(SignatureError::Malformed);
+    }
+}
+
+impl FromStr for SignatureScheme {
+    type Err = anyhow::Error;
+
+    /// `algorithm:header:encoding[:prefix[:body]]`, e.g.
+    /// `sha256:x-hub-signature-256:hex:sha256=` or `sha1:x-signature:hex::decoded`.
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut parts = spec.trim().splitn(5, ':');
+        let algorithm = match parts.next().unwrap_or_default() {
+            "sha1" => Algorithm::Sha1,
+            "sha256" => Algorithm::Sha256,
+            "sha512" => Algorithm::Sha512,
+            other => anyhow::bail!("unknown signature algorithm {:?}", other),
+        };
+        let header = parts.next().unwrap_or_default();
+        let header = HeaderName::from_str(header)
+            .with_context(|| format!("invalid signature header {:?}", header))?;
+        let encoding = match parts.next().unwrap_or_default() {
+            "hex" => Encoding::Hex,
+            "base64" => Encoding::Base64,
+            other => anyhow::bail!("unknown signature encoding {:?}", other),
+        };
+        let prefix = parts.next().unwrap_or_default().to_owned();
+        let body = match parts.next().unwrap_or_default() {
+            "" | "raw" => SignedBody::Raw,
+            "decoded" => SignedBody::Decoded,
+            other => anyhow::bail!("unknown signed body {:?}", other),
+        };
+        return Ok(Self {
+            algorithm,
+            header,
+            encoding,
+            prefix,
+            body,
+        });
+    }
+}
+
+/// The signature scheme of every ingest route, vercel's unless overridden.
+#[derive(Debug, Clone, Default)]
+pub struct RouteSignatures {
+    routes: HashMap<String, SignatureScheme>,
+    default: SignatureScheme,
+}
+
+impl RouteSignatures {
+    /// Comma separated `route=scheme` pairs, see `SignatureScheme::from_str`.
+    pub fn parse(routes: &str) -> Result<Self> {
+        let routes = routes
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let Some((route, scheme)) = pair.split_once('=') else {
+                    anyhow::bail!("invalid route signature {:?}, expected route=scheme", pair);
+                };
+                let route = route.trim();
+                if !route.starts_with('/') {
+                    anyhow::bail!("invalid route {:?}, expected a path", route);
+                }
+                Ok((route.to_owned(), scheme.parse()?))
+            })
+            .collect::<Result<_>>()?;
+        return Ok(Self {
+            routes,
+            default: Default::default(),
+        });
+    }
+
+    pub fn for_route(&self, route: &str) -> &SignatureScheme {
+        self.routes.get(route).unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_schemes() -> Result<()> {
+        let scheme: SignatureScheme = "sha256:x-hub-signature-256:hex:sha256=".parse()?;
+        assert_eq!(scheme.algorithm, Algorithm::Sha256);
+        assert_eq!(scheme.header, "x-hub-signature-256");
+        assert_eq!(scheme.encoding, Encoding::Hex);
+        assert_eq!(scheme.prefix, "sha256=");
+        assert_eq!(scheme.body, SignedBody::Raw);
+        let scheme: SignatureScheme = "sha512:x-signature:base64".parse()?;
+        assert_eq!(scheme.prefix, "");
+        let scheme: SignatureScheme = "sha1:x-signature:hex::decoded".parse()?;
+        assert_eq!(scheme.prefix, "");
+        assert_eq!(scheme.body, SignedBody::Decoded);
+        assert!("sha1:x-signature:hex::compressed"
+            .parse::<SignatureScheme>()
+            .is_err());
+
+        assert!("md5:x-signature:hex".parse::<SignatureScheme>().is_err());
+        assert!("sha1:bad header:hex".parse::<SignatureScheme>().is_err());
+        assert!("sha1:x-signature:base32"
+            .parse::<SignatureScheme>()
+            .is_err());
+        assert!("sha1".parse::<SignatureScheme>().is_err());
+
+        let routes = RouteSignatures::parse(
+            "/vercel/web-analytics=sha256:x-signature:base64, /vercel=sha1:x-sig:hex:v1=",
+        )?;
+        assert_eq!(
+            routes.for_route("/vercel/web-analytics").encoding,
+            Encoding::Base64
+        );
+        assert_eq!(routes.for_route("/vercel").prefix, "v1=");
+        assert_eq!(
+            routes.for_route("/otlp/v1/traces"),
+            &SignatureScheme::default()
+        );
+        assert!(RouteSignatures::parse("vercel=sha1:x-sig:hex").is_err());
+        assert!(RouteSignatures::parse("/vercel").is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn reads_signatures_from_headers() -> Result<()> {
+        let scheme: SignatureScheme = "sha256:x-signature:base64:sha256=".parse()?;
+        let mut headers = HeaderMap::new();
+        assert_eq!(scheme.signature(&headers), Err(SignatureError::Missing));
+        headers.insert("x-signature", "sha256=3q2+7w==".parse()?);
+        assert_eq!(scheme.signature(&headers), Ok(vec![0xde, 0xad, 0xbe, 0xef]));
+        // the prefix is optional
+        headers.insert("x-signature", "3q2+7w==".parse()?);
+        assert_eq!(scheme.signature(&headers), Ok(vec![0xde, 0xad, 0xbe, 0xef]));
+        headers.insert("x-signature", "deadbeef!".parse()?);
+        assert!(matches!(
+            scheme.signature(&headers),
+            Err(SignatureError::Malformed(_))
+        ));
+        return Ok(());
+    }
+}
@@ -0,0 +1,537 @@
+use crate::handlers::{forward, Refused};
+use crate::types::{AppState, ParsedPayload};
+
+use anyhow::{Context, Result};
+use axum_prometheus::metrics::{counter, gauge};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+// longest frame accepted, RFC 5425 asks receivers to handle at least 8KiB
+const MAX_FRAME: usize = 1024 * 1024;
+// how long to wait for room in the ingest queue before trying again
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// One RFC 5424 message, borrowing from the frame it was read from. Header
+/// fields that were the nil value `-` are `None`.
+#[derive(Debug, PartialEq)]
+pub struct SyslogMessage<'a> {
+    pub severity: u8,
+    pub timestamp: Option<&'a str>,
+    pub hostname: Option<&'a str>,
+    pub app_name: Option<&'a str>,
+    pub proc_id: Option<&'a str>,
+    pub msg_id: Option<&'a str>,
+    // (SD-ID, [(PARAM-NAME, PARAM-VALUE)])
+    pub structured_data: Vec<(&'a str, Vec<(&'a str, String)>)>,
+    pub msg: &'a str,
+}
+
+/// Loads a PEM certificate chain and private key for the TLS listener.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(
+        std::fs::File::open(cert)
+            .with_context(|| format!("opening syslog certificate {}", cert.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("reading syslog certificate")?;
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(
+        std::fs::File::open(key)
+            .with_context(|| format!("opening syslog private key {}", key.display()))?,
+    ))
+    .context("reading syslog private key")?
+    .context("no private key found")?;
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    return Ok(TlsAcceptor::from(Arc::new(config)));
+}
+
+/// Accepts syslog connections until `shutdown` fires, then closes them and
+/// returns once every connection has let go of the ingest queue.
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    state: AppState,
+    mut shutdown: watch::Receiver<()>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("failed accepting syslog connection: {:?}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        debug!(%peer, "syslog connection opened");
+        let (state, tls, shutdown) = (state.clone(), tls.clone(), shutdown.clone());
+        connections.spawn(async move {
+            gauge!("drain_syslog_connections").increment(1);
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => receive(stream, &state, shutdown).await,
+                    Err(e) => Err(e.into()),
+                },
+                None => receive(stream, &state, shutdown).await,
+            };
+            if let Err(e) = result {
+                warn!(%peer, "syslog connection failed: {:#}", e);
+            }
+            gauge!("drain_syslog_connections").decrement(1);
+            debug!(%peer, "syslog connection closed");
+        });
+        // reap finished connections as we go
+        while connections.try_join_next().is_some() {}
+    }
+    drop(state);
+    info!("syslog listener stopped");
+    while connections.join_next().await.is_some() {}
+}
+
+/// Reads frames off one connection and forwards them, holding off while the
+/// ingest queue is full as syslog senders can't be told to retry.
+async fn receive<S: AsyncRead + Unpin>(
+    stream: S,
+    state: &AppState,
+    mut shutdown: watch::Receiver<()>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame(&mut reader) => frame?,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let Some(frame) = frame else {
+            return Ok(());
+        };
+        counter!("drain_syslog_frames").increment(1);
+        loop {
+            match forward(state, payload(&frame)).await {
+                Ok(_) => break,
+                Err(Refused::QueueFull) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(QUEUE_FULL_BACKOFF) => {}
+                        _ = shutdown.changed() => return Ok(()),
+                    }
+                }
+                Err(refused) => {
+                    error!(?refused, "dropping syslog message");
+                    counter!("drain_syslog_dropped_frames").increment(1);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Reads the next frame, octet-counted (`LEN SP MSG`) when it starts with a
+/// digit and newline delimited otherwise. `None` once the peer hangs up.
+async fn read_frame<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<String>> {
+    loop {
+        let first = match reader.fill_buf().await {
+            Ok(buf) => buf.first().copied(),
+            // TLS peers often hang up without a close_notify between frames
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e.into()),
+        };
+        let Some(first) = first else {
+            return Ok(None);
+        };
+        let mut frame = Vec::new();
+        if first.is_ascii_digit() {
+            let mut len = Vec::new();
+            (&mut *reader).take(16).read_until(b' ', &mut len).await?;
+            let len: usize = std::str::from_utf8(&len)?
+                .trim_end()
+                .parse()
+                .context("invalid syslog frame length")?;
+            anyhow::ensure!(
+                len <= MAX_FRAME,
+                "syslog frame of {} bytes is too long",
+                len
+            );
+            frame.resize(len, 0);
+            reader.read_exact(&mut frame).await?;
+        } else {
+            let read = (&mut *reader)
+                .take(MAX_FRAME as u64 + 1)
+                .read_until(b'\n', &mut frame)
+                .await?;
+            anyhow::ensure!(read <= MAX_FRAME, "syslog frame is too long");
+            while frame
+                .last()
+                .is_some_and(|byte| *byte == b'\n' || *byte == b'\r')
+            {
+                frame.pop();
+            }
+        }
+        if frame.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        return Ok(Some(String::from_utf8_lossy(&frame).into_owned()));
+    }
+}
+
+/// Turns a frame into a payload of one message, or one invalid message when
+/// it isn't RFC 5424.
+fn payload(frame: &str) -> ParsedPayload<'_> {
+    let mut payload = ParsedPayload::default();
+    match parse(frame) {
+        Ok(syslog) => {
+            payload.push_value(frame, to_value(&syslog, frame));
+            let has_id = syslog
+                .structured_data
+                .iter()
+                .any(|(_, params)| params.iter().any(|(name, _)| *name == "id"));
+            if let Some(message) = payload.messages.last_mut() {
+                message.id_derived = !has_id;
+            }
+        }
+        Err(e) => payload.push_invalid(frame, "syslog", format!("{:#}", e)),
+    }
+    return payload;
+}
+
+/// Parses an RFC 5424 message:
+/// `<PRI>VERSION SP TIMESTAMP SP HOSTNAME SP APP-NAME SP PROCID SP MSGID SP STRUCTURED-DATA [SP MSG]`
+pub fn parse(frame: &str) -> Result<SyslogMessage<'_>> {
+    let rest = frame.strip_prefix('<').context("missing priority")?;
+    let (pri, rest) = rest.split_once('>').context("unterminated priority")?;
+    let pri: u8 = pri.parse().context("invalid priority")?;
+    anyhow::ensure!(pri <= 191, "invalid priority {}", pri);
+    let (version, rest) = rest.split_once(' ').context("missing version")?;
+    anyhow::ensure!(version == "1", "unsupported syslog version {:?}", version);
+
+    let mut fields = rest.splitn(6, ' ');
+    let mut field = |name: &str| -> Result<Option<&str>> {
+        let value = fields.next().with_context(|| format!("missing {}", name))?;
+        return Ok((value != "-").then_some(value));
+    };
+    let timestamp = field("timestamp")?;
+    let hostname = field("hostname")?;
+    let app_name = field("app name")?;
+    let proc_id = field("process id")?;
+    let msg_id = field("message id")?;
+    let rest = fields.next().context("missing structured data")?;
+
+    let (structured_data, rest) = parse_structured_data(rest)?;
+    let msg = match rest.strip_prefix(' ') {
+        Some(msg) => msg.strip_prefix('\u{feff}').unwrap_or(msg),
+        None if rest.is_empty() => "",
+        None => anyhow::bail!("missing space after structured data"),
+    };
+    Ok(SyslogMessage {
+        severity: pri % 8,
+        timestamp,
+        hostname,
+        app_name,
+        proc_id,
+        msg_id,
+        structured_data,
+        msg,
+    })
+}
+
+type StructuredData<'a> = Vec<(&'a str, Vec<(&'a str, String)>)>;
+
+/// Parses `-` or a run of `[SD-ID *(SP PARAM-NAME="PARAM-VALUE")]` elements,
+/// returning whatever follows them.
+fn parse_structured_data(input: &str) -> Result<(StructuredData<'_>, &str)> {
+    if let Some(rest) = input.strip_prefix('-') {
+        return Ok((Vec::new(), rest));
+    }
+    let mut elements = Vec::new();
+    let mut rest = input;
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_end = element
+            .find([' ', ']'])
+            .context("unterminated structured data")?;
+        let (id, mut element) = element.split_at(id_end);
+        let mut params = Vec::new();
+        while let Some(param) = element.strip_prefix(' ') {
+            let (name, value) = param
+                .split_once("=\"")
+                .context("invalid structured data parameter")?;
+            let mut unescaped = String::new();
+            let mut chars = value.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c @ ('"' | '\\' | ']'))) => unescaped.push(c),
+                        Some((_, c)) => {
+                            unescaped.push('\\');
+                            unescaped.push(c);
+                        }
+                        None => anyhow::bail!("unterminated structured data parameter"),
+                    },
+                    Some((i, '"')) => break i,
+                    Some((_, c)) => unescaped.push(c),
+                    None => anyhow::bail!("unterminated structured data parameter"),
+                }
+            };
+            params.push((name, unescaped));
+            element = &value[end + 1..];
+        }
+        rest = element
+            .strip_prefix(']')
+            .context("unterminated structured data")?;
+        elements.push((id, params));
+    }
+    anyhow::ensure!(!elements.is_empty(), "missing structured data");
+    return Ok((elements, rest));
+}
+
+/// Maps a syslog message onto vercel's JSON fields. Structured data
+/// parameters named like a field (`projectId`, `deploymentId`, `requestId`,
+/// ...) are taken as they are, the header fills in what is left: HOSTNAME is
+/// the `host`, APP-NAME the `projectName`, PROCID the `deploymentId` and MSGID
+/// the `source`. Without an `id` one is derived from the frame.
+fn to_value(syslog: &SyslogMessage, frame: &str) -> serde_json::Value {
+    let mut fields = serde_json::Map::new();
+    for (_, params) in &syslog.structured_data {
+        for (name, value) in params {
+            // not strings in vercel's format, they come from the header and MSG
+            if matches!(*name, "timestamp" | "message" | "proxy") {
+                continue;
+            }
+            fields.insert(name.to_string(), value.clone().into());
+        }
+    }
+    let mut fallback = |name: &str, value: Option<&str>| {
+        if let Some(value) = value {
+            fields
+                .entry(name)
+                .or_insert_with(|| value.to_owned().into());
+        }
+    };
+    fallback("host", syslog.hostname);
+    fallback("projectName", syslog.app_name);
+    fallback("deploymentId", syslog.proc_id);
+    fallback("source", syslog.msg_id);
+    fallback(
+        "type",
+        Some(match syslog.severity {
+            0..=3 => "stderr",
+            _ => "stdout",
+        }),
+    );
+    fields
+        .entry("id")
+        .or_insert_with(|| crate::types::derived_id(frame).into());
+    let timestamp = syslog
+        .timestamp
+        .and_then(parse_timestamp)
+        .unwrap_or_else(crate::deadletter::now_millis);
+    fields.insert("timestamp".to_owned(), timestamp.into());
+    fields.insert("message".to_owned(), syslog.msg.into());
+    return serde_json::Value::Object(fields);
+}
+
+/// Unix milliseconds from an RFC 3339 timestamp such as
+/// `2024-01-27T03:58:34.122Z` or `2024-01-27T05:58:34+02:00`.
+pub fn parse_timestamp(timestamp: &str) -> Option<i64> {
+    let (date, time) = timestamp.split_once(['T', 't'])?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, offset) = match time.find(['Z', 'z', '+', '-']) {
+        Some(i) => time.split_at(i),
+        None => return None,
+    };
+    let offset_minutes = match offset {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            sign * (hours.parse::<i64>().ok()? * 60 + minutes.parse::<i64>().ok()?)
+        }
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    let millis = format!("{:0<3}", fraction.get(..3).unwrap_or(fraction))
+        .parse::<i64>()
+        .ok()?;
+
+    // days since the epoch of a proleptic gregorian date
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400 + hour * 3_600 + minute * 60 + second - offset_minutes * 60;
+    return Some(seconds * 1000 + millis);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::{ingest_queue, OverflowPolicy};
+
+    const FRAME: &str = concat!(
+        r#"<14>1 2024-01-27T03:58:34.122Z code4rena.com code4rena-com dpl_1 lambda "#,
+        r#"[vercel@1 id="1706327914122" projectId="prj_1" requestId="bom1::x\"y\]"] "#,
+        "\u{feff}hello world"
+    );
+
+    #[test]
+    fn parses_rfc5424() -> Result<()> {
+        let syslog = parse(FRAME)?;
+        assert_eq!(syslog.severity, 6);
+        assert_eq!(syslog.hostname, Some("code4rena.com"));
+        assert_eq!(syslog.msg_id, Some("lambda"));
+        assert_eq!(
+            syslog.structured_data,
+            vec![(
+                "vercel@1",
+                vec![
+                    ("id", "1706327914122".to_owned()),
+                    ("projectId", "prj_1".to_owned()),
+                    ("requestId", r#"bom1::x"y]"#.to_owned()),
+                ]
+            )]
+        );
+        assert_eq!(syslog.msg, "hello world");
+
+        let syslog = parse("<11>1 - - - - - -")?;
+        assert_eq!(syslog.severity, 3);
+        assert_eq!(syslog.hostname, None);
+        assert_eq!(syslog.msg, "");
+        assert!(parse("<14>Jan 27 03:58:34 host app: legacy bsd syslog").is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn maps_onto_vercel_messages() -> Result<()> {
+        let value = to_value(&parse(FRAME)?, FRAME);
+        let message: crate::types::Message = serde_json::from_value(value)?;
+        assert_eq!(message.id, "1706327914122");
+        assert_eq!(message.project_id, "prj_1");
+        assert_eq!(message.project_name, "code4rena-com");
+        assert_eq!(message.deployment_id, "dpl_1");
+        assert_eq!(message.source, "lambda");
+        assert_eq!(message.host, "code4rena.com");
+        assert_eq!(message.output_type.as_deref(), Some("stdout"));
+        assert_eq!(message.request_id.as_deref(), Some(r#"bom1::x"y]"#));
+        assert_eq!(message.timestamp, 1706327914122);
+        assert_eq!(message.message, "hello world");
+        return Ok(());
+    }
+
+    #[test]
+    fn marks_ids_made_up_from_the_timestamp() {
+        assert!(!payload(FRAME).messages[0].id_derived);
+        let frame = FRAME.replace(r#"id="1706327914122" "#, "");
+        assert!(payload(&frame).messages[0].id_derived);
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_timestamp("2024-01-27T03:58:34.122Z"),
+            Some(1706327914122)
+        );
+        assert_eq!(
+            parse_timestamp("2024-01-27T05:58:34.122456+02:00"),
+            Some(1706327914122)
+        );
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[tokio::test]
+    async fn reads_both_framings() -> Result<()> {
+        let data = format!(
+            "{} {}\n<11>1 - host - - - -\r\n\n{} {}",
+            FRAME.len(),
+            FRAME,
+            3,
+            "<1>"
+        );
+        let mut reader = BufReader::new(data.as_bytes());
+        assert_eq!(read_frame(&mut reader).await?.as_deref(), Some(FRAME));
+        assert_eq!(
+            read_frame(&mut reader).await?.as_deref(),
+            Some("<11>1 - host - - - -")
+        );
+        assert_eq!(read_frame(&mut reader).await?.as_deref(), Some("<1>"));
+        assert_eq!(read_frame(&mut reader).await?, None);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn forwards_frames_to_the_queue() -> Result<()> {
+        let (tx, rx) = ingest_queue(10, OverflowPolicy::Reject, None)?;
+        let state = crate::app::test_state(tx)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let (shutdown_tx, shutdown) = watch::channel(());
+        let server = tokio::spawn(serve(listener, None, state, shutdown));
+
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        let data = format!("{} {}not syslog\n", FRAME.len(), FRAME);
+        tokio::io::AsyncWriteExt::write_all(&mut stream, data.as_bytes()).await?;
+        drop(stream);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while rx.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        shutdown_tx.send(())?;
+        server.await?;
+        assert_eq!(rx.len(), 1);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn waits_for_room_in_the_queue() -> Result<()> {
+        let (tx, mut rx) = ingest_queue(1, OverflowPolicy::Reject, None)?;
+        let queued = crate::types::Message {
+            id: String::from("queued"),
+            ..Default::default()
+        };
+        tx.send_batch(vec![queued.into()])?;
+        let state = crate::app::test_state(tx)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let (shutdown_tx, shutdown) = watch::channel(());
+        let server = tokio::spawn(serve(listener, None, state, shutdown));
+
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut stream, format!("{}\n", FRAME).as_bytes()).await?;
+        tokio::time::sleep(QUEUE_FULL_BACKOFF * 2).await;
+        assert_eq!(rx.recv().await.unwrap().message.id, "queued");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while rx.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        drop(stream);
+        shutdown_tx.send(())?;
+        server.await?;
+        assert_ne!(rx.recv().await.unwrap().message.id, "queued");
+        return Ok(());
+    }
+}
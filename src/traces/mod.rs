@@ -0,0 +1,344 @@
+mod proto;
+
+use crate::deadletter::{now_millis, DeadLetterFile};
+use crate::types::TraceDriver;
+use anyhow::Result;
+use axum_prometheus::metrics::counter;
+use prost::Message as _;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
+
+pub use proto::{AnyValue, ExportTraceServiceRequest, KeyValue};
+
+/// Span attributes the id of the request a span belongs to may be found
+/// under, checked in order and then on the span's resource.
+const REQUEST_ID_KEYS: &[&str] = &[
+    "request_id",
+    "vercel.request_id",
+    "vercel.requestId",
+    "requestId",
+    "http.request.id",
+    "http.request_id",
+];
+
+/// The attribute spans carry their request id in once correlated, named like
+/// the `request_id` structured metadata of the loki driver.
+pub const REQUEST_ID_ATTRIBUTE: &str = "request_id";
+
+/// The two OTLP/HTTP encodings, told apart by the content type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Protobuf,
+    Json,
+}
+
+impl Encoding {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let content_type = content_type.split(';').next()?.trim();
+        match content_type.to_ascii_lowercase().as_str() {
+            "application/x-protobuf" | "application/protobuf" => Some(Encoding::Protobuf),
+            "application/json" => Some(Encoding::Json),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Protobuf => "application/x-protobuf",
+            Encoding::Json => "application/json",
+        }
+    }
+
+    pub fn decode(&self, body: &[u8]) -> Result<ExportTraceServiceRequest> {
+        match self {
+            Encoding::Protobuf => Ok(ExportTraceServiceRequest::decode(body)?),
+            Encoding::Json => Ok(serde_json::from_slice(body)?),
+        }
+    }
+
+    /// An empty `ExportTraceServiceResponse`, telling the sender every span
+    /// was accepted.
+    pub fn accepted(&self) -> &'static [u8] {
+        match self {
+            Encoding::Protobuf => b"",
+            Encoding::Json => b"{}",
+        }
+    }
+}
+
+pub fn span_count(request: &ExportTraceServiceRequest) -> usize {
+    request
+        .resource_spans
+        .iter()
+        .flat_map(|resource| &resource.scope_spans)
+        .map(|scope| scope.spans.len())
+        .sum()
+}
+
+/// Gives every span that belongs to a request a `request_id` attribute, so
+/// spans can be joined with the log messages of the same request.
+pub fn correlate(request: &mut ExportTraceServiceRequest) {
+    for resource in &mut request.resource_spans {
+        let resource_request_id = resource
+            .resource
+            .as_ref()
+            .and_then(|resource| request_id(&resource.attributes))
+            .map(str::to_owned);
+        for span in resource
+            .scope_spans
+            .iter_mut()
+            .flat_map(|scope| &mut scope.spans)
+        {
+            if span
+                .attributes
+                .iter()
+                .any(|attribute| attribute.key == REQUEST_ID_ATTRIBUTE)
+            {
+                continue;
+            }
+            let Some(id) = request_id(&span.attributes)
+                .map(str::to_owned)
+                .or_else(|| resource_request_id.clone())
+            else {
+                continue;
+            };
+            span.attributes.push(KeyValue {
+                key: REQUEST_ID_ATTRIBUTE.to_owned(),
+                value: Some(AnyValue::string(&id)),
+            });
+        }
+    }
+}
+
+fn request_id(attributes: &[KeyValue]) -> Option<&str> {
+    REQUEST_ID_KEYS.iter().find_map(|key| {
+        attributes
+            .iter()
+            .find(|attribute| attribute.key == *key)
+            .and_then(|attribute| attribute.value.as_ref()?.as_str())
+    })
+}
+
+/// A trace driver along with the name its metrics are labelled with.
+pub struct TraceSpec {
+    pub name: String,
+    pub driver: Box<dyn TraceDriver>,
+}
+
+/// Hands exports to every trace driver's queue, held by the ingest handler.
+#[derive(Debug, Clone)]
+pub struct TraceSender {
+    drivers: Arc<Vec<mpsc::Sender<Arc<ExportTraceServiceRequest>>>>,
+}
+
+impl TraceSender {
+    /// Queues the export for every driver, or for none of them when one of
+    /// the queues is full.
+    pub fn send(&self, request: ExportTraceServiceRequest) -> Result<()> {
+        let permits = self
+            .drivers
+            .iter()
+            .map(|tx| tx.try_reserve())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow::anyhow!("trace queue is unavailable: {}", e))?;
+        let request = Arc::new(request);
+        for permit in permits {
+            permit.send(request.clone());
+        }
+        return Ok(());
+    }
+}
+
+/// An export a trace driver gave up on, as written to the trace dead-letter
+/// file.
+#[derive(Serialize)]
+struct FailedExport<'a> {
+    driver: &'a str,
+    error: String,
+    // unix milliseconds
+    failed_at: i64,
+    export: &'a ExportTraceServiceRequest,
+}
+
+/// Starts a worker per trace driver, each with a queue of `capacity`
+/// exports, writing the exports a driver gives up on to `dead_letter` when
+/// there is one. The returned task finishes once every sender is dropped and
+/// the queues are drained.
+pub fn spawn(
+    specs: Vec<TraceSpec>,
+    capacity: usize,
+    dead_letter: Option<DeadLetterFile>,
+) -> (TraceSender, tokio::task::JoinHandle<()>) {
+    let dead_letter = dead_letter.map(|file| Arc::new(Mutex::new(file)));
+    let mut workers = JoinSet::new();
+    let mut drivers = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        drivers.push(tx);
+        workers.spawn(run_worker(spec, rx, dead_letter.clone()));
+    }
+    let task = tokio::spawn(async move {
+        while workers.join_next().await.is_some() {}
+        info!("trace drivers finished");
+    });
+    let sender = TraceSender {
+        drivers: Arc::new(drivers),
+    };
+    return (sender, task);
+}
+
+async fn run_worker(
+    mut spec: TraceSpec,
+    mut rx: mpsc::Receiver<Arc<ExportTraceServiceRequest>>,
+    dead_letter: Option<Arc<Mutex<DeadLetterFile>>>,
+) {
+    while let Some(request) = rx.recv().await {
+        let spans = span_count(&request) as u64;
+        debug!(driver = spec.name, spans, "sending spans");
+        match spec.driver.send_traces(&request).await {
+            Ok(_) => counter!("drain_trace_driver_sent_spans", "driver" => spec.name.clone())
+                .increment(spans),
+            Err(e) => {
+                error!(driver = spec.name, "failed sending spans: {:?}", e);
+                counter!("drain_trace_driver_failed_spans", "driver" => spec.name.clone())
+                    .increment(spans);
+                if let Some(file) = &dead_letter {
+                    dead_letter_export(&spec.name, file, request, e, spans).await;
+                }
+            }
+        }
+    }
+}
+
+/// Writes an export the driver gave up on to the dead-letter file, on the
+/// blocking pool.
+async fn dead_letter_export(
+    driver: &str,
+    file: &Arc<Mutex<DeadLetterFile>>,
+    request: Arc<ExportTraceServiceRequest>,
+    error: anyhow::Error,
+    spans: u64,
+) {
+    let (name, file) = (driver.to_owned(), file.clone());
+    let written = tokio::task::spawn_blocking(move || {
+        let entry = FailedExport {
+            driver: &name,
+            error: format!("{:#}", error),
+            failed_at: now_millis(),
+            export: &request,
+        };
+        file.lock().unwrap().write(&entry)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|written| written);
+    if let Err(e) = written {
+        error!(driver, "failed writing trace dead letter: {:?}", e);
+        return;
+    }
+    counter!("drain_trace_dead_letter_spans", "driver" => driver.to_owned()).increment(spans);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::any_value;
+    use super::*;
+
+    struct FailingDriver;
+
+    #[async_trait::async_trait]
+    impl TraceDriver for FailingDriver {
+        async fn send_traces(&mut self, _request: &ExportTraceServiceRequest) -> Result<()> {
+            anyhow::bail!("collector down");
+        }
+    }
+
+    #[tokio::test]
+    async fn dead_letters_failed_exports() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trace-dead-letters.ndjson");
+        let (traces, task) = spawn(
+            vec![TraceSpec {
+                name: String::from("otlp"),
+                driver: Box::new(FailingDriver),
+            }],
+            10,
+            Some(DeadLetterFile::open(&path, u64::MAX, 1)?),
+        );
+        let request: ExportTraceServiceRequest =
+            serde_json::from_str(include_str!("../fixtures/traces.json"))?;
+        traces.send(request.clone())?;
+        drop(traces);
+        task.await?;
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["driver"], "otlp");
+        assert_eq!(lines[0]["error"], "collector down");
+        let export: ExportTraceServiceRequest = serde_json::from_value(lines[0]["export"].clone())?;
+        assert_eq!(export, request);
+        return Ok(());
+    }
+
+    #[test]
+    fn decodes_both_encodings_alike() -> Result<()> {
+        let json = include_str!("../fixtures/traces.json");
+        let request = Encoding::Json.decode(json.as_bytes())?;
+        assert_eq!(span_count(&request), 2);
+        let span = &request.resource_spans[0].scope_spans[0].spans[0];
+        assert_eq!(
+            hex::encode(&span.trace_id),
+            "5b8efff798038103d269b633813fc60c"
+        );
+        assert_eq!(span.start_time_unix_nano, 1706327914122000000);
+        assert_eq!(
+            span.attributes[1].value.as_ref().unwrap().value,
+            Some(any_value::Value::IntValue(200))
+        );
+
+        let protobuf = request.encode_to_vec();
+        assert_eq!(Encoding::Protobuf.decode(&protobuf)?, request);
+        let reencoded = serde_json::to_string(&request)?;
+        assert_eq!(Encoding::Json.decode(reencoded.as_bytes())?, request);
+        return Ok(());
+    }
+
+    #[test]
+    fn correlates_spans_by_request_id() -> Result<()> {
+        let mut request =
+            Encoding::Json.decode(include_str!("../fixtures/traces.json").as_bytes())?;
+        correlate(&mut request);
+        let ids: Vec<Option<&str>> = request.resource_spans[0].scope_spans[0]
+            .spans
+            .iter()
+            .map(|span| {
+                span.attributes
+                    .iter()
+                    .find(|attribute| attribute.key == REQUEST_ID_ATTRIBUTE)
+                    .and_then(|attribute| attribute.value.as_ref()?.as_str())
+            })
+            .collect();
+        // the second span only has the request id on its resource
+        assert_eq!(ids, vec![Some("iad1::abcde-1706327914122-0"); 2]);
+        return Ok(());
+    }
+
+    #[test]
+    fn picks_the_encoding_from_the_content_type() {
+        assert_eq!(
+            Encoding::from_content_type("application/x-protobuf"),
+            Some(Encoding::Protobuf)
+        );
+        assert_eq!(
+            Encoding::from_content_type("application/json; charset=utf-8"),
+            Some(Encoding::Json)
+        );
+        assert_eq!(Encoding::from_content_type("text/plain"), None);
+    }
+}
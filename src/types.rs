@@ -1,19 +1,144 @@
+use crate::traces::ExportTraceServiceRequest;
 use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub vercel_verify: String,
-    pub vercel_secret: ring::hmac::Key,
-    pub log_queue: tokio::sync::mpsc::UnboundedSender<Message>,
+    pub vercel_secrets: crate::secrets::Secrets,
+    pub signatures: crate::signature::RouteSignatures,
+    pub endpoints: crate::endpoints::Endpoints,
+    pub log_queue: crate::queue::IngestSender,
+    pub wal: Option<std::sync::Arc<crate::wal::Wal>>,
+    pub readiness: crate::readiness::Readiness,
+    pub quarantine: Option<std::sync::Arc<crate::quarantine::Quarantine>>,
+    pub traces: Option<crate::traces::TraceSender>,
+    // cap on a request body once its `Content-Encoding` is undone
+    pub max_decompressed_bytes: usize,
+    // payloads with a message further off are refused as replays
+    pub max_timestamp_skew: Option<Duration>,
+    pub dedupe: Option<std::sync::Arc<crate::dedupe::Dedupe>>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct VercelPayload(pub Vec<Message>);
+/// A payload parsed one message at a time, see `ParsedPayload::parse` and
+/// `ParsedPayload::parse_ndjson`.
+#[derive(Debug, Default)]
+pub struct ParsedPayload<'a> {
+    pub messages: Vec<Message>,
+    pub invalid: Vec<InvalidMessage<'a>>,
+}
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A message that failed to parse, along with the JSON it came in as.
+#[derive(Debug)]
+pub struct InvalidMessage<'a> {
+    pub raw: &'a str,
+    // path of the offending field, e.g. `host` or `proxy.userAgent`
+    pub field: String,
+    pub error: String,
+}
+
+impl ParsedPayload<'_> {
+    /// Parses the array one record at a time, so a malformed record doesn't
+    /// take the rest of the payload down with it. Only fails when the body
+    /// isn't a JSON array.
+    pub fn parse<T: Record>(body: &str) -> serde_json::Result<ParsedPayload<'_>> {
+        let raw = serde_json::from_str::<Vec<&RawValue>>(body)?;
+        let mut payload = ParsedPayload {
+            messages: Vec::with_capacity(raw.len()),
+            invalid: Vec::new(),
+        };
+        for raw in raw {
+            payload.push::<T>(raw.get());
+        }
+        return Ok(payload);
+    }
+
+    /// Parses newline delimited JSON, one record per line. Blank lines are
+    /// skipped and a line that isn't JSON only fails its own record.
+    pub fn parse_ndjson<T: Record>(body: &str) -> ParsedPayload<'_> {
+        let mut payload = ParsedPayload {
+            messages: Vec::new(),
+            invalid: Vec::new(),
+        };
+        for line in body.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            payload.push::<T>(line);
+        }
+        return payload;
+    }
+}
+
+impl<'a> ParsedPayload<'a> {
+    fn push<T: Record>(&mut self, raw: &'a str) {
+        let deserializer = &mut serde_json::Deserializer::from_str(raw);
+        let (field, error) = match serde_path_to_error::deserialize::<_, T>(&mut *deserializer) {
+            Ok(record) => match deserializer.end() {
+                Ok(_) => return self.messages.push(record.into_message(raw)),
+                Err(e) => (String::from("."), e.to_string()),
+            },
+            Err(e) => (error_field(&e), e.inner().to_string()),
+        };
+        self.invalid.push(InvalidMessage { raw, field, error });
+    }
+
+    /// Adds a message another ingest format was mapped onto, `raw` being what
+    /// it was mapped from.
+    pub fn push_value(&mut self, raw: &'a str, value: serde_json::Value) {
+        match serde_path_to_error::deserialize::<_, Message>(value) {
+            Ok(message) => self.messages.push(message),
+            Err(e) => self.push_invalid(raw, &error_field(&e), e.inner().to_string()),
+        }
+    }
+
+    pub fn push_invalid(&mut self, raw: &'a str, field: &str, error: String) {
+        self.invalid.push(InvalidMessage {
+            raw,
+            field: field.to_owned(),
+            error,
+        });
+    }
+}
+
+/// One record of a drain payload, mapped onto a `Message` so every drain kind
+/// goes through the same queue and drivers.
+pub trait Record: serde::de::DeserializeOwned {
+    /// `raw` is the record's JSON as received.
+    fn into_message(self, raw: &str) -> Message;
+}
+
+impl Record for Message {
+    fn into_message(self, _raw: &str) -> Message {
+        self
+    }
+}
+
+/// An id for records that come without one, derived from their content so a
+/// redelivered record keeps its id.
+pub fn derived_id(raw: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, raw.as_bytes());
+    return hex::encode(&digest.as_ref()[..16]);
+}
+
+/// The path of the field a message failed on. Missing fields are reported on
+/// their parent by serde, so their name is taken from the error.
+fn error_field(error: &serde_path_to_error::Error<serde_json::Error>) -> String {
+    let path = error.path().to_string();
+    let missing = error
+        .inner()
+        .to_string()
+        .strip_prefix("missing field `")
+        .and_then(|rest| rest.split('`').next().map(str::to_owned));
+    match (path.as_str(), missing) {
+        (".", Some(field)) => field,
+        (_, Some(field)) => format!("{}.{}", path, field),
+        (_, None) => path,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Message {
     pub id: String,
     #[serde(deserialize_with = "deserialize_message_data")]
@@ -32,12 +157,45 @@ pub struct Message {
     #[serde(rename = "buildId")]
     pub build_id: Option<String>,
     pub host: String,
+    pub environment: Option<String>,
     pub path: Option<String>,
     pub entrypoint: Option<String>,
     #[serde(rename = "requestId")]
     pub request_id: Option<String>,
     #[allow(private_interfaces)]
     pub proxy: Option<VercelProxy>,
+    // the named ingest endpoint the message came in through, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    // the record came without an id and `id` is derived from its content, so
+    // identical records share it and it can't tell a replay apart
+    #[serde(skip)]
+    pub id_derived: bool,
+}
+
+/// A message on its way through the queues, holding on to its write-ahead log
+/// entry until every driver is done with it.
+#[derive(Debug)]
+pub struct Envelope {
+    pub message: Message,
+    // the entry is acknowledged when the last copy drops
+    pub ack: Option<std::sync::Arc<crate::wal::WalAck>>,
+}
+
+impl Envelope {
+    /// Keeps the message's write-ahead log entry, if it has one, for the next
+    /// start instead of acknowledging it, as a driver failed to deliver it.
+    pub fn keep(&self) {
+        if let Some(ack) = &self.ack {
+            ack.keep(&self.message);
+        }
+    }
+}
+
+impl From<Message> for Envelope {
+    fn from(message: Message) -> Self {
+        Self { message, ack: None }
+    }
 }
 
 fn deserialize_message_data<'de, D>(deserializer: D) -> Result<serde_json::Value, D::Error>
@@ -78,10 +236,67 @@ struct VercelProxy {
     vercel_cache: Option<String>,
 }
 
+/// What became of messages a buffering driver was holding. Messages are
+/// numbered in the order the driver buffered them, counting from 0 across
+/// every `send_batch` that succeeded.
+#[derive(Debug, Default, Clone)]
+pub struct Settled {
+    pub delivered: Vec<u64>,
+    // messages given up on, grouped by the error they failed with
+    pub failed: Vec<(Vec<u64>, std::sync::Arc<anyhow::Error>)>,
+}
+
 #[async_trait]
 pub trait LogDriver: Send + Sync {
     async fn init(&mut self) -> Result<()>;
     async fn send_log(&mut self, message: &Message) -> Result<()>;
+    /// Sends every message that was waiting in the driver's queue at once, by
+    /// default one `send_log` at a time, carrying on past failed messages.
+    async fn send_batch(&mut self, messages: &[&Message]) -> Result<()> {
+        let mut result = Ok(());
+        for message in messages {
+            if let Err(e) = self.send_log(message).await {
+                result = Err(e);
+            }
+        }
+        result
+    }
+    /// Sends any messages the driver is holding back, called every
+    /// `flush_interval` and once more when the controller stops. A buffering
+    /// driver reports delivery through `take_settled`: `send_batch` fails
+    /// just for messages it didn't buffer, and a flush fails only when
+    /// messages it couldn't send are kept for the next one. Messages that can
+    /// never be sent are given up on rather than kept.
+    async fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Takes what became of buffered messages since the last call, see
+    /// `Settled`. Drivers that send every message immediately report nothing.
+    fn take_settled(&mut self) -> Settled {
+        Settled::default()
+    }
+    /// How long buffered messages may wait before `flush` is called, `None`
+    /// for drivers that send every message immediately.
+    fn flush_interval(&self) -> Option<Duration> {
+        None
+    }
+    /// Called once the driver's queue has been drained on shutdown, by
+    /// default a last `flush`.
+    async fn shutdown(&mut self) -> Result<()> {
+        self.flush().await
+    }
+    /// Whether the driver can currently deliver, usually judged from its last
+    /// request so checking is cheap.
+    async fn health(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A destination for the spans received on `/otlp/v1/traces`.
+#[async_trait]
+pub trait TraceDriver: Send + Sync {
+    /// Sends one export as received, retrying on its own.
+    async fn send_traces(&mut self, request: &ExportTraceServiceRequest) -> Result<()>;
 }
 
 #[cfg(test)]
@@ -96,11 +311,54 @@ mod test {
             include_str!("fixtures/sample_5.json"),
         ];
         for data in test_data {
-            let result = serde_json::from_str::<super::VercelPayload>(data);
+            let result = serde_json::from_str::<Vec<super::Message>>(data);
             assert!(result.is_ok());
         }
     }
     #[test]
+    fn invalid_messages_are_parsed_around() {
+        let data = include_str!("fixtures/partially_invalid.json");
+        let payload = super::ParsedPayload::parse::<super::Message>(data).unwrap();
+        let ids: Vec<&str> = payload.messages.iter().map(|msg| msg.id.as_str()).collect();
+        assert_eq!(ids, vec!["29306223290170632791412265300001"]);
+        let fields: Vec<&str> = payload
+            .invalid
+            .iter()
+            .map(|invalid| invalid.field.as_str())
+            .collect();
+        assert_eq!(fields, vec!["host", "proxy.userAgent"]);
+        let raw: serde_json::Value = serde_json::from_str(payload.invalid[0].raw).unwrap();
+        assert_eq!(raw["message"], "missing its host");
+        assert!(super::ParsedPayload::parse::<super::Message>("{}").is_err());
+    }
+    #[test]
+    fn ndjson_parses_like_the_array_format() {
+        let array =
+            super::ParsedPayload::parse::<super::Message>(include_str!("fixtures/sample_2.json"))
+                .unwrap();
+        let ndjson = super::ParsedPayload::parse_ndjson::<super::Message>(include_str!(
+            "fixtures/sample_2.ndjson"
+        ));
+        assert!(ndjson.invalid.is_empty());
+        assert_eq!(
+            serde_json::to_value(&ndjson.messages).unwrap(),
+            serde_json::to_value(&array.messages).unwrap()
+        );
+    }
+    #[test]
+    fn invalid_ndjson_lines_are_parsed_around() {
+        let data = include_str!("fixtures/partially_invalid.ndjson");
+        let payload = super::ParsedPayload::parse_ndjson::<super::Message>(data);
+        assert_eq!(payload.messages.len(), 1);
+        let fields: Vec<&str> = payload
+            .invalid
+            .iter()
+            .map(|invalid| invalid.field.as_str())
+            .collect();
+        assert_eq!(fields, vec!["host", "id", "proxy.userAgent"]);
+        assert_eq!(payload.invalid[1].raw, r#"{"id": "truncated"#);
+    }
+    #[test]
     fn parses_structured_messages() {
         let test_data = [
             include_str!("fixtures/structured_message_1.json"),
@@ -108,9 +366,9 @@ mod test {
             include_str!("fixtures/sample_1.json"),
         ];
         for (index, data) in test_data.iter().enumerate() {
-            let result = serde_json::from_str::<super::VercelPayload>(data);
+            let result = serde_json::from_str::<Vec<super::Message>>(data);
             assert!(result.is_ok());
-            let payload = result.unwrap().0;
+            let payload = result.unwrap();
             for msg in payload {
                 match index {
                     0 => assert!(msg.message.is_object()),
@@ -123,10 +381,10 @@ mod test {
     #[test]
     fn parses_structured_data_as_expected() {
         let test_data = include_str!("fixtures/structured_message_1.json");
-        let result = serde_json::from_str::<super::VercelPayload>(test_data);
+        let result = serde_json::from_str::<Vec<super::Message>>(test_data);
         assert!(result.is_ok());
         let payload = result.unwrap();
-        let msg = payload.0.first().unwrap();
+        let msg = payload.first().unwrap();
         let message = &msg.message;
         match message {
             serde_json::Value::Object(obj) => {
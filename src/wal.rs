@@ -0,0 +1,689 @@
+use crate::types::{Envelope, Message};
+
+use anyhow::{Context, Result};
+use axum_prometheus::metrics::{counter, gauge};
+use std::collections::{BTreeSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+// every record is: magic | sequence number | payload length | payload crc32 | payload
+const MAGIC: [u8; 4] = *b"WAL1";
+const HEADER_LEN: usize = 4 + 8 + 4 + 4;
+const SEGMENT_EXTENSION: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+// entries kept for the next start, written apart from the segments so the
+// checkpoint can move past them
+const KEPT_FILE: &str = "kept";
+
+/// When appended entries are flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// before the payload is acknowledged to vercel
+    Always,
+    /// periodically in the background
+    Interval,
+    /// whenever the OS decides to
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    pub max_segment_bytes: u64,
+    pub fsync: FsyncPolicy,
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    last_seq: u64,
+}
+
+struct Inner {
+    next_seq: u64,
+    active: File,
+    active_path: PathBuf,
+    active_bytes: u64,
+    dirty: bool,
+    sealed: VecDeque<Segment>,
+    // appended or replayed entries that haven't been acknowledged yet
+    outstanding: BTreeSet<u64>,
+    // every entry before this one has been acknowledged or kept
+    checkpoint: u64,
+    checkpoint_written: u64,
+    // opened with the first kept entry
+    kept: Option<File>,
+}
+
+impl Inner {
+    fn low_watermark(&self) -> u64 {
+        self.outstanding.first().copied().unwrap_or(self.next_seq)
+    }
+}
+
+/// A segmented write-ahead log of accepted messages. Entries are removed once
+/// every driver is done with them, anything left is replayed on startup.
+pub struct Wal {
+    dir: PathBuf,
+    max_segment_bytes: u64,
+    fsync: FsyncPolicy,
+    frozen: AtomicBool,
+    inner: Mutex<Inner>,
+}
+
+/// Acknowledges its entry when dropped, shared by every driver handling the
+/// entry's message so the last one to finish releases it. A driver that
+/// couldn't deliver the message, or hand it to a dead-letter sink, `keep`s
+/// the entry instead.
+pub struct WalAck {
+    seq: u64,
+    wal: Arc<Wal>,
+    // the message as written to the kept file once the entry is kept
+    kept: Mutex<Option<Vec<u8>>>,
+}
+
+impl WalAck {
+    fn new(seq: u64, wal: Arc<Wal>) -> Self {
+        Self {
+            seq,
+            wal,
+            kept: Mutex::new(None),
+        }
+    }
+
+    /// Has `message`, the entry's message, replayed on the next start. It is
+    /// moved out of the segments once every driver is done with it, so later
+    /// entries are acknowledged and removed as usual.
+    pub fn keep(&self, message: &Message) {
+        let mut kept = self.kept.lock().unwrap();
+        if kept.is_some() {
+            return;
+        }
+        match serde_json::to_vec(message) {
+            Ok(payload) => *kept = Some(payload),
+            Err(e) => error!(seq = self.seq, "failed to encode kept wal entry: {:?}", e),
+        }
+    }
+}
+
+impl std::fmt::Debug for WalAck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalAck").field("seq", &self.seq).finish()
+    }
+}
+
+impl Drop for WalAck {
+    fn drop(&mut self) {
+        match self.kept.get_mut().unwrap().take() {
+            Some(payload) => self.wal.keep(self.seq, &payload),
+            None => self.wal.ack(self.seq),
+        }
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_seq, SEGMENT_EXTENSION))
+}
+
+fn segment_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn encode_record(seq: u64, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&MAGIC);
+    record.extend_from_slice(&seq.to_le_bytes());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Reads the records of one segment, skipping over torn or corrupted records
+/// by scanning ahead for the next valid one.
+struct SegmentReader {
+    data: Vec<u8>,
+    pos: usize,
+    path: PathBuf,
+}
+
+impl SegmentReader {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            data: std::fs::read(path)
+                .with_context(|| format!("reading wal segment {}", path.display()))?,
+            pos: 0,
+            path: path.to_owned(),
+        })
+    }
+
+    fn record_at(&self, pos: usize) -> Option<(u64, &[u8])> {
+        let header = self.data.get(pos..pos + HEADER_LEN)?;
+        if header[0..4] != MAGIC {
+            return None;
+        }
+        let seq = u64::from_le_bytes(header[4..12].try_into().ok()?);
+        let len = u32::from_le_bytes(header[12..16].try_into().ok()?) as usize;
+        let crc = u32::from_le_bytes(header[16..20].try_into().ok()?);
+        let payload = self
+            .data
+            .get(pos + HEADER_LEN..(pos + HEADER_LEN).checked_add(len)?)?;
+        if crc32fast::hash(payload) != crc {
+            return None;
+        }
+        Some((seq, payload))
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = (u64, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut skipped = false;
+        while self.pos + HEADER_LEN <= self.data.len() {
+            if let Some((seq, payload)) = self.record_at(self.pos) {
+                let record = (seq, payload.to_vec());
+                self.pos += HEADER_LEN + payload.len();
+                return Some(record);
+            }
+            if !skipped {
+                warn!(
+                    path = ?self.path,
+                    offset = self.pos,
+                    "skipping corrupt wal record"
+                );
+                counter!("drain_wal_corrupt_records").increment(1);
+                skipped = true;
+            }
+            self.pos += 1;
+        }
+        if self.pos < self.data.len() && !skipped {
+            warn!(path = ?self.path, offset = self.pos, "ignoring truncated wal record");
+            counter!("drain_wal_corrupt_records").increment(1);
+        }
+        self.pos = self.data.len();
+        None
+    }
+}
+
+/// Yields the entries left over from a previous run, oldest first.
+pub struct WalReplay {
+    wal: Arc<Wal>,
+    checkpoint: u64,
+    segments: VecDeque<PathBuf>,
+    current: Option<SegmentReader>,
+}
+
+impl Iterator for WalReplay {
+    type Item = Envelope;
+
+    fn next(&mut self) -> Option<Envelope> {
+        loop {
+            let Some(reader) = self.current.as_mut() else {
+                let path = self.segments.pop_front()?;
+                match SegmentReader::open(&path) {
+                    Ok(reader) => self.current = Some(reader),
+                    Err(e) => error!("failed to replay wal segment: {:?}", e),
+                }
+                continue;
+            };
+            let Some((seq, payload)) = reader.next() else {
+                self.current = None;
+                continue;
+            };
+            if seq < self.checkpoint {
+                continue;
+            }
+            let ack = Arc::new(WalAck::new(seq, self.wal.clone()));
+            match serde_json::from_slice::<Message>(&payload) {
+                Ok(message) => {
+                    counter!("drain_wal_replayed_entries").increment(1);
+                    return Some(Envelope {
+                        message,
+                        ack: Some(ack),
+                    });
+                }
+                Err(e) => error!(seq, "dropping unreadable wal entry: {:?}", e),
+            }
+        }
+    }
+}
+
+impl Wal {
+    /// Opens (or creates) the log in `config.dir`, returning it along with the
+    /// entries that still have to be delivered.
+    pub fn open(config: WalConfig) -> Result<(Arc<Wal>, WalReplay)> {
+        std::fs::create_dir_all(&config.dir)
+            .with_context(|| format!("creating wal directory {}", config.dir.display()))?;
+        let checkpoint = match std::fs::read_to_string(config.dir.join(CHECKPOINT_FILE)) {
+            Ok(checkpoint) => checkpoint.trim().parse().unwrap_or_else(|e| {
+                warn!("ignoring unreadable wal checkpoint: {:?}", e);
+                0
+            }),
+            Err(_) => 0,
+        };
+
+        let mut next_seq = checkpoint;
+        let mut outstanding = BTreeSet::new();
+        let mut sealed = VecDeque::new();
+        for path in segment_paths(&config.dir)? {
+            let mut last_seq = None;
+            for (seq, _) in SegmentReader::open(&path)? {
+                next_seq = next_seq.max(seq + 1);
+                if seq >= checkpoint {
+                    outstanding.insert(seq);
+                    last_seq = last_seq.max(Some(seq));
+                }
+            }
+            match last_seq {
+                Some(last_seq) => sealed.push_back(Segment { path, last_seq }),
+                None => std::fs::remove_file(&path)?,
+            }
+        }
+        // kept entries become ordinary ones again, in a segment of their own
+        let kept_path = config.dir.join(KEPT_FILE);
+        if kept_path.exists() {
+            let path = segment_path(&config.dir, next_seq);
+            let mut records = Vec::new();
+            for (_, payload) in SegmentReader::open(&kept_path)? {
+                records.extend(encode_record(next_seq, &payload));
+                outstanding.insert(next_seq);
+                next_seq += 1;
+            }
+            let mut segment = File::create(&path)?;
+            segment.write_all(&records)?;
+            segment.sync_data()?;
+            if !records.is_empty() {
+                sealed.push_back(Segment {
+                    path,
+                    last_seq: next_seq - 1,
+                });
+            }
+            std::fs::remove_file(&kept_path)?;
+        }
+        info!(
+            pending = outstanding.len(),
+            segments = sealed.len(),
+            "opened write-ahead log"
+        );
+        gauge!("drain_wal_pending_entries").set(outstanding.len() as f64);
+
+        let active_path = segment_path(&config.dir, next_seq);
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&active_path)?;
+        let replay_segments = sealed.iter().map(|segment| segment.path.clone()).collect();
+        let wal = Arc::new(Wal {
+            dir: config.dir,
+            max_segment_bytes: config.max_segment_bytes,
+            fsync: config.fsync,
+            frozen: AtomicBool::new(false),
+            inner: Mutex::new(Inner {
+                next_seq,
+                active,
+                active_path,
+                active_bytes: 0,
+                dirty: false,
+                sealed,
+                outstanding,
+                checkpoint,
+                checkpoint_written: checkpoint,
+                kept: None,
+            }),
+        });
+        let replay = WalReplay {
+            wal: wal.clone(),
+            checkpoint,
+            segments: replay_segments,
+            current: None,
+        };
+        Ok((wal, replay))
+    }
+
+    /// Durably records a batch of messages, handing them back wrapped with the
+    /// acknowledgement for their entry.
+    pub async fn append(self: &Arc<Self>, batch: Vec<Message>) -> Result<Vec<Envelope>> {
+        let wal = self.clone();
+        tokio::task::spawn_blocking(move || wal.append_blocking(batch)).await?
+    }
+
+    fn append_blocking(self: &Arc<Self>, batch: Vec<Message>) -> Result<Vec<Envelope>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.active_bytes >= self.max_segment_bytes {
+            self.roll(&mut inner)?;
+        }
+        let first_seq = inner.next_seq;
+        let mut records = Vec::new();
+        for (seq, message) in (first_seq..).zip(&batch) {
+            records.extend(encode_record(seq, &serde_json::to_vec(message)?));
+        }
+        // a batch is written whole or not at all, a partial write is cut off
+        // again so its sequence numbers can be reused
+        let written = inner
+            .active
+            .write_all(&records)
+            .and_then(|_| match self.fsync {
+                FsyncPolicy::Always => inner.active.sync_data(),
+                _ => Ok(()),
+            });
+        if let Err(e) = written {
+            if let Err(truncate) = inner.active.set_len(inner.active_bytes) {
+                error!("failed to truncate wal segment: {:?}", truncate);
+                // whatever made it to disk must not be mistaken for later entries
+                inner.next_seq += batch.len() as u64;
+            }
+            return Err(e).context("appending to the write-ahead log");
+        }
+        let seqs = first_seq..first_seq + batch.len() as u64;
+        inner.next_seq = seqs.end;
+        inner.active_bytes += records.len() as u64;
+        inner.dirty = self.fsync != FsyncPolicy::Always;
+        inner.outstanding.extend(seqs.clone());
+        gauge!("drain_wal_pending_entries").set(inner.outstanding.len() as f64);
+        drop(inner);
+
+        Ok(batch
+            .into_iter()
+            .zip(seqs)
+            .map(|(message, seq)| Envelope {
+                message,
+                ack: Some(Arc::new(WalAck::new(seq, self.clone()))),
+            })
+            .collect())
+    }
+
+    fn roll(&self, inner: &mut Inner) -> Result<()> {
+        if self.fsync != FsyncPolicy::Never {
+            inner.active.sync_data()?;
+        }
+        let path = segment_path(&self.dir, inner.next_seq);
+        let active = OpenOptions::new().create(true).append(true).open(&path)?;
+        inner.active = active;
+        let sealed_path = std::mem::replace(&mut inner.active_path, path);
+        inner.sealed.push_back(Segment {
+            path: sealed_path,
+            last_seq: inner.next_seq - 1,
+        });
+        inner.active_bytes = 0;
+        inner.dirty = false;
+        debug!(segments = inner.sealed.len() + 1, "rolled wal segment");
+        Ok(())
+    }
+
+    /// Stops acknowledging entries, so whatever is still in flight when the
+    /// process gives up on it is replayed on the next start.
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::SeqCst);
+    }
+
+    fn ack(&self, seq: u64) {
+        if self.frozen.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        self.release(&mut inner, seq);
+    }
+
+    /// Copies the entry to the kept file, to be replayed on the next start,
+    /// and releases it from its segment. If the copy fails the entry stays
+    /// outstanding, holding back the checkpoint as before.
+    fn keep(&self, seq: u64, payload: &[u8]) {
+        counter!("drain_wal_kept_entries").increment(1);
+        if self.frozen.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let written = self.write_kept(&mut inner, &encode_record(seq, payload));
+        if let Err(e) = written {
+            error!(seq, "failed to move kept wal entry: {:?}", e);
+            return;
+        }
+        self.release(&mut inner, seq);
+    }
+
+    fn write_kept(&self, inner: &mut Inner, record: &[u8]) -> std::io::Result<()> {
+        if inner.kept.is_none() {
+            let kept = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(KEPT_FILE))?;
+            inner.kept = Some(kept);
+        }
+        let kept = inner.kept.as_mut().unwrap();
+        kept.write_all(record)?;
+        // the checkpoint can move past the entry as soon as it is released
+        if self.fsync != FsyncPolicy::Never {
+            kept.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the entry from the outstanding ones, moving the checkpoint and
+    /// removing the segments that are no longer needed.
+    fn release(&self, inner: &mut Inner, seq: u64) {
+        inner.outstanding.remove(&seq);
+        let low = inner.low_watermark();
+        inner.checkpoint = low;
+        while inner
+            .sealed
+            .front()
+            .is_some_and(|segment| segment.last_seq < low)
+        {
+            let segment = inner.sealed.pop_front().unwrap();
+            if let Err(e) = std::fs::remove_file(&segment.path) {
+                error!(path = ?segment.path, "failed to remove wal segment: {:?}", e);
+            }
+        }
+        gauge!("drain_wal_pending_entries").set(inner.outstanding.len() as f64);
+    }
+
+    /// Flushes the active segment if needed and persists the checkpoint so
+    /// acknowledged entries aren't replayed after a restart.
+    pub fn sync(&self) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.dirty && self.fsync == FsyncPolicy::Interval {
+            inner.active.sync_data()?;
+            inner.dirty = false;
+        }
+        if inner.checkpoint != inner.checkpoint_written {
+            let tmp = self.dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+            std::fs::write(&tmp, inner.checkpoint.to_string())?;
+            std::fs::rename(&tmp, self.dir.join(CHECKPOINT_FILE))?;
+            inner.checkpoint_written = inner.checkpoint;
+        }
+        Ok(())
+    }
+
+    /// Calls `sync` every `interval` for as long as the log is in use.
+    pub fn spawn_sync_task(self: &Arc<Self>, interval: Duration) {
+        let wal: Weak<Wal> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            loop {
+                timer.tick().await;
+                let Some(wal) = wal.upgrade() else {
+                    break;
+                };
+                if let Err(e) = wal.sync() {
+                    error!("failed to sync write-ahead log: {:?}", e);
+                }
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for Wal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Wal")
+            .field("dir", &self.dir)
+            .field("fsync", &self.fsync)
+            .finish()
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("failed to sync write-ahead log on close: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, max_segment_bytes: u64) -> WalConfig {
+        WalConfig {
+            dir: dir.to_owned(),
+            max_segment_bytes,
+            fsync: FsyncPolicy::Always,
+        }
+    }
+
+    fn message(id: usize) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(),
+            "timestamp": 1,
+            "source": "lambda",
+            "projectName": "project",
+            "projectId": "prj_1",
+            "deploymentId": "dpl_1",
+            "host": "example.vercel.app",
+        }))
+        .unwrap()
+    }
+
+    fn batch(ids: std::ops::Range<usize>) -> Vec<Message> {
+        ids.map(message).collect()
+    }
+
+    fn replayed_ids(replay: WalReplay) -> Vec<String> {
+        replay.map(|envelope| envelope.message.id).collect()
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_entries_are_replayed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, replay) = Wal::open(config(dir.path(), 1024))?;
+        assert_eq!(replay.count(), 0);
+
+        let mut envelopes = wal.append(batch(0..4)).await?;
+        // entries 0 and 2 are delivered, then the process dies
+        envelopes.remove(2);
+        envelopes.remove(0);
+        wal.sync()?;
+        std::mem::forget(envelopes);
+        std::mem::forget(wal);
+
+        // replay starts at the oldest unacknowledged entry, so 2 is sent twice
+        let (_wal, replay) = Wal::open(config(dir.path(), 1024))?;
+        assert_eq!(replayed_ids(replay), vec!["1", "2", "3"]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn acknowledged_segments_are_removed() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(config(dir.path(), 1))?;
+        // segments roll between batches
+        let mut envelopes = Vec::new();
+        for id in 0..5 {
+            envelopes.extend(wal.append(batch(id..id + 1)).await?);
+        }
+        assert_eq!(segment_paths(dir.path())?.len(), 5);
+
+        drop(envelopes);
+        assert_eq!(segment_paths(dir.path())?.len(), 1);
+        drop(wal);
+
+        let (_wal, replay) = Wal::open(config(dir.path(), 1))?;
+        assert_eq!(replay.count(), 0);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn replayed_entries_are_acknowledged() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(config(dir.path(), 1024))?;
+        std::mem::forget(wal.append(batch(0..3)).await?);
+        drop(wal);
+
+        let (wal, replay) = Wal::open(config(dir.path(), 1024))?;
+        assert_eq!(replayed_ids(replay), vec!["0", "1", "2"]);
+        drop(wal);
+
+        let (_wal, replay) = Wal::open(config(dir.path(), 1024))?;
+        assert_eq!(replay.count(), 0);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn reader_skips_corrupt_records() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(config(dir.path(), 1024 * 1024))?;
+        std::mem::forget(wal.append(batch(0..3)).await?);
+        drop(wal);
+
+        // flip a byte in the first record's payload and tear the last record
+        let path = segment_paths(dir.path())?.remove(0);
+        let mut data = std::fs::read(&path)?;
+        data[HEADER_LEN + 5] ^= 0xff;
+        data.truncate(data.len() - 3);
+        std::fs::write(&path, data)?;
+
+        let (_wal, replay) = Wal::open(config(dir.path(), 1024 * 1024))?;
+        assert_eq!(replayed_ids(replay), vec!["1"]);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn kept_entries_do_not_hold_back_segment_removal() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(config(dir.path(), 1))?;
+        let mut envelopes = Vec::new();
+        for id in 0..5 {
+            envelopes.extend(wal.append(batch(id..id + 1)).await?);
+        }
+        envelopes[0].keep();
+        drop(envelopes);
+        assert_eq!(segment_paths(dir.path())?.len(), 1);
+        wal.sync()?;
+        drop(wal);
+
+        // only the kept entry is replayed, and only once
+        let (wal, replay) = Wal::open(config(dir.path(), 1))?;
+        assert_eq!(replayed_ids(replay), vec!["0"]);
+        drop(wal);
+        let (_wal, replay) = Wal::open(config(dir.path(), 1))?;
+        assert_eq!(replay.count(), 0);
+        return Ok(());
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers_continue_after_restart() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (wal, _) = Wal::open(config(dir.path(), 1024))?;
+        drop(wal.append(batch(0..3)).await?);
+        drop(wal);
+
+        let (wal, _) = Wal::open(config(dir.path(), 1024))?;
+        std::mem::forget(wal.append(batch(3..4)).await?);
+        drop(wal);
+
+        let (_wal, replay) = Wal::open(config(dir.path(), 1024))?;
+        assert_eq!(replayed_ids(replay), vec!["3"]);
+        return Ok(());
+    }
+}